@@ -0,0 +1,38 @@
+"""RFM96W LoRa radio, built from the same Rust driver as the `rora` binary."""
+
+import asyncio
+from typing import Optional
+
+from ._rora import (
+    BROADCAST,
+    MAX_PAYLOAD,
+    MAX_PLAINTEXT,
+    Compressor,
+    FecDecoder,
+    FecEncoder,
+    Header,
+    LoRa,
+    Packet,
+    SecureChannel,
+)
+
+__all__ = [
+    "BROADCAST",
+    "MAX_PAYLOAD",
+    "MAX_PLAINTEXT",
+    "Compressor",
+    "FecDecoder",
+    "FecEncoder",
+    "Header",
+    "LoRa",
+    "Packet",
+    "SecureChannel",
+    "receive_async",
+]
+
+
+async def receive_async(radio: LoRa, timeout_ms: Optional[int] = None) -> Optional[Packet]:
+    """Awaits a packet without blocking the event loop. `LoRa.receive` releases the GIL, so
+    it runs in the default executor. Other calls on `radio` raise until it returns."""
+    loop = asyncio.get_running_loop()
+    return await loop.run_in_executor(None, radio.receive, timeout_ms)
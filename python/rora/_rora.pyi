@@ -0,0 +1,89 @@
+from typing import List, Optional, Tuple, Union
+
+BROADCAST: int
+MAX_PAYLOAD: int
+MAX_PLAINTEXT: int
+
+class Packet:
+    """A received packet and its link metadata."""
+
+    @property
+    def data(self) -> bytes: ...
+    @property
+    def rssi(self) -> float: ...
+    @property
+    def snr(self) -> float: ...
+    @property
+    def frequency_error(self) -> int: ...
+    @property
+    def crc_ok(self) -> bool: ...
+
+class LoRa:
+    """The RFM96W on the board's default pins."""
+
+    def __init__(self) -> None: ...
+    frequency: int
+    """Frequency in hertz."""
+    spreading_factor: int
+    bandwidth: int
+    """Signal bandwidth in hertz."""
+    coding_rate: int
+    """Coding rate denominator, 5 to 8 for 4/5 to 4/8."""
+    def set_tx_power(self, dbm: int, pa_boost: bool = True) -> None: ...
+    @property
+    def tx_power(self) -> int:
+        """Transmit power in dBm."""
+    lna_gain: Optional[int]
+    """Fixed LNA gain from 1 (maximum) to 6, None while the AGC sets it."""
+    def set_preamble_length(self, length: int) -> None: ...
+    def set_crc(self, enabled: bool) -> None: ...
+    def set_invert_iq(self, inverted: bool) -> None: ...
+    implicit_length: Optional[int]
+    """Fixed payload length of implicit header mode, None for an explicit header."""
+    def transmit(self, data: bytes) -> None:
+        """Sends up to 255 bytes and blocks until they are on air."""
+    def receive(self, timeout_ms: Optional[int] = None) -> Optional[Packet]:
+        """Waits for a packet, for at most `timeout_ms` if given. Returns None on timeout."""
+
+class Header:
+    """The RadioHead style datagram header."""
+
+    def __init__(self, to: int, from_: int, id: int = 0, flags: int = 0) -> None: ...
+    @property
+    def to(self) -> int: ...
+    @property
+    def from_(self) -> int: ...
+    @property
+    def id(self) -> int: ...
+    @property
+    def flags(self) -> int: ...
+
+class SecureChannel:
+    """AEAD encryption with pre-shared keys per peer."""
+
+    def __init__(self, address: int) -> None: ...
+    def add_peer(self, address: int, cipher: str, key: str) -> None:
+        """`cipher` is "aes128" or "chacha20", `key` is hex."""
+    def persist_counter(self, path: str) -> None:
+        """Keeps the transmit counter in `path`, required before `seal`."""
+    def seal(self, to: int, payload: bytes, flags: int = 0) -> bytes: ...
+    def open(self, packet: bytes) -> Tuple[Header, bytes]: ...
+
+class Compressor:
+    """LZSS compression negotiated by a header flag."""
+
+    def __init__(self, dictionary: Union[None, bytes, str] = None) -> None:
+        """Plain LZSS, or static dictionary mode; "telemetry" selects the built in one."""
+    def encode(self, header: Header, payload: bytes) -> bytes: ...
+    def decode(self, message: bytes) -> Tuple[Header, bytes]: ...
+
+class FecEncoder:
+    """Erasure coding across the packets of a transfer."""
+
+    def __init__(self, overhead_percent: int = 50) -> None: ...
+    def encode(self, header: Header, data: bytes) -> List[bytes]: ...
+
+class FecDecoder:
+    def __init__(self) -> None: ...
+    def push(self, packet: bytes) -> Optional[Tuple[Header, bytes]]:
+        """Returns the header and data once enough fragments of a transfer arrived."""
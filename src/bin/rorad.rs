@@ -0,0 +1,27 @@
+use anyhow::Result;
+use rora::capture::Capture;
+use rora::cli::Options;
+use rora::daemon::{self, DaemonConfig};
+
+/// `rorad [--socket path | --no-socket] [--tcp addr] [--queue n] [--pcap path]`
+///
+/// Owns the radio and serves line-delimited JSON to any number of clients, see `rora::daemon`.
+fn main() -> Result<()> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let options = Options::new(&args);
+    let socket = match options.get("socket")? {
+        Some(path) => Some(path),
+        None if options.flag("no-socket") => None,
+        None => Some("/tmp/rorad.sock".into()),
+    };
+    let config = DaemonConfig {
+        socket,
+        tcp: options.get("tcp")?,
+        queue_limit: options.get_or("queue", 32)?,
+    };
+    let mut radio = rora::open_radio()?;
+    if let Some(path) = options.get::<String>("pcap")? {
+        radio.set_capture(Some(Capture::create(path)?));
+    }
+    daemon::run(radio, &config)
+}
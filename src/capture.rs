@@ -0,0 +1,211 @@
+use anyhow::Result;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// pcapng block types.
+const SECTION_HEADER: u32 = 0x0a0d_0d0a;
+const INTERFACE_DESCRIPTION: u32 = 0x0000_0001;
+const ENHANCED_PACKET: u32 = 0x0000_0006;
+const BYTE_ORDER_MAGIC: u32 = 0x1a2b_3c4d;
+
+/// LINKTYPE_LORATAP, the link-layer header Wireshark's LoRaTap and LoRaWAN dissectors expect.
+const LINKTYPE_LORATAP: u16 = 270;
+const OPT_END: u16 = 0;
+const OPT_EPB_FLAGS: u16 = 2;
+
+// LoRaTap version 1 header: version, padding, length, frequency, bandwidth, SF, 4 bytes of
+// RSSI and SNR, sync word, source gateway (15-22), timestamp (23-26), flags (27), coding
+// rate (28), datarate (29-30), IF channel (31), RF chain (32) and tag (33-34).
+const LORATAP_VERSION: u8 = 1;
+const LORATAP_LEN: usize = 35;
+const LORATAP_FLAGS: usize = 27;
+const LORATAP_CODING_RATE: usize = 28;
+const FLAG_IQ_INVERTED: u8 = 0x02;
+const FLAG_IMPLICIT_HEADER: u8 = 0x04;
+const FLAG_CRC_OK: u8 = 0x08;
+const FLAG_CRC_BAD: u8 = 0x10;
+const FLAG_CRC_NONE: u8 = 0x20;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+/// Payload CRC status of a frame.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Crc {
+    Ok,
+    Bad,
+    None,
+}
+
+/// Radio settings and link metadata recorded with every frame.
+pub struct LinkInfo {
+    pub direction: Direction,
+    pub frequency: u64,
+    pub bandwidth: i64,
+    pub spreading_factor: u8,
+    /// Denominator of the coding rate, `5` for `4/5`.
+    pub coding_rate: u8,
+    pub sync_word: u8,
+    pub invert_iq: bool,
+    pub implicit_header: bool,
+    /// Only known for received frames.
+    pub rssi: Option<f32>,
+    pub snr: Option<f32>,
+    pub crc: Crc,
+}
+
+impl LinkInfo {
+    /// Encodes the LoRaTap v1 header. Multi-byte fields are big endian.
+    fn loratap(&self) -> [u8; LORATAP_LEN] {
+        let mut header = [0u8; LORATAP_LEN];
+        header[0] = LORATAP_VERSION;
+        header[2..4].copy_from_slice(&(LORATAP_LEN as u16).to_be_bytes());
+        header[4..8].copy_from_slice(&(self.frequency as u32).to_be_bytes());
+        // in steps of 125 kHz, the narrow bandwidths round to 0
+        header[8] = ((self.bandwidth + 62_500) / 125_000) as u8;
+        header[9] = self.spreading_factor;
+        // RSSI is stored as dBm + 139, the packet and current RSSI are the same reading
+        let rssi = self.rssi.map_or(0, |rssi| (rssi + 139.0).round().clamp(0.0, 255.0) as u8);
+        header[10] = rssi;
+        header[11] = rssi;
+        header[12] = rssi;
+        header[13] = self.snr.map_or(0, |snr| (snr * 4.0).round().clamp(-128.0, 127.0) as i8 as u8);
+        header[14] = self.sync_word;
+        // source gateway and timestamp stay 0, pcapng carries the time
+        let mut flags = match self.crc {
+            Crc::Ok => FLAG_CRC_OK,
+            Crc::Bad => FLAG_CRC_BAD,
+            Crc::None => FLAG_CRC_NONE,
+        };
+        if self.invert_iq {
+            flags |= FLAG_IQ_INVERTED;
+        }
+        if self.implicit_header {
+            flags |= FLAG_IMPLICIT_HEADER;
+        }
+        header[LORATAP_FLAGS] = flags;
+        header[LORATAP_CODING_RATE] = self.coding_rate;
+        // datarate, IF channel, RF chain and tag only apply to FSK and multi-channel gateways
+        header
+    }
+}
+
+/// Writes frames to a pcapng file with LoRaTap headers, so captures open directly in
+/// Wireshark. Every frame is flushed, the file stays usable if the program is killed.
+pub struct Capture {
+    file: BufWriter<File>,
+}
+
+impl Capture {
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let mut capture = Capture { file: BufWriter::new(File::create(path)?) };
+
+        let mut section = BYTE_ORDER_MAGIC.to_le_bytes().to_vec();
+        // version 1.0, section length unknown
+        section.extend_from_slice(&1u16.to_le_bytes());
+        section.extend_from_slice(&0u16.to_le_bytes());
+        section.extend_from_slice(&(-1i64).to_le_bytes());
+        capture.write_block(SECTION_HEADER, &section)?;
+
+        let mut interface = LINKTYPE_LORATAP.to_le_bytes().to_vec();
+        interface.extend_from_slice(&0u16.to_le_bytes());
+        // no snapshot length limit
+        interface.extend_from_slice(&0u32.to_le_bytes());
+        capture.write_block(INTERFACE_DESCRIPTION, &interface)?;
+        capture.file.flush()?;
+        Ok(capture)
+    }
+
+    /// Records one frame as an enhanced packet block, with the direction in its flags.
+    pub fn write(&mut self, info: &LinkInfo, data: &[u8]) -> Result<()> {
+        let micros = SystemTime::now().duration_since(UNIX_EPOCH)?.as_micros() as u64;
+        let mut frame = info.loratap().to_vec();
+        frame.extend_from_slice(data);
+
+        let mut block = 0u32.to_le_bytes().to_vec();
+        block.extend_from_slice(&((micros >> 32) as u32).to_le_bytes());
+        block.extend_from_slice(&(micros as u32).to_le_bytes());
+        block.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+        block.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+        block.extend_from_slice(&frame);
+        block.resize(block.len().next_multiple_of(4), 0);
+
+        let direction: u32 = match info.direction {
+            Direction::Inbound => 1,
+            Direction::Outbound => 2,
+        };
+        block.extend_from_slice(&OPT_EPB_FLAGS.to_le_bytes());
+        block.extend_from_slice(&4u16.to_le_bytes());
+        block.extend_from_slice(&direction.to_le_bytes());
+        block.extend_from_slice(&OPT_END.to_le_bytes());
+        block.extend_from_slice(&0u16.to_le_bytes());
+
+        self.write_block(ENHANCED_PACKET, &block)?;
+        self.file.flush()?;
+        Ok(())
+    }
+
+    /// Writes a block with its type and the total length before and after the body.
+    fn write_block(&mut self, block_type: u32, body: &[u8]) -> Result<()> {
+        let total = (body.len() + 12) as u32;
+        self.file.write_all(&block_type.to_le_bytes())?;
+        self.file.write_all(&total.to_le_bytes())?;
+        self.file.write_all(body)?;
+        self.file.write_all(&total.to_le_bytes())?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info() -> LinkInfo {
+        LinkInfo {
+            direction: Direction::Inbound,
+            frequency: 433_175_000,
+            bandwidth: 125_000,
+            spreading_factor: 9,
+            coding_rate: 5,
+            sync_word: 0x12,
+            invert_iq: true,
+            implicit_header: false,
+            rssi: Some(-100.0),
+            snr: Some(-2.5),
+            crc: Crc::Ok,
+        }
+    }
+
+    #[test]
+    fn loratap_offsets() {
+        let header = info().loratap();
+        assert_eq!(header.len(), 35);
+        assert_eq!(header[0], 1);
+        assert_eq!(header[1], 0);
+        assert_eq!(&header[2..4], &35u16.to_be_bytes());
+        assert_eq!(&header[4..8], &433_175_000u32.to_be_bytes());
+        assert_eq!(header[8], 1);
+        assert_eq!(header[9], 9);
+        assert_eq!(&header[10..13], &[39, 39, 39]);
+        assert_eq!(header[13], (-10i8) as u8);
+        assert_eq!(header[14], 0x12);
+        // source gateway and timestamp
+        assert_eq!(&header[15..27], &[0; 12]);
+        assert_eq!(header[27], FLAG_CRC_OK | FLAG_IQ_INVERTED);
+        assert_eq!(header[28], 5);
+        // datarate, IF channel, RF chain and tag
+        assert_eq!(&header[29..35], &[0; 6]);
+    }
+
+    #[test]
+    fn loratap_flags() {
+        let header = LinkInfo { crc: Crc::None, invert_iq: false, implicit_header: true, rssi: None, snr: None, ..info() }.loratap();
+        assert_eq!(header[27], FLAG_CRC_NONE | FLAG_IMPLICIT_HEADER);
+        assert_eq!(&header[10..14], &[0; 4]);
+    }
+}
@@ -0,0 +1,70 @@
+use anyhow::{anyhow, Result};
+use std::fmt::Display;
+use std::str::FromStr;
+
+/// Arguments that follow a subcommand. Leading arguments are positional, the rest are given as
+/// `--name value` or as a bare `--flag`.
+pub struct Options {
+    args: Vec<String>,
+}
+
+impl Options {
+    pub fn new(args: &[String]) -> Self {
+        Options { args: args.to_vec() }
+    }
+
+    /// Returns the positional argument at `index`, if present.
+    pub fn positional(&self, index: usize) -> Option<&str> {
+        self.args
+            .iter()
+            .take_while(|a| !a.starts_with("--"))
+            .nth(index)
+            .map(String::as_str)
+    }
+
+    /// Returns true if `--name` was given.
+    pub fn flag(&self, name: &str) -> bool {
+        self.args.iter().any(|a| a.strip_prefix("--") == Some(name))
+    }
+
+    /// Returns the value following `--name`, parsed into `T`.
+    pub fn get<T>(&self, name: &str) -> Result<Option<T>>
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        let position = self.args.iter().position(|a| a.strip_prefix("--") == Some(name));
+        match position.and_then(|i| self.args.get(i + 1)) {
+            Some(value) if !value.starts_with("--") => value
+                .parse()
+                .map(Some)
+                .map_err(|e| anyhow!("Invalid value for --{}: {}", name, e)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Returns the value following `--name`, or `default` if it was not given.
+    pub fn get_or<T>(&self, name: &str, default: T) -> Result<T>
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        Ok(self.get(name)?.unwrap_or(default))
+    }
+
+    /// Returns the comma separated values following `--name`, each parsed into `T`.
+    pub fn get_list<T>(&self, name: &str) -> Result<Option<Vec<T>>>
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        let Some(values) = self.get::<String>(name)? else {
+            return Ok(None);
+        };
+        values
+            .split(',')
+            .map(|value| value.trim().parse().map_err(|e| anyhow!("Invalid value for --{}: {}", name, e)))
+            .collect::<Result<Vec<T>>>()
+            .map(Some)
+    }
+}
@@ -0,0 +1,368 @@
+#![allow(dead_code)]
+
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+
+use crate::datagram::{Header, FLAG_COMPRESSED, FLAG_FRAGMENT, HEADER_LEN, MAX_PAYLOAD};
+use crate::rfm96w::LoRa;
+
+// LZSS parameters, a back reference costs 1 + 10 + 5 bits against 9 per literal.
+const WINDOW_BITS: u32 = 10;
+const LENGTH_BITS: u32 = 5;
+const WINDOW: usize = 1 << WINDOW_BITS;
+const MIN_MATCH: usize = 2;
+const MAX_MATCH: usize = MIN_MATCH + (1 << LENGTH_BITS) - 1;
+// Refuse to inflate beyond this, a corrupt packet must not eat all the memory.
+const MAX_OUTPUT: usize = 64 * 1024;
+// A fragment starts with its index and the number of fragments in the message.
+const FRAGMENT_HEADER_LEN: usize = 2;
+const FRAGMENT_LEN: usize = MAX_PAYLOAD - FRAGMENT_HEADER_LEN;
+
+/// Dictionary for the JSON telemetry our nodes send, used by `Lzss::telemetry`.
+pub const TELEMETRY_DICTIONARY: &[u8] = br#"{"id":"node","ts":,"temp":,"hum":,"pres":,"batt":,"volt":,"rssi":,"snr":,"lat":,"lon":,"alt":,"seq":,"status":"ok","error":null,"data":[],true,false}"#;
+
+/// A compression algorithm usable as a stage of the send/receive path.
+pub trait Codec {
+    fn compress(&self, data: &[u8]) -> Vec<u8>;
+    fn decompress(&self, data: &[u8]) -> Result<Vec<u8>>;
+}
+
+/// Heatshrink style LZSS. Each token is a flag bit, then either an 8 bit literal (`1`) or a
+/// 10 bit offset and 5 bit length back into the last 1 KiB (`0`). An optional static
+/// dictionary pre-fills the window, so even the first bytes of a short packet find matches.
+#[derive(Default)]
+pub struct Lzss {
+    dictionary: Vec<u8>,
+}
+
+impl Lzss {
+    pub fn new() -> Self {
+        Lzss { dictionary: Vec::new() }
+    }
+
+    /// Uses `dictionary` as shared history. Only the last 1 KiB of it can be referenced.
+    pub fn with_dictionary(dictionary: &[u8]) -> Self {
+        let start = dictionary.len().saturating_sub(WINDOW);
+        Lzss { dictionary: dictionary[start..].to_vec() }
+    }
+
+    /// Static dictionary mode for the JSON telemetry format.
+    pub fn telemetry() -> Self {
+        Self::with_dictionary(TELEMETRY_DICTIONARY)
+    }
+
+    /// Returns the position and length of the longest match for `history[pos..]`.
+    fn longest_match(history: &[u8], pos: usize) -> (usize, usize) {
+        let max = MAX_MATCH.min(history.len() - pos);
+        let mut best = (0, 0);
+        for candidate in pos.saturating_sub(WINDOW)..pos {
+            let len = (0..max)
+                .take_while(|&i| history[candidate + i] == history[pos + i])
+                .count();
+            if len > best.1 {
+                best = (candidate, len);
+                if len == max {
+                    break;
+                }
+            }
+        }
+        best
+    }
+}
+
+impl Codec for Lzss {
+    fn compress(&self, data: &[u8]) -> Vec<u8> {
+        let mut history = self.dictionary.clone();
+        history.extend_from_slice(data);
+        let mut writer = BitWriter::default();
+        let mut pos = self.dictionary.len();
+        while pos < history.len() {
+            let (candidate, len) = Self::longest_match(&history, pos);
+            if len >= MIN_MATCH {
+                writer.write(0, 1);
+                writer.write((pos - candidate - 1) as u32, WINDOW_BITS);
+                writer.write((len - MIN_MATCH) as u32, LENGTH_BITS);
+                pos += len;
+            } else {
+                writer.write(1, 1);
+                writer.write(history[pos] as u32, 8);
+                pos += 1;
+            }
+        }
+        writer.finish()
+    }
+
+    fn decompress(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let mut output = self.dictionary.clone();
+        let mut reader = BitReader::new(data);
+        // trailing padding is too short to hold a whole token
+        while let Some(tag) = reader.read(1) {
+            if tag == 1 {
+                match reader.read(8) {
+                    Some(byte) => output.push(byte as u8),
+                    None => break,
+                }
+            } else {
+                let (offset, len) = match (reader.read(WINDOW_BITS), reader.read(LENGTH_BITS)) {
+                    (Some(offset), Some(len)) => (offset as usize + 1, len as usize + MIN_MATCH),
+                    _ => break,
+                };
+                if offset > output.len() {
+                    return Err(anyhow!("Back reference outside the window."));
+                }
+                let start = output.len() - offset;
+                for i in 0..len {
+                    output.push(output[start + i]);
+                }
+            }
+            if output.len() - self.dictionary.len() > MAX_OUTPUT {
+                return Err(anyhow!("Decompressed data exceeds {} bytes.", MAX_OUTPUT));
+            }
+        }
+        Ok(output.split_off(self.dictionary.len()))
+    }
+}
+
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    bits: u8,
+}
+
+impl BitWriter {
+    /// Appends the low `count` bits of `value`, most significant first.
+    fn write(&mut self, value: u32, count: u32) {
+        for i in (0..count).rev() {
+            if self.bits == 0 {
+                self.bytes.push(0);
+            }
+            let bit = ((value >> i) & 1) as u8;
+            *self.bytes.last_mut().unwrap() |= bit << (7 - self.bits);
+            self.bits = (self.bits + 1) % 8;
+        }
+    }
+
+    fn finish(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        BitReader { bytes, position: 0 }
+    }
+
+    /// Reads `count` bits, or `None` if fewer are left.
+    fn read(&mut self, count: u32) -> Option<u32> {
+        if self.position + count as usize > self.bytes.len() * 8 {
+            return None;
+        }
+        let mut value = 0;
+        for _ in 0..count {
+            let bit = (self.bytes[self.position / 8] >> (7 - self.position % 8)) & 1;
+            value = (value << 1) | bit as u32;
+            self.position += 1;
+        }
+        Some(value)
+    }
+}
+
+/// The fragments received so far of a message from one sender.
+struct Partial {
+    header: Header,
+    fragments: Vec<Option<Vec<u8>>>,
+}
+
+/// Compression stage between the datagram layer and the radio. Payloads are only sent
+/// compressed, with `FLAG_COMPRESSED` set, when that makes them shorter; the receiver
+/// decompresses based on the flag, so both ends only have to agree on the codec.
+///
+/// Messages longer than a packet are split into fragments with `FLAG_FRAGMENT` set, each
+/// carrying the header, its index and the fragment count. `receive` puts them back together.
+pub struct Compressor {
+    codec: Box<dyn Codec>,
+    /// One message in progress per sender, a new id from the same sender replaces it.
+    partial: HashMap<u8, Partial>,
+}
+
+impl Compressor {
+    pub fn new(codec: Box<dyn Codec>) -> Self {
+        Compressor { codec, partial: HashMap::new() }
+    }
+
+    /// Returns `header` followed by the payload, compressed if that saves space.
+    pub fn encode(&self, mut header: Header, payload: &[u8]) -> Vec<u8> {
+        let compressed = self.codec.compress(payload);
+        let body = if compressed.len() < payload.len() {
+            header.flags |= FLAG_COMPRESSED;
+            &compressed[..]
+        } else {
+            header.flags &= !FLAG_COMPRESSED;
+            payload
+        };
+        let mut message = header.to_bytes().to_vec();
+        message.extend_from_slice(body);
+        message
+    }
+
+    /// Splits a received message into its header and payload, decompressing if flagged.
+    pub fn decode(&self, message: &[u8]) -> Result<(Header, Vec<u8>)> {
+        let mut header = Header::from_bytes(message)?;
+        let body = &message[HEADER_LEN..];
+        if header.flags & FLAG_COMPRESSED == 0 {
+            return Ok((header, body.to_vec()));
+        }
+        header.flags &= !FLAG_COMPRESSED;
+        Ok((header, self.codec.decompress(body)?))
+    }
+
+    /// Encodes `payload` into packets of at most 255 bytes. A message that fits is a single
+    /// packet, the same as `encode`, longer ones are split into fragments.
+    pub fn packets(&self, header: Header, payload: &[u8]) -> Result<Vec<Vec<u8>>> {
+        let message = self.encode(header, payload);
+        if message.len() <= HEADER_LEN + MAX_PAYLOAD {
+            return Ok(vec![message]);
+        }
+        let header = Header::from_bytes(&message)?;
+        let body = &message[HEADER_LEN..];
+        let count = body.len().div_ceil(FRAGMENT_LEN);
+        if count > u8::MAX as usize {
+            return Err(anyhow!("Message needs {} fragments, at most 255 are possible.", count));
+        }
+        let header = Header { flags: header.flags | FLAG_FRAGMENT, ..header };
+        Ok(body
+            .chunks(FRAGMENT_LEN)
+            .enumerate()
+            .map(|(index, chunk)| {
+                let mut packet = header.to_bytes().to_vec();
+                packet.extend_from_slice(&[index as u8, count as u8]);
+                packet.extend_from_slice(chunk);
+                packet
+            })
+            .collect())
+    }
+
+    /// Decodes a received packet. Returns `None` for fragments until the message is complete.
+    pub fn receive(&mut self, packet: &[u8]) -> Result<Option<(Header, Vec<u8>)>> {
+        let header = Header::from_bytes(packet)?;
+        if header.flags & FLAG_FRAGMENT == 0 {
+            return self.decode(packet).map(Some);
+        }
+        let (index, count) = match packet[HEADER_LEN..] {
+            [index, count, ..] if index < count => (index as usize, count as usize),
+            _ => return Err(anyhow!("Bad fragment header.")),
+        };
+        let header = Header { flags: header.flags & !FLAG_FRAGMENT, ..header };
+        let partial = self.partial.entry(header.from).or_insert_with(|| Partial { header, fragments: Vec::new() });
+        if partial.header != header || partial.fragments.len() != count {
+            *partial = Partial { header, fragments: vec![None; count] };
+        }
+        partial.fragments[index] = Some(packet[HEADER_LEN + FRAGMENT_HEADER_LEN..].to_vec());
+        if partial.fragments.iter().any(Option::is_none) {
+            return Ok(None);
+        }
+        let partial = self.partial.remove(&header.from).unwrap();
+        let mut message = header.to_bytes().to_vec();
+        message.extend(partial.fragments.into_iter().flatten().flatten());
+        self.decode(&message).map(Some)
+    }
+
+    /// Encodes `payload` and sends its packets with `tx_bulk`, so fewer chunks go on air.
+    pub fn send_bulk(&self, radio: &mut LoRa, header: Header, payload: &[u8]) -> Result<()> {
+        for packet in self.packets(header, payload)? {
+            radio.tx_bulk(&packet)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header() -> Header {
+        Header { to: 2, from: 1, id: 7, flags: 0 }
+    }
+
+    /// Bytes LZSS cannot shrink.
+    fn noise(len: usize) -> Vec<u8> {
+        let mut state = 0x2545_f491u32;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn lzss_round_trip() {
+        let lzss = Lzss::telemetry();
+        let data = br#"{"id":"node","ts":1700000000,"temp":21.5,"hum":40,"batt":3.9,"status":"ok"}"#;
+        let compressed = lzss.compress(data);
+        assert!(compressed.len() < data.len());
+        assert_eq!(lzss.decompress(&compressed).unwrap(), data);
+    }
+
+    #[test]
+    fn short_message_is_one_packet() {
+        let compressor = Compressor::new(Box::new(Lzss::new()));
+        let payload = b"hello hello hello hello";
+        let packets = compressor.packets(header(), payload).unwrap();
+        assert_eq!(packets, vec![compressor.encode(header(), payload)]);
+        assert_eq!(packets[0][3] & FLAG_FRAGMENT, 0);
+    }
+
+    #[test]
+    fn multi_packet_round_trip() {
+        let text: Vec<u8> = (0..400).flat_map(|i| format!("{{\"seq\":{},\"temp\":{}}}", i, i % 37).into_bytes()).collect();
+        for payload in [noise(700), text] {
+            let sender = Compressor::new(Box::new(Lzss::new()));
+            let mut receiver = Compressor::new(Box::new(Lzss::new()));
+            let packets = sender.packets(header(), &payload).unwrap();
+            assert!(packets.len() > 1);
+            assert!(packets.iter().all(|p| p.len() <= 255));
+
+            // arrival order does not matter, only the last fragment completes the message
+            let (last, rest) = packets.split_first().unwrap();
+            for packet in rest.iter().rev() {
+                assert!(receiver.receive(packet).unwrap().is_none());
+            }
+            let (received, data) = receiver.receive(last).unwrap().unwrap();
+            assert_eq!(received, header());
+            assert_eq!(data, payload);
+            assert!(receiver.partial.is_empty());
+        }
+    }
+
+    #[test]
+    fn fragments_of_a_new_message_replace_the_old() {
+        let sender = Compressor::new(Box::new(Lzss::new()));
+        let mut receiver = Compressor::new(Box::new(Lzss::new()));
+        let old = sender.packets(header(), &noise(600)).unwrap();
+        let new = sender.packets(Header { id: 8, ..header() }, &noise(500)).unwrap();
+        receiver.receive(&old[0]).unwrap();
+        let mut result = None;
+        for packet in &new {
+            result = receiver.receive(packet).unwrap();
+        }
+        assert_eq!(result.unwrap().1, noise(500));
+        // the stale fragment of the old message must not complete anything
+        assert!(receiver.receive(&old[1]).unwrap().is_none());
+    }
+
+    #[test]
+    fn bad_fragment_header_is_rejected() {
+        let mut receiver = Compressor::new(Box::new(Lzss::new()));
+        let flags = FLAG_FRAGMENT;
+        assert!(receiver.receive(&[2, 1, 7, flags, 3, 3, 0]).is_err());
+        assert!(receiver.receive(&[2, 1, 7, flags, 0]).is_err());
+    }
+}
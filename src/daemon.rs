@@ -0,0 +1,435 @@
+use anyhow::{anyhow, Result};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use serde_json::{json, Value};
+use std::collections::VecDeque;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{Shutdown, TcpListener};
+use std::os::unix::net::UnixListener;
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, Sender, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::register::Bandwidth;
+use crate::rfm96w::{LoRa, TxPower};
+
+const POLL_MS: i32 = 10;
+/// Events waiting for a subscriber, one that falls further behind is disconnected.
+const SUBSCRIBER_QUEUE: usize = 256;
+
+/// Where `rorad` listens for clients.
+pub struct DaemonConfig {
+    pub socket: Option<PathBuf>,
+    pub tcp: Option<String>,
+    /// Transmissions waiting beyond this are refused.
+    pub queue_limit: usize,
+}
+
+type Writer = Arc<Mutex<Box<dyn Write + Send>>>;
+/// Shuts the client's socket down, which also ends its thread.
+type Close = Box<dyn Fn() + Send>;
+
+/// What a client thread asks of the radio thread.
+enum Request {
+    Subscribe(Writer, Close),
+    Transmit(Vec<u8>, Sender<Value>),
+    Status(Sender<Value>),
+    Configure(Value, Sender<Value>),
+}
+
+/// Writes one JSON line, returning false if the client is gone.
+fn write_line(writer: &Writer, value: &Value) -> bool {
+    let mut writer = writer.lock().unwrap();
+    writeln!(writer, "{}", value).and_then(|_| writer.flush()).is_ok()
+}
+
+/// Serves one connection. Every line is a JSON request with a `cmd` and an optional `id`
+/// that is copied into the reply:
+///
+/// - `{"cmd":"subscribe"}` streams `{"event":"packet",...}` lines for every received packet,
+///   a client that falls more than `SUBSCRIBER_QUEUE` events behind is disconnected.
+/// - `{"cmd":"transmit","data":"<base64>"}` queues up to 255 bytes for transmission.
+/// - `{"cmd":"status"}` returns the radio settings, queue length and counters.
+/// - `{"cmd":"config","frequency":433175000,"sf":9,...}` changes settings, see `configure`.
+fn serve<R: Read, C: Fn() + Send + Clone + 'static>(reader: R, writer: Writer, close: C, requests: Sender<Request>) {
+    for line in BufReader::new(reader).lines() {
+        let Ok(line) = line else { break };
+        if line.trim().is_empty() {
+            continue;
+        }
+        let request: Value = match serde_json::from_str(&line) {
+            Ok(request) => request,
+            Err(e) => {
+                write_line(&writer, &json!({ "ok": false, "error": e.to_string() }));
+                continue;
+            }
+        };
+        let (reply_tx, reply_rx) = mpsc::channel();
+        let sent = match request["cmd"].as_str() {
+            Some("subscribe") => {
+                let ok = requests.send(Request::Subscribe(writer.clone(), Box::new(close.clone()))).is_ok();
+                let _ = reply_tx.send(json!({ "ok": ok }));
+                ok
+            }
+            Some("transmit") => match request["data"].as_str().map(|d| BASE64.decode(d)) {
+                Some(Ok(payload)) => requests.send(Request::Transmit(payload, reply_tx)).is_ok(),
+                _ => reply_tx.send(json!({ "ok": false, "error": "data must be base64" })).is_ok(),
+            },
+            Some("status") => requests.send(Request::Status(reply_tx)).is_ok(),
+            Some("config") => requests.send(Request::Configure(request.clone(), reply_tx)).is_ok(),
+            _ => reply_tx.send(json!({ "ok": false, "error": "unknown cmd" })).is_ok(),
+        };
+        if !sent {
+            // the radio thread is gone
+            break;
+        }
+        let mut reply = reply_rx.recv().unwrap_or_else(|_| json!({ "ok": false }));
+        if let Some(id) = request.get("id") {
+            reply["id"] = id.clone();
+        }
+        if !write_line(&writer, &reply) {
+            break;
+        }
+    }
+}
+
+fn accept_unix(listener: UnixListener, requests: Sender<Request>) {
+    for stream in listener.incoming().flatten() {
+        let (Ok(writer), Ok(closer)) = (stream.try_clone(), stream.try_clone()) else { continue };
+        let writer: Writer = Arc::new(Mutex::new(Box::new(writer)));
+        let closer = Arc::new(closer);
+        let close = move || {
+            let _ = closer.shutdown(Shutdown::Both);
+        };
+        let requests = requests.clone();
+        thread::spawn(move || serve(stream, writer, close, requests));
+    }
+}
+
+fn accept_tcp(listener: TcpListener, requests: Sender<Request>) {
+    for stream in listener.incoming().flatten() {
+        let (Ok(writer), Ok(closer)) = (stream.try_clone(), stream.try_clone()) else { continue };
+        let writer: Writer = Arc::new(Mutex::new(Box::new(writer)));
+        let closer = Arc::new(closer);
+        let close = move || {
+            let _ = closer.shutdown(Shutdown::Both);
+        };
+        let requests = requests.clone();
+        thread::spawn(move || serve(stream, writer, close, requests));
+    }
+}
+
+/// A subscribed client. Events are written by a thread of its own, so a slow client never
+/// blocks the radio thread.
+struct Subscriber {
+    events: SyncSender<Value>,
+    close: Close,
+}
+
+impl Subscriber {
+    fn new(writer: Writer, close: Close) -> Self {
+        let (events, queue) = mpsc::sync_channel::<Value>(SUBSCRIBER_QUEUE);
+        thread::spawn(move || {
+            for event in queue {
+                if !write_line(&writer, &event) {
+                    break;
+                }
+            }
+        });
+        Subscriber { events, close }
+    }
+}
+
+/// Queues `event` for every subscriber without blocking. Clients that are gone or whose queue
+/// is full are dropped, the latter also disconnected.
+fn publish(subscribers: &mut Vec<Subscriber>, event: &Value) {
+    subscribers.retain(|subscriber| match subscriber.events.try_send(event.clone()) {
+        Ok(()) => true,
+        Err(TrySendError::Full(_)) => {
+            (subscriber.close)();
+            false
+        }
+        Err(TrySendError::Disconnected(_)) => false,
+    });
+}
+
+/// The settings of a `config` request, all checked before any is applied.
+#[derive(Debug, Default, PartialEq)]
+struct Settings {
+    frequency: Option<u64>,
+    sf: Option<u8>,
+    bw: Option<i64>,
+    cr: Option<u8>,
+    power: Option<i32>,
+    preamble: Option<i64>,
+    crc: Option<bool>,
+    invert_iq: Option<bool>,
+}
+
+impl Settings {
+    /// Reads `frequency` (Hz), `sf`, `bw` (Hz), `cr` (denominator), `power` (dBm on PA_BOOST),
+    /// `preamble`, `crc` and `invert_iq`, rejecting values the radio does not support.
+    fn parse(settings: &Value) -> Result<Self> {
+        let number = |key: &str, range: std::ops::RangeInclusive<i64>| -> Result<Option<i64>> {
+            match settings.get(key) {
+                None => Ok(None),
+                Some(value) => match value.as_i64() {
+                    Some(n) if range.contains(&n) => Ok(Some(n)),
+                    Some(n) => Err(anyhow!("{} {} is outside {} to {}", key, n, range.start(), range.end())),
+                    None => Err(anyhow!("{} must be a number", key)),
+                },
+            }
+        };
+        let flag = |key: &str| -> Result<Option<bool>> {
+            match settings.get(key) {
+                None => Ok(None),
+                Some(value) => value.as_bool().map(Some).ok_or_else(|| anyhow!("{} must be a boolean", key)),
+            }
+        };
+        let bw = number("bw", 0..=500_000)?;
+        if let Some(bw) = bw.filter(|&bw| Bandwidth::from_hz(bw).is_none()) {
+            return Err(anyhow!("bw {} is not a LoRa bandwidth", bw));
+        }
+        let power = number("power", i32::MIN as i64..=i32::MAX as i64)?.map(|p| p as i32);
+        if let Some(power) = power {
+            TxPower::pa_boost(power).registers()?;
+        }
+        Ok(Settings {
+            // the SX1276 synthesizer range
+            frequency: number("frequency", 137_000_000..=1_020_000_000)?.map(|f| f as u64),
+            sf: number("sf", 6..=12)?.map(|sf| sf as u8),
+            bw,
+            cr: number("cr", 5..=8)?.map(|cr| cr as u8),
+            power,
+            preamble: number("preamble", 6..=0xffff)?,
+            crc: flag("crc")?,
+            invert_iq: flag("invert_iq")?,
+        })
+    }
+}
+
+/// Runs the daemon: client connections get a thread each, the calling thread owns the radio
+/// and is the only one touching the SPI bus.
+pub fn run(radio: LoRa, config: &DaemonConfig) -> Result<()> {
+    let (requests, receiver) = mpsc::channel();
+    if let Some(path) = &config.socket {
+        // a socket left behind by a previous run
+        let _ = std::fs::remove_file(path);
+        let listener = UnixListener::bind(path)?;
+        let requests = requests.clone();
+        thread::spawn(move || accept_unix(listener, requests));
+        println!("rorad listening on {}.", path.display());
+    }
+    if let Some(address) = &config.tcp {
+        let listener = TcpListener::bind(address)?;
+        let requests = requests.clone();
+        thread::spawn(move || accept_tcp(listener, requests));
+        println!("rorad listening on tcp {}.", address);
+    }
+    if config.socket.is_none() && config.tcp.is_none() {
+        return Err(anyhow!("Neither a socket nor a TCP address was given."));
+    }
+    drop(requests);
+    RadioThread::new(radio, config.queue_limit).run(receiver)
+}
+
+struct RadioThread {
+    radio: LoRa,
+    queue: VecDeque<Vec<u8>>,
+    queue_limit: usize,
+    subscribers: Vec<Subscriber>,
+    received: u64,
+    crc_errors: u64,
+    transmitted: u64,
+}
+
+impl RadioThread {
+    fn new(radio: LoRa, queue_limit: usize) -> Self {
+        RadioThread {
+            radio,
+            queue: VecDeque::new(),
+            queue_limit,
+            subscribers: Vec::new(),
+            received: 0,
+            crc_errors: 0,
+            transmitted: 0,
+        }
+    }
+
+    fn run(&mut self, requests: Receiver<Request>) -> Result<()> {
+        loop {
+            while let Ok(request) = requests.try_recv() {
+                self.handle(request);
+            }
+
+            if let Some(payload) = self.queue.pop_front() {
+                let mut buffer = [0u8; 255];
+                buffer[..payload.len()].copy_from_slice(&payload);
+                self.radio.transmit_payload_busy(buffer, payload.len())?;
+                self.transmitted += 1;
+                self.publish(&json!({ "event": "sent", "size": payload.len() }));
+            }
+
+            if let Ok(size) = self.radio.poll_irq(Some(POLL_MS)) {
+                let crc_ok = !self.radio.crc_error();
+                let buffer = self.radio.read_packet()?;
+                self.received += 1;
+                self.crc_errors += !crc_ok as u64;
+                let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u64;
+                let event = json!({
+                    "event": "packet",
+                    "data": BASE64.encode(&buffer[..size]),
+                    "size": size,
+                    "crc_ok": crc_ok,
+                    "rssi": self.radio.get_packet_rssi()?,
+                    "snr": self.radio.get_packet_snr()?,
+                    "frequency_error": self.radio.get_frequency_error()?,
+                    "timestamp": timestamp,
+                });
+                self.publish(&event);
+            }
+        }
+    }
+
+    fn publish(&mut self, event: &Value) {
+        publish(&mut self.subscribers, event);
+    }
+
+    fn handle(&mut self, request: Request) {
+        match request {
+            Request::Subscribe(writer, close) => self.subscribers.push(Subscriber::new(writer, close)),
+            Request::Transmit(payload, reply) => {
+                let response = if payload.len() > 255 {
+                    json!({ "ok": false, "error": "payload exceeds 255 bytes" })
+                } else if self.queue.len() >= self.queue_limit {
+                    json!({ "ok": false, "error": "queue full" })
+                } else {
+                    self.queue.push_back(payload);
+                    json!({ "ok": true, "queued": self.queue.len() })
+                };
+                let _ = reply.send(response);
+            }
+            Request::Status(reply) => {
+                let _ = reply.send(self.status().unwrap_or_else(error));
+            }
+            Request::Configure(settings, reply) => {
+                let response = self.configure(&settings).and_then(|_| self.status());
+                let _ = reply.send(response.unwrap_or_else(error));
+            }
+        }
+    }
+
+    fn status(&mut self) -> Result<Value> {
+        Ok(json!({
+            "ok": true,
+            "frequency": self.radio.get_frequency(),
+            "sf": self.radio.get_spreading_factor()?,
+            "bw": self.radio.get_signal_bandwidth()?,
+            "cr": self.radio.get_coding_rate_4()?,
+            "queue": self.queue.len(),
+            "subscribers": self.subscribers.len(),
+            "received": self.received,
+            "crc_errors": self.crc_errors,
+            "transmitted": self.transmitted,
+        }))
+    }
+
+    /// Applies the settings present in a `config` request, nothing is changed if any of them
+    /// is invalid. See `Settings::parse`.
+    fn configure(&mut self, settings: &Value) -> Result<()> {
+        let settings = Settings::parse(settings)?;
+        if let Some(frequency) = settings.frequency {
+            self.radio.set_frequency_hz(frequency)?;
+        }
+        if let Some(sf) = settings.sf {
+            self.radio.set_spreading_factor(sf)?;
+        }
+        if let Some(bw) = settings.bw {
+            self.radio.set_signal_bandwidth(bw)?;
+        }
+        if let Some(cr) = settings.cr {
+            self.radio.set_coding_rate_4(cr)?;
+        }
+        if let Some(power) = settings.power {
+            self.radio.set_tx_power(TxPower::pa_boost(power))?;
+        }
+        if let Some(preamble) = settings.preamble {
+            self.radio.set_preamble_length(preamble)?;
+        }
+        if let Some(crc) = settings.crc {
+            self.radio.set_crc(crc)?;
+        }
+        if let Some(invert_iq) = settings.invert_iq {
+            self.radio.set_invert_iq(invert_iq)?;
+        }
+        Ok(())
+    }
+}
+
+fn error(e: anyhow::Error) -> Value {
+    json!({ "ok": false, "error": e.to_string() })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    /// A client that never reads, every write blocks until the test ends.
+    struct Stalled(Mutex<Receiver<()>>);
+
+    impl Write for Stalled {
+        fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+            let _ = self.0.lock().unwrap().recv();
+            Err(std::io::ErrorKind::BrokenPipe.into())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn slow_subscriber_is_dropped() {
+        let (unblock, blocked) = mpsc::channel();
+        let writer: Writer = Arc::new(Mutex::new(Box::new(Stalled(Mutex::new(blocked)))));
+        let closed = Arc::new(AtomicBool::new(false));
+        let flag = closed.clone();
+        let mut subscribers = vec![Subscriber::new(writer, Box::new(move || flag.store(true, Ordering::SeqCst)))];
+
+        // the writer thread holds one event, the queue the rest
+        for i in 0..=SUBSCRIBER_QUEUE {
+            publish(&mut subscribers, &json!({ "event": i }));
+        }
+        publish(&mut subscribers, &json!({ "event": "overflow" }));
+        publish(&mut subscribers, &json!({ "event": "overflow" }));
+        assert!(subscribers.is_empty());
+        assert!(closed.load(Ordering::SeqCst));
+        drop(unblock);
+    }
+
+    #[test]
+    fn settings_are_checked() {
+        let settings = Settings::parse(&json!({ "frequency": 433_175_000, "sf": 9, "bw": 125_000, "crc": true })).unwrap();
+        assert_eq!(
+            settings,
+            Settings { frequency: Some(433_175_000), sf: Some(9), bw: Some(125_000), crc: Some(true), ..Default::default() }
+        );
+        for bad in [
+            json!({ "frequency": -433_175_000 }),
+            json!({ "frequency": 433.175 }),
+            json!({ "sf": 13 }),
+            json!({ "bw": 100_000 }),
+            json!({ "cr": 9 }),
+            json!({ "power": 21 }),
+            json!({ "preamble": 0 }),
+            json!({ "crc": 1 }),
+            // one bad value rejects the valid ones too
+            json!({ "sf": 9, "invert_iq": "yes" }),
+        ] {
+            assert!(Settings::parse(&bad).is_err(), "{}", bad);
+        }
+    }
+}
@@ -0,0 +1,55 @@
+#![allow(dead_code)]
+
+use anyhow::{anyhow, Result};
+
+/// Length of the RadioHead compatible header that starts every datagram.
+pub const HEADER_LEN: usize = 4;
+/// Largest payload that fits in one packet behind the header.
+pub const MAX_PAYLOAD: usize = 255 - HEADER_LEN;
+/// Destination address every node accepts.
+pub const BROADCAST: u8 = 0xff;
+
+/// The payload is encrypted and authenticated, see `secure`.
+pub const FLAG_SECURE: u8 = 0x01;
+/// The payload is compressed, see `compress`.
+pub const FLAG_COMPRESSED: u8 = 0x02;
+/// The payload is one fragment of an erasure coded transfer, see `fec`.
+pub const FLAG_FEC: u8 = 0x04;
+/// The payload is a file transfer message, see `transfer`.
+pub const FLAG_TRANSFER: u8 = 0x08;
+/// The payload is one fragment of a message longer than a packet, see `compress`.
+pub const FLAG_FRAGMENT: u8 = 0x10;
+
+/// The RadioHead header: to, from, id and flags, in that order. `PYTHON_HEADER` in `main.rs` is
+/// a broadcast from address `255` with no flags.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Header {
+    pub to: u8,
+    pub from: u8,
+    pub id: u8,
+    pub flags: u8,
+}
+
+impl Header {
+    pub fn to_bytes(self) -> [u8; HEADER_LEN] {
+        [self.to, self.from, self.id, self.flags]
+    }
+
+    /// Reads the header from the start of a packet.
+    pub fn from_bytes(packet: &[u8]) -> Result<Self> {
+        match packet {
+            [to, from, id, flags, ..] => Ok(Header {
+                to: *to,
+                from: *from,
+                id: *id,
+                flags: *flags,
+            }),
+            _ => Err(anyhow!("Packet too short for a header.")),
+        }
+    }
+
+    /// Returns true if a node with `address` should accept this datagram.
+    pub fn is_for(&self, address: u8) -> bool {
+        self.to == address || self.to == BROADCAST
+    }
+}
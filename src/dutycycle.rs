@@ -0,0 +1,123 @@
+use anyhow::{anyhow, Result};
+use std::time::{Duration, Instant};
+
+use crate::rfm96w::{max_payload_symbols, symbol_us, LoRa, RadioMode, SYMBOL_TIMEOUT};
+
+/// Time from sleep to a running receiver, oscillator start-up and PLL lock with some margin.
+const WAKE_UP: Duration = Duration::from_millis(2);
+/// Length of a channel activity detection.
+const CAD_SYMBOLS: u64 = 2;
+/// Preamble left after the receiver woke up, enough to lock onto it.
+const LOCK_SYMBOLS: u64 = 8;
+/// The 4.25 symbols the modem adds to the programmed preamble length, rounded up.
+const FIXED_PREAMBLE_SYMBOLS: u64 = 5;
+
+/// How a sleeping receiver checks the channel when it wakes.
+#[derive(Clone, Copy)]
+pub enum Wake {
+    /// Channel activity detection, full RX only follows a detected preamble.
+    Cad,
+    /// An `RxSingle` window of this many symbols, which receives the packet when it finds one.
+    /// It must be within `SYMBOL_TIMEOUT`.
+    RxSingle(u16),
+}
+
+/// Duty-cycled receive for battery powered nodes: the radio sleeps for `sleep`, then wakes
+/// and checks the channel. Transmitters send a preamble longer than the sleep period, so a
+/// receiver always wakes up during it. Both ends must use the same settings.
+#[derive(Clone, Copy)]
+pub struct DutyCycle {
+    pub sleep: Duration,
+    pub wake: Wake,
+}
+
+impl DutyCycle {
+    /// The preamble length in symbols that spans a whole sleep period, the check after waking
+    /// and enough preamble to lock onto.
+    pub fn preamble_length(&self, spreading_factor: u8, bandwidth: i64) -> Result<u16> {
+        let symbol = symbol_us(spreading_factor, bandwidth);
+        let asleep = (self.sleep + WAKE_UP).as_micros() as u64;
+        let check = match self.wake {
+            Wake::Cad => CAD_SYMBOLS,
+            Wake::RxSingle(window) if SYMBOL_TIMEOUT.contains(&window) => window as u64,
+            Wake::RxSingle(window) => {
+                return Err(anyhow!("An RxSingle window of {} symbols is not between 4 and 1023.", window))
+            }
+        };
+        let length = asleep.div_ceil(symbol) + check + LOCK_SYMBOLS;
+        u16::try_from(length).map_err(|_| {
+            anyhow!("A {} ms sleep needs {} preamble symbols, at most 65535 fit.", self.sleep.as_millis(), length)
+        })
+    }
+
+    /// Sets the preamble length for the current spreading factor and bandwidth. Transmitters
+    /// need it to reach sleeping receivers and receivers expect at least that much preamble.
+    pub fn configure(&self, radio: &mut LoRa) -> Result<()> {
+        let length = self.preamble_length(radio.get_spreading_factor()?, radio.get_signal_bandwidth()?)?;
+        radio.set_preamble_length(length as i64)
+    }
+
+    /// Sleeps and wakes until a packet arrives and returns its size, read it with
+    /// `read_packet`. Returns `None` once `timeout` passed. The radio is left in standby.
+    pub fn receive(&self, radio: &mut LoRa, timeout: Option<Duration>) -> Result<Option<usize>> {
+        let sf = radio.get_spreading_factor()?;
+        let bw = radio.get_signal_bandwidth()?;
+        let symbol = symbol_us(sf, bw);
+        let preamble = self.preamble_length(sf, bw)? as u64 + FIXED_PREAMBLE_SYMBOLS;
+        // a detected preamble may have only just started
+        let packet_us = (preamble + max_payload_symbols(sf, bw, radio.get_coding_rate_4()?)) * symbol;
+        let packet_ms = packet_us.div_ceil(1000) as i32;
+
+        let start = Instant::now();
+        while timeout.is_none_or(|timeout| start.elapsed() < timeout) {
+            radio.set_mode(RadioMode::Sleep)?;
+            spin_sleep::sleep(self.sleep);
+            let received = match self.wake {
+                Wake::Cad => {
+                    if !radio.channel_activity()? {
+                        continue;
+                    }
+                    let received = radio.poll_irq(Some(packet_ms)).ok();
+                    radio.set_mode(RadioMode::Stdby)?;
+                    received
+                }
+                Wake::RxSingle(window) => radio.receive_single(window)?,
+            };
+            if received.is_some() {
+                return Ok(received);
+            }
+        }
+        radio.set_mode(RadioMode::Stdby)?;
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn preamble_spans_sleep_and_window() {
+        let duty = DutyCycle { sleep: Duration::from_millis(1000), wake: Wake::RxSingle(8) };
+        // SF7 at 125 kHz has 1024 µs symbols, 1002 ms asleep is 979 of them
+        assert_eq!(duty.preamble_length(7, 125_000).unwrap(), 979 + 8 + LOCK_SYMBOLS as u16);
+        let cad = DutyCycle { wake: Wake::Cad, ..duty };
+        assert_eq!(cad.preamble_length(7, 125_000).unwrap(), 979 + 2 + LOCK_SYMBOLS as u16);
+    }
+
+    #[test]
+    fn window_must_fit_symbol_timeout() {
+        for window in [0, 3, 1024, u16::MAX] {
+            let duty = DutyCycle { sleep: Duration::from_millis(100), wake: Wake::RxSingle(window) };
+            assert!(duty.preamble_length(7, 125_000).is_err(), "{}", window);
+        }
+        let duty = DutyCycle { sleep: Duration::from_millis(100), wake: Wake::RxSingle(1023) };
+        assert!(duty.preamble_length(7, 125_000).is_ok());
+    }
+
+    #[test]
+    fn long_sleep_is_rejected() {
+        let duty = DutyCycle { sleep: Duration::from_secs(600), wake: Wake::Cad };
+        assert!(duty.preamble_length(7, 500_000).is_err());
+    }
+}
@@ -0,0 +1,344 @@
+#![allow(dead_code)]
+
+use anyhow::{anyhow, Result};
+use std::collections::{HashMap, VecDeque};
+
+use crate::datagram::{Header, FLAG_FEC, HEADER_LEN, MAX_PAYLOAD};
+use crate::rfm96w::LoRa;
+
+/// Transfer id, fragment index, K, N and the 16 bit length of the original data.
+pub const FRAGMENT_HEADER_LEN: usize = 6;
+/// Largest shard that fits in a packet next to both headers.
+pub const MAX_SHARD: usize = MAX_PAYLOAD - FRAGMENT_HEADER_LEN;
+/// Fragment indices are a byte and the code needs distinct field elements per fragment.
+pub const MAX_FRAGMENTS: usize = 255;
+/// Completed transfers remembered to drop their late fragments.
+const COMPLETED: usize = 64;
+
+/// Arithmetic in GF(2^8) with the polynomial x^8 + x^4 + x^3 + x^2 + 1 (0x11d).
+struct Gf256 {
+    exp: [u8; 512],
+    log: [u8; 256],
+}
+
+impl Gf256 {
+    fn new() -> Self {
+        let mut gf = Gf256 { exp: [0; 512], log: [0; 256] };
+        let mut x: u16 = 1;
+        for i in 0..255 {
+            gf.exp[i] = x as u8;
+            gf.log[x as usize] = i as u8;
+            x <<= 1;
+            if x & 0x100 != 0 {
+                x ^= 0x11d;
+            }
+        }
+        for i in 255..512 {
+            gf.exp[i] = gf.exp[i - 255];
+        }
+        gf
+    }
+
+    fn mul(&self, a: u8, b: u8) -> u8 {
+        if a == 0 || b == 0 {
+            0
+        } else {
+            self.exp[self.log[a as usize] as usize + self.log[b as usize] as usize]
+        }
+    }
+
+    fn inv(&self, a: u8) -> u8 {
+        self.exp[255 - self.log[a as usize] as usize]
+    }
+
+    /// Row `index` of the systematic generator: the identity for data fragments, a Cauchy row
+    /// for repair fragments. Any K rows of this matrix are linearly independent.
+    fn row(&self, index: usize, k: usize) -> Vec<u8> {
+        if index < k {
+            (0..k).map(|j| (j == index) as u8).collect()
+        } else {
+            (0..k).map(|j| self.inv(index as u8 ^ j as u8)).collect()
+        }
+    }
+
+    /// Inverts a square matrix by Gauss-Jordan elimination.
+    fn invert(&self, mut matrix: Vec<Vec<u8>>) -> Result<Vec<Vec<u8>>> {
+        let n = matrix.len();
+        let mut inverse: Vec<Vec<u8>> = (0..n).map(|i| self.row(i, n)).collect();
+        for col in 0..n {
+            let pivot = (col..n)
+                .find(|&r| matrix[r][col] != 0)
+                .ok_or_else(|| anyhow!("Singular matrix."))?;
+            matrix.swap(col, pivot);
+            inverse.swap(col, pivot);
+            let scale = self.inv(matrix[col][col]);
+            for j in 0..n {
+                matrix[col][j] = self.mul(matrix[col][j], scale);
+                inverse[col][j] = self.mul(inverse[col][j], scale);
+            }
+            for r in 0..n {
+                let factor = matrix[r][col];
+                if r != col && factor != 0 {
+                    for j in 0..n {
+                        matrix[r][j] ^= self.mul(factor, matrix[col][j]);
+                        inverse[r][j] ^= self.mul(factor, inverse[col][j]);
+                    }
+                }
+            }
+        }
+        Ok(inverse)
+    }
+
+    /// Returns the sum of `shards` weighted by `coefficients`.
+    fn combine(&self, coefficients: &[u8], shards: &[&[u8]], len: usize) -> Vec<u8> {
+        let mut out = vec![0u8; len];
+        for (&c, shard) in coefficients.iter().zip(shards) {
+            if c != 0 {
+                for (o, &s) in out.iter_mut().zip(shard.iter()) {
+                    *o ^= self.mul(c, s);
+                }
+            }
+        }
+        out
+    }
+}
+
+/// Splits data into K equal shards and adds N - K repair shards with a systematic
+/// Reed-Solomon (Cauchy) erasure code. The receiver rebuilds the data from any K of the N
+/// packets, so lost fragments need no back channel.
+pub struct FecEncoder {
+    gf: Gf256,
+    /// Repair fragments sent for every 100 data fragments, rounded up.
+    overhead_percent: usize,
+    transfer_id: u8,
+}
+
+impl FecEncoder {
+    pub fn new(overhead_percent: usize) -> Self {
+        FecEncoder {
+            gf: Gf256::new(),
+            overhead_percent,
+            transfer_id: 0,
+        }
+    }
+
+    /// Encodes `data` into the packets of one transfer, datagram header included.
+    pub fn encode(&mut self, mut header: Header, data: &[u8]) -> Result<Vec<Vec<u8>>> {
+        let k = data.len().div_ceil(MAX_SHARD).max(1);
+        let repair = (k * self.overhead_percent).div_ceil(100);
+        let n = k + repair;
+        if n > MAX_FRAGMENTS || data.len() > u16::MAX as usize {
+            return Err(anyhow!("Data too large for one transfer."));
+        }
+        // spread the data evenly so the last shard is not mostly padding
+        let shard_len = data.len().div_ceil(k).max(1);
+        let mut padded = data.to_vec();
+        padded.resize(shard_len * k, 0);
+        let shards: Vec<&[u8]> = padded.chunks(shard_len).collect();
+
+        header.flags |= FLAG_FEC;
+        let transfer_id = self.transfer_id;
+        self.transfer_id = self.transfer_id.wrapping_add(1);
+        let length = (data.len() as u16).to_be_bytes();
+        Ok((0..n)
+            .map(|index| {
+                let mut packet = header.to_bytes().to_vec();
+                packet.extend_from_slice(&[transfer_id, index as u8, k as u8, n as u8]);
+                packet.extend_from_slice(&length);
+                if index < k {
+                    packet.extend_from_slice(shards[index]);
+                } else {
+                    let row = self.gf.row(index, k);
+                    packet.extend_from_slice(&self.gf.combine(&row, &shards, shard_len));
+                }
+                packet
+            })
+            .collect())
+    }
+
+    /// Encodes `data` and sends every fragment with `tx_bulk`.
+    pub fn send(&mut self, radio: &mut LoRa, header: Header, data: &[u8]) -> Result<()> {
+        for packet in self.encode(header, data)? {
+            radio.tx_bulk(&packet)?;
+        }
+        Ok(())
+    }
+}
+
+/// Sender address and transfer id.
+type TransferKey = (u8, u8);
+
+/// Fragments collected so far for one transfer.
+struct Transfer {
+    k: usize,
+    length: usize,
+    shards: HashMap<usize, Vec<u8>>,
+}
+
+/// Reassembles transfers from `FecEncoder` fragments as they arrive.
+pub struct FecDecoder {
+    gf: Gf256,
+    transfers: HashMap<TransferKey, Transfer>,
+    /// The most recently completed transfers with their K and length, oldest first. A reused
+    /// id with a different K or length is a new transfer.
+    completed: VecDeque<(TransferKey, usize, usize)>,
+}
+
+impl Default for FecDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FecDecoder {
+    pub fn new() -> Self {
+        FecDecoder {
+            gf: Gf256::new(),
+            transfers: HashMap::new(),
+            completed: VecDeque::new(),
+        }
+    }
+
+    /// Adds a received packet. Returns the sender and the data once K distinct fragments of
+    /// the transfer are in, each transfer is delivered once and its late fragments dropped.
+    pub fn push(&mut self, packet: &[u8]) -> Result<Option<(Header, Vec<u8>)>> {
+        let header = Header::from_bytes(packet)?;
+        if header.flags & FLAG_FEC == 0 {
+            return Err(anyhow!("Not a FEC fragment."));
+        }
+        let fragment = &packet[HEADER_LEN..];
+        if fragment.len() <= FRAGMENT_HEADER_LEN {
+            return Err(anyhow!("Fragment too short."));
+        }
+        let (transfer_id, index, k, n) = (fragment[0], fragment[1] as usize, fragment[2] as usize, fragment[3] as usize);
+        let length = u16::from_be_bytes([fragment[4], fragment[5]]) as usize;
+        if k == 0 || index >= n || k > n {
+            return Err(anyhow!("Bad fragment header."));
+        }
+
+        let key = (header.from, transfer_id);
+        if self.completed.contains(&(key, k, length)) {
+            return Ok(None);
+        }
+        let transfer = self.transfers.entry(key).or_insert_with(|| Transfer {
+            k,
+            length,
+            shards: HashMap::new(),
+        });
+        if transfer.k != k || transfer.length != length {
+            // the id wrapped around onto a stale transfer
+            *transfer = Transfer { k, length, shards: HashMap::new() };
+        }
+        transfer.shards.insert(index, fragment[FRAGMENT_HEADER_LEN..].to_vec());
+        if transfer.shards.len() < k {
+            return Ok(None);
+        }
+
+        let transfer = self.transfers.remove(&key).unwrap();
+        self.completed.retain(|(completed, _, _)| *completed != key);
+        if self.completed.len() == COMPLETED {
+            self.completed.pop_front();
+        }
+        self.completed.push_back((key, k, length));
+        let mut header = header;
+        header.flags &= !FLAG_FEC;
+        Ok(Some((header, self.reconstruct(transfer)?)))
+    }
+
+    fn reconstruct(&self, transfer: Transfer) -> Result<Vec<u8>> {
+        let k = transfer.k;
+        let mut received: Vec<(usize, Vec<u8>)> = transfer.shards.into_iter().take(k).collect();
+        received.sort_by_key(|(index, _)| *index);
+        let shard_len = received[0].1.len();
+        if received.iter().any(|(_, s)| s.len() != shard_len) {
+            return Err(anyhow!("Fragments differ in size."));
+        }
+
+        let mut data = Vec::with_capacity(shard_len * k);
+        if received.iter().enumerate().all(|(i, (index, _))| i == *index) {
+            // every data fragment arrived, nothing to solve
+            for (_, shard) in &received {
+                data.extend_from_slice(shard);
+            }
+        } else {
+            let matrix = received.iter().map(|(index, _)| self.gf.row(*index, k)).collect();
+            let inverse = self.gf.invert(matrix)?;
+            let shards: Vec<&[u8]> = received.iter().map(|(_, s)| &s[..]).collect();
+            for row in &inverse {
+                data.extend_from_slice(&self.gf.combine(row, &shards, shard_len));
+            }
+        }
+        if transfer.length > data.len() {
+            return Err(anyhow!("Transfer length exceeds the fragments."));
+        }
+        data.truncate(transfer.length);
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header() -> Header {
+        Header { to: 2, from: 1, id: 0, flags: 0 }
+    }
+
+    /// Every subset of `size` indices out of `0..n`.
+    fn subsets(n: usize, size: usize) -> Vec<Vec<usize>> {
+        (0u32..1 << n)
+            .filter(|mask| mask.count_ones() as usize == size)
+            .map(|mask| (0..n).filter(|i| mask & (1 << i) != 0).collect())
+            .collect()
+    }
+
+    #[test]
+    fn any_k_of_n_delivers_once() {
+        // K = 1 with two repair fragments and K = 3 with three
+        for (len, overhead) in [(40, 200), (3 * MAX_SHARD - 10, 100)] {
+            let data: Vec<u8> = (0..len).map(|i| (i * 7) as u8).collect();
+            let packets = FecEncoder::new(overhead).encode(header(), &data).unwrap();
+            let (k, n) = (packets[0][HEADER_LEN + 2] as usize, packets.len());
+            assert!(n > k);
+
+            for subset in subsets(n, k) {
+                let mut decoder = FecDecoder::new();
+                let mut delivered = Vec::new();
+                // the subset first, then every fragment again, late and duplicated
+                for index in subset.iter().copied().chain(0..n) {
+                    if let Some((from, received)) = decoder.push(&packets[index]).unwrap() {
+                        assert_eq!(from, header());
+                        delivered.push(received);
+                    }
+                }
+                assert_eq!(delivered, vec![data.clone()], "subset {:?}", subset);
+                assert!(decoder.transfers.is_empty());
+            }
+        }
+    }
+
+    #[test]
+    fn fewer_than_k_is_not_delivered() {
+        let packets = FecEncoder::new(50).encode(header(), &[1; 3 * MAX_SHARD]).unwrap();
+        let mut decoder = FecDecoder::new();
+        assert!(decoder.push(&packets[0]).unwrap().is_none());
+        assert!(decoder.push(&packets[4]).unwrap().is_none());
+        assert!(decoder.push(&packets[4]).unwrap().is_none());
+        assert!(decoder.push(&packets[1]).unwrap().is_some());
+    }
+
+    #[test]
+    fn completed_transfers_are_bounded() {
+        let mut encoder = FecEncoder::new(100);
+        let mut decoder = FecDecoder::new();
+        let mut first = None;
+        for i in 0..COMPLETED + 1 {
+            let packets = encoder.encode(header(), &[i as u8; 10]).unwrap();
+            assert!(decoder.push(&packets[0]).unwrap().is_some());
+            first.get_or_insert(packets);
+        }
+        assert_eq!(decoder.completed.len(), COMPLETED);
+        // the first transfer was forgotten, so its id can be used again
+        assert!(decoder.push(&first.unwrap()[1]).unwrap().is_some());
+    }
+}
@@ -0,0 +1,623 @@
+use anyhow::{anyhow, Result};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use serde_json::{json, Value};
+use std::net::UdpSocket;
+use std::time::{Duration, Instant};
+
+use crate::rfm96w::{LoRa, TxPower};
+
+// Semtech GWMP (packet forwarder UDP protocol) version 2.
+const PROTOCOL_VERSION: u8 = 2;
+const PUSH_DATA: u8 = 0x00;
+const PUSH_ACK: u8 = 0x01;
+const PULL_DATA: u8 = 0x02;
+const PULL_RESP: u8 = 0x03;
+const PULL_ACK: u8 = 0x04;
+const TX_ACK: u8 = 0x05;
+
+const POLL_MS: i32 = 10;
+const STAT_INTERVAL: Duration = Duration::from_secs(30);
+// Downlinks scheduled further ahead than this are rejected, like the reference forwarder.
+const MAX_SCHEDULE_AHEAD_US: i32 = 3_000_000;
+
+/// Settings of the single-channel gateway.
+pub struct GatewayConfig {
+    /// Address of the network server, e.g. `127.0.0.1:1700`.
+    pub server: String,
+    pub gateway_eui: [u8; 8],
+    /// Interval between `PULL_DATA` keepalives.
+    pub keepalive: Duration,
+    /// Power used for downlinks that do not specify one, in dBm.
+    pub tx_power: i32,
+}
+
+impl GatewayConfig {
+    /// Builds a gateway EUI from the MAC address of `interface` by inserting `FFFE` in the
+    /// middle, falling back to all zeros if the interface does not exist.
+    pub fn eui_from_interface(interface: &str) -> [u8; 8] {
+        let mut eui = [0u8; 8];
+        if let Ok(mac) = std::fs::read_to_string(format!("/sys/class/net/{}/address", interface)) {
+            let bytes: Vec<u8> = mac
+                .trim()
+                .split(':')
+                .filter_map(|b| u8::from_str_radix(b, 16).ok())
+                .collect();
+            if bytes.len() == 6 {
+                eui[..3].copy_from_slice(&bytes[..3]);
+                eui[3] = 0xff;
+                eui[4] = 0xfe;
+                eui[5..].copy_from_slice(&bytes[3..]);
+            }
+        }
+        eui
+    }
+
+    /// Parses a gateway EUI written as 16 hex digits.
+    pub fn parse_eui(hex: &str) -> Result<[u8; 8]> {
+        if hex.len() != 16 || !hex.is_ascii() {
+            return Err(anyhow!("Gateway EUI must be 16 hex digits."));
+        }
+        let mut eui = [0u8; 8];
+        for (i, byte) in eui.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)?;
+        }
+        Ok(eui)
+    }
+}
+
+/// Receive settings the radio returns to after every downlink.
+struct Channel {
+    frequency: u64,
+    spreading_factor: u8,
+    bandwidth: i64,
+    coding_rate: u8,
+}
+
+/// A downlink taken from a `PULL_RESP`, waiting for its transmit time.
+struct Downlink {
+    /// `None` for immediate transmission.
+    tmst: Option<u32>,
+    frequency: u64,
+    spreading_factor: u8,
+    bandwidth: i64,
+    coding_rate: u8,
+    invert_iq: bool,
+    power: i32,
+    payload: Vec<u8>,
+}
+
+#[derive(Default)]
+struct Stats {
+    rxnb: u32,
+    rxok: u32,
+    rxfw: u32,
+    pushed: u32,
+    acked: u32,
+    dwnb: u32,
+    txnb: u32,
+}
+
+/// A datagram from the network server.
+enum ServerMessage {
+    PushAck,
+    PullAck,
+    PullResp { token: [u8; 2], body: Vec<u8> },
+}
+
+/// The UDP side of the forwarder, speaking GWMP to the network server.
+struct Link {
+    socket: UdpSocket,
+    gateway_eui: [u8; 8],
+    token: u16,
+}
+
+impl Link {
+    fn connect(server: &str, gateway_eui: [u8; 8]) -> Result<Self> {
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        socket.connect(server)?;
+        socket.set_nonblocking(true)?;
+        Ok(Link { socket, gateway_eui, token: 0 })
+    }
+
+    /// Version, the next token, the identifier and the gateway EUI.
+    fn header(&mut self, identifier: u8) -> Vec<u8> {
+        self.token = self.token.wrapping_add(1);
+        let mut header = vec![PROTOCOL_VERSION];
+        header.extend_from_slice(&self.token.to_be_bytes());
+        header.push(identifier);
+        header.extend_from_slice(&self.gateway_eui);
+        header
+    }
+
+    fn send_header(&mut self, identifier: u8) -> Result<()> {
+        let packet = self.header(identifier);
+        self.socket.send(&packet)?;
+        Ok(())
+    }
+
+    fn push(&mut self, body: &Value) -> Result<()> {
+        let mut packet = self.header(PUSH_DATA);
+        packet.extend_from_slice(body.to_string().as_bytes());
+        self.socket.send(&packet)?;
+        Ok(())
+    }
+
+    fn send_tx_ack(&mut self, token: [u8; 2], error: &str) -> Result<()> {
+        let mut packet = vec![PROTOCOL_VERSION, token[0], token[1], TX_ACK];
+        packet.extend_from_slice(&self.gateway_eui);
+        packet.extend_from_slice(json!({ "txpk_ack": { "error": error } }).to_string().as_bytes());
+        self.socket.send(&packet)?;
+        Ok(())
+    }
+
+    /// Returns the next datagram waiting on the socket, skipping ones that are not GWMP v2.
+    fn receive(&mut self) -> Result<Option<ServerMessage>> {
+        let mut buffer = [0u8; 2048];
+        loop {
+            let len = match self.socket.recv(&mut buffer) {
+                Ok(len) => len,
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => return Ok(None),
+                // The server not listening yet shows up as a refused connection.
+                Err(e) if e.kind() == std::io::ErrorKind::ConnectionRefused => return Ok(None),
+                Err(e) => return Err(e.into()),
+            };
+            if len < 4 || buffer[0] != PROTOCOL_VERSION {
+                continue;
+            }
+            match buffer[3] {
+                PUSH_ACK => return Ok(Some(ServerMessage::PushAck)),
+                PULL_ACK => return Ok(Some(ServerMessage::PullAck)),
+                PULL_RESP => {
+                    let token = [buffer[1], buffer[2]];
+                    return Ok(Some(ServerMessage::PullResp { token, body: buffer[4..len].to_vec() }));
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// A single-channel packet forwarder. Received frames are pushed to the network server with
+/// `PUSH_DATA`, downlinks arrive as `PULL_RESP` and are sent at the requested concentrator time.
+pub struct Gateway<'a> {
+    radio: &'a mut LoRa,
+    config: GatewayConfig,
+    link: Link,
+    channel: Channel,
+    start: Instant,
+    queue: Vec<Downlink>,
+    stats: Stats,
+}
+
+impl<'a> Gateway<'a> {
+    pub fn new(radio: &'a mut LoRa, config: GatewayConfig) -> Result<Self> {
+        let link = Link::connect(&config.server, config.gateway_eui)?;
+        let channel = Channel {
+            frequency: radio.get_frequency(),
+            spreading_factor: radio.get_spreading_factor()?,
+            bandwidth: radio.get_signal_bandwidth()?,
+            coding_rate: radio.get_coding_rate_4()?,
+        };
+        Ok(Gateway {
+            radio,
+            config,
+            link,
+            channel,
+            start: Instant::now(),
+            queue: Vec::new(),
+            stats: Stats::default(),
+        })
+    }
+
+    /// Runs the forwarder until an I/O error occurs.
+    pub fn run(&mut self) -> Result<()> {
+        let mut last_pull = None;
+        let mut last_stat = Instant::now();
+        loop {
+            if last_pull.is_none_or(|t: Instant| t.elapsed() >= self.config.keepalive) {
+                self.link.send_header(PULL_DATA)?;
+                last_pull = Some(Instant::now());
+            }
+            if last_stat.elapsed() >= STAT_INTERVAL {
+                self.push_stat()?;
+                last_stat = Instant::now();
+            }
+
+            self.receive_server()?;
+            self.transmit_due()?;
+
+            // Never poll past the next scheduled downlink.
+            let timeout = self.next_downlink_ms().map_or(POLL_MS, |ms| ms.clamp(1, POLL_MS));
+            if let Ok(size) = self.radio.poll_irq(Some(timeout)) {
+                self.forward_uplink(size)?;
+            }
+        }
+    }
+
+    /// Concentrator counter, microseconds since the gateway started. Wraps like the SX1301's.
+    fn tmst(&self) -> u32 {
+        self.start.elapsed().as_micros() as u32
+    }
+
+    fn push(&mut self, body: &Value) -> Result<()> {
+        self.link.push(body)?;
+        self.stats.pushed += 1;
+        Ok(())
+    }
+
+    fn forward_uplink(&mut self, size: usize) -> Result<()> {
+        let tmst = self.tmst();
+        let crc_error = self.radio.crc_error();
+        let buffer = self.radio.read_packet()?;
+        self.stats.rxnb += 1;
+        if crc_error {
+            println!("RX {} bytes, CRC error.", size);
+            return Ok(());
+        }
+        self.stats.rxok += 1;
+
+        let rssi = self.radio.get_packet_rssi()?;
+        let snr = self.radio.get_packet_snr()?;
+        let rxpk = json!({
+            "tmst": tmst,
+            "chan": 0,
+            "rfch": 0,
+            "freq": self.channel.frequency as f64 / 1e6,
+            "stat": 1,
+            "modu": "LORA",
+            "datr": datarate(self.channel.spreading_factor, self.channel.bandwidth),
+            "codr": format!("4/{}", self.channel.coding_rate),
+            "rssi": rssi.round() as i32,
+            "lsnr": snr,
+            "size": size,
+            "data": BASE64.encode(&buffer[..size]),
+        });
+        self.push(&json!({ "rxpk": [rxpk] }))?;
+        self.stats.rxfw += 1;
+        println!("RX {} bytes, RSSI {:.0} dBm, SNR {:.1} dB, forwarded.", size, rssi, snr);
+        Ok(())
+    }
+
+    fn push_stat(&mut self) -> Result<()> {
+        let ackr = if self.stats.pushed == 0 {
+            0.0
+        } else {
+            100.0 * self.stats.acked as f64 / self.stats.pushed as f64
+        };
+        let stat = json!({
+            "rxnb": self.stats.rxnb,
+            "rxok": self.stats.rxok,
+            "rxfw": self.stats.rxfw,
+            "ackr": ackr,
+            "dwnb": self.stats.dwnb,
+            "txnb": self.stats.txnb,
+        });
+        self.push(&json!({ "stat": stat }))
+    }
+
+    /// Handles every datagram waiting on the socket.
+    fn receive_server(&mut self) -> Result<()> {
+        while let Some(message) = self.link.receive()? {
+            match message {
+                ServerMessage::PushAck => self.stats.acked += 1,
+                ServerMessage::PullAck => {}
+                ServerMessage::PullResp { token, body } => {
+                    self.stats.dwnb += 1;
+                    let error = match parse_pull_resp(&body, self.config.tx_power) {
+                        Ok(downlink) => {
+                            let now = self.tmst();
+                            schedule(&mut self.queue, downlink, now)
+                        }
+                        Err(e) => {
+                            println!("Bad PULL_RESP: {}", e);
+                            "TX_FREQ"
+                        }
+                    };
+                    self.link.send_tx_ack(token, error)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Milliseconds until the earliest scheduled downlink.
+    fn next_downlink_ms(&self) -> Option<i32> {
+        let now = self.tmst();
+        self.queue
+            .iter()
+            .map(|d| d.tmst.map_or(0, |t| t.wrapping_sub(now) as i32 / 1000))
+            .min()
+    }
+
+    fn transmit_due(&mut self) -> Result<()> {
+        let now = self.tmst();
+        while let Some(downlink) = take_due(&mut self.queue, now) {
+            self.transmit(&downlink)?;
+        }
+        Ok(())
+    }
+
+    fn transmit(&mut self, downlink: &Downlink) -> Result<()> {
+        self.radio.set_frequency_hz(downlink.frequency)?;
+        self.radio.set_spreading_factor(downlink.spreading_factor)?;
+        self.radio.set_signal_bandwidth(downlink.bandwidth)?;
+        self.radio.set_coding_rate_4(downlink.coding_rate)?;
+        self.radio.set_invert_iq(downlink.invert_iq)?;
+        self.radio.set_tx_power(TxPower::pa_boost(downlink.power))?;
+
+        let mut buffer = [0u8; 255];
+        buffer[..downlink.payload.len()].copy_from_slice(&downlink.payload);
+        self.radio.transmit_payload_busy(buffer, downlink.payload.len())?;
+        self.stats.txnb += 1;
+        println!("TX {} bytes at {} Hz.", downlink.payload.len(), downlink.frequency);
+
+        // back to the uplink channel
+        self.radio.set_frequency_hz(self.channel.frequency)?;
+        self.radio.set_spreading_factor(self.channel.spreading_factor)?;
+        self.radio.set_signal_bandwidth(self.channel.bandwidth)?;
+        self.radio.set_coding_rate_4(self.channel.coding_rate)?;
+        self.radio.set_invert_iq(false)?;
+        self.radio.set_tx_power(TxPower::pa_boost(self.config.tx_power))
+    }
+}
+
+/// Reads the `txpk` of a `PULL_RESP`, using `default_power` when it gives none.
+fn parse_pull_resp(body: &[u8], default_power: i32) -> Result<Downlink> {
+    let value: Value = serde_json::from_slice(body)?;
+    let txpk = value.get("txpk").ok_or_else(|| anyhow!("missing txpk"))?;
+    let immediate = txpk["imme"].as_bool().unwrap_or(false);
+    let tmst = match txpk["tmst"].as_u64() {
+        Some(t) if !immediate => Some(t as u32),
+        _ if immediate => None,
+        _ => return Err(anyhow!("missing tmst")),
+    };
+    let frequency = (txpk["freq"].as_f64().ok_or_else(|| anyhow!("missing freq"))? * 1e6).round() as u64;
+    let (spreading_factor, bandwidth) =
+        parse_datarate(txpk["datr"].as_str().ok_or_else(|| anyhow!("missing datr"))?)?;
+    let coding_rate = txpk["codr"]
+        .as_str()
+        .and_then(|c| c.strip_prefix("4/"))
+        .and_then(|d| d.parse().ok())
+        .unwrap_or(5);
+    let payload = BASE64.decode(txpk["data"].as_str().ok_or_else(|| anyhow!("missing data"))?)?;
+    Ok(Downlink {
+        tmst,
+        frequency,
+        spreading_factor,
+        bandwidth,
+        coding_rate,
+        // LoRaWAN downlinks are sent with inverted IQ unless told otherwise.
+        invert_iq: txpk["ipol"].as_bool().unwrap_or(true),
+        // the server may ask for more than PA_BOOST can do, send at its limit
+        power: txpk["powe"].as_i64().map_or(default_power, |p| p.clamp(2, 20) as i32),
+        payload,
+    })
+}
+
+/// Queues a downlink at concentrator time `now`, returning the `txpk_ack` error code.
+fn schedule(queue: &mut Vec<Downlink>, downlink: Downlink, now: u32) -> &'static str {
+    if downlink.payload.len() > 255 {
+        return "TOO_LARGE";
+    }
+    if let Some(tmst) = downlink.tmst {
+        let ahead = tmst.wrapping_sub(now) as i32;
+        if ahead < 0 {
+            return "TOO_LATE";
+        }
+        if ahead > MAX_SCHEDULE_AHEAD_US {
+            return "TOO_EARLY";
+        }
+        if queue.iter().any(|d| d.tmst == Some(tmst)) {
+            return "COLLISION_PACKET";
+        }
+    }
+    queue.push(downlink);
+    "NONE"
+}
+
+/// Removes the first downlink whose time has come, immediate ones always have.
+fn take_due(queue: &mut Vec<Downlink>, now: u32) -> Option<Downlink> {
+    let index = queue
+        .iter()
+        .position(|d| d.tmst.is_none_or(|t| t.wrapping_sub(now) as i32 <= 0))?;
+    Some(queue.remove(index))
+}
+
+/// Formats a LoRa datarate identifier, e.g. `SF7BW125`.
+pub fn datarate(spreading_factor: u8, bandwidth: i64) -> String {
+    format!("SF{}BW{}", spreading_factor, bandwidth / 1000)
+}
+
+/// Parses a LoRa datarate identifier into its spreading factor and bandwidth in hertz.
+pub fn parse_datarate(datr: &str) -> Result<(u8, i64)> {
+    let rest = datr.strip_prefix("SF").ok_or_else(|| anyhow!("bad datr {}", datr))?;
+    let (sf, bw) = rest.split_once("BW").ok_or_else(|| anyhow!("bad datr {}", datr))?;
+    let bw: i64 = bw.parse()?;
+    // 7.8, 10.4, ... are written as whole kHz by some servers
+    let bw = match bw {
+        7 => 7_800,
+        10 => 10_400,
+        15 => 15_600,
+        20 => 20_800,
+        31 => 31_250,
+        41 => 41_700,
+        62 => 62_500,
+        _ => bw * 1000,
+    };
+    Ok((sf.parse()?, bw))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::register::Bandwidth;
+
+    fn downlink(tmst: Option<u32>) -> Downlink {
+        Downlink {
+            tmst,
+            frequency: 433_175_000,
+            spreading_factor: 7,
+            bandwidth: 125_000,
+            coding_rate: 5,
+            invert_iq: true,
+            power: 14,
+            payload: vec![0; 12],
+        }
+    }
+
+    /// Waits briefly for a datagram on a non-blocking link.
+    fn receive(link: &mut Link) -> ServerMessage {
+        for _ in 0..100 {
+            if let Some(message) = link.receive().unwrap() {
+                return message;
+            }
+            std::thread::sleep(Duration::from_millis(2));
+        }
+        panic!("nothing received");
+    }
+
+    #[test]
+    fn parse_eui() {
+        assert_eq!(
+            GatewayConfig::parse_eui("0011223344aabbff").unwrap(),
+            [0x00, 0x11, 0x22, 0x33, 0x44, 0xaa, 0xbb, 0xff]
+        );
+        assert!(GatewayConfig::parse_eui("0011").is_err());
+        assert!(GatewayConfig::parse_eui("00112233445566zz").is_err());
+        // 16 bytes but not 16 characters
+        assert!(GatewayConfig::parse_eui("éééééééé").is_err());
+    }
+
+    #[test]
+    fn datarate_round_trip() {
+        for bits in 0..10 {
+            let bandwidth = Bandwidth::from_bits(bits).unwrap().hz();
+            for spreading_factor in 6..=12 {
+                let datr = datarate(spreading_factor, bandwidth);
+                assert_eq!(parse_datarate(&datr).unwrap(), (spreading_factor, bandwidth), "{}", datr);
+            }
+        }
+        assert_eq!(datarate(7, 125_000), "SF7BW125");
+        assert!(parse_datarate("SF7").is_err());
+        assert!(parse_datarate("BW125").is_err());
+        assert!(parse_datarate("SFxBW125").is_err());
+    }
+
+    #[test]
+    fn pull_resp() {
+        let body = json!({ "txpk": {
+            "tmst": 1_000_000, "freq": 433.175, "rfch": 0, "powe": 14, "modu": "LORA",
+            "datr": "SF9BW125", "codr": "4/6", "ipol": false, "size": 3, "data": "AQID",
+        }});
+        let downlink = parse_pull_resp(body.to_string().as_bytes(), 17).unwrap();
+        assert_eq!(downlink.tmst, Some(1_000_000));
+        assert_eq!(downlink.frequency, 433_175_000);
+        assert_eq!((downlink.spreading_factor, downlink.bandwidth), (9, 125_000));
+        assert_eq!(downlink.coding_rate, 6);
+        assert!(!downlink.invert_iq);
+        assert_eq!(downlink.power, 14);
+        assert_eq!(downlink.payload, [1, 2, 3]);
+
+        let body = json!({ "txpk": {
+            "imme": true, "freq": 433.175, "powe": 27, "datr": "SF7BW125", "data": "",
+        }});
+        let downlink = parse_pull_resp(body.to_string().as_bytes(), 17).unwrap();
+        assert_eq!(downlink.tmst, None);
+        assert_eq!(downlink.power, 20);
+        assert_eq!(downlink.coding_rate, 5);
+        assert!(downlink.invert_iq);
+
+        let body = json!({ "txpk": { "imme": true, "freq": 433.175, "datr": "SF7BW125", "data": "" }});
+        assert_eq!(parse_pull_resp(body.to_string().as_bytes(), 17).unwrap().power, 17);
+
+        let no_tmst = json!({ "txpk": { "freq": 433.175, "datr": "SF7BW125", "data": "" }});
+        assert!(parse_pull_resp(no_tmst.to_string().as_bytes(), 17).is_err());
+        assert!(parse_pull_resp(b"{}", 17).is_err());
+        assert!(parse_pull_resp(b"not json", 17).is_err());
+    }
+
+    #[test]
+    fn scheduling() {
+        let mut queue = Vec::new();
+        let now = 5_000_000;
+        assert_eq!(schedule(&mut queue, downlink(Some(now - 1)), now), "TOO_LATE");
+        assert_eq!(schedule(&mut queue, downlink(Some(now + 3_000_001)), now), "TOO_EARLY");
+        let mut large = downlink(None);
+        large.payload = vec![0; 256];
+        assert_eq!(schedule(&mut queue, large, now), "TOO_LARGE");
+        assert!(queue.is_empty());
+
+        assert_eq!(schedule(&mut queue, downlink(Some(now + 2_000)), now), "NONE");
+        assert_eq!(schedule(&mut queue, downlink(Some(now + 2_000)), now), "COLLISION_PACKET");
+        assert_eq!(schedule(&mut queue, downlink(Some(now + 1_000)), now), "NONE");
+        assert_eq!(schedule(&mut queue, downlink(None), now), "NONE");
+
+        // the immediate one first, then each as its time comes
+        assert_eq!(take_due(&mut queue, now).unwrap().tmst, None);
+        assert!(take_due(&mut queue, now).is_none());
+        assert_eq!(take_due(&mut queue, now + 1_000).unwrap().tmst, Some(now + 1_000));
+        assert!(take_due(&mut queue, now + 1_999).is_none());
+        assert_eq!(take_due(&mut queue, now + 2_500).unwrap().tmst, Some(now + 2_000));
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn scheduling_across_counter_wrap() {
+        let mut queue = Vec::new();
+        let now = u32::MAX - 500;
+        let tmst = now.wrapping_add(1_000);
+        assert_eq!(schedule(&mut queue, downlink(Some(tmst)), now), "NONE");
+        assert!(take_due(&mut queue, now).is_none());
+        assert_eq!(take_due(&mut queue, tmst).unwrap().tmst, Some(tmst));
+    }
+
+    /// Exchanges GWMP datagrams with a UDP socket standing in for the network server.
+    #[test]
+    fn server_exchange() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        server.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        let eui = [1, 2, 3, 4, 5, 6, 7, 8];
+        let mut link = Link::connect(&server.local_addr().unwrap().to_string(), eui).unwrap();
+        let mut buffer = [0u8; 2048];
+
+        link.send_header(PULL_DATA).unwrap();
+        let (len, gateway) = server.recv_from(&mut buffer).unwrap();
+        assert_eq!(len, 12);
+        assert_eq!(buffer[0], PROTOCOL_VERSION);
+        assert_eq!(buffer[3], PULL_DATA);
+        assert_eq!(buffer[4..12], eui);
+        server.send_to(&[PROTOCOL_VERSION, buffer[1], buffer[2], PULL_ACK], gateway).unwrap();
+        assert!(matches!(receive(&mut link), ServerMessage::PullAck));
+
+        let rxpk = json!({ "rxpk": [{ "size": 3, "data": "AQID" }] });
+        link.push(&rxpk).unwrap();
+        let (len, _) = server.recv_from(&mut buffer).unwrap();
+        assert_eq!(buffer[3], PUSH_DATA);
+        assert_eq!(buffer[4..12], eui);
+        assert_eq!(serde_json::from_slice::<Value>(&buffer[12..len]).unwrap(), rxpk);
+        // every datagram gets a new token
+        assert_eq!(u16::from_be_bytes([buffer[1], buffer[2]]), 2);
+        server.send_to(&[PROTOCOL_VERSION, buffer[1], buffer[2], PUSH_ACK], gateway).unwrap();
+        assert!(matches!(receive(&mut link), ServerMessage::PushAck));
+
+        let txpk = json!({ "txpk": { "imme": true, "freq": 433.175, "datr": "SF7BW125", "data": "AQID" }});
+        let mut pull_resp = vec![PROTOCOL_VERSION, 0xab, 0xcd, PULL_RESP];
+        pull_resp.extend_from_slice(txpk.to_string().as_bytes());
+        // datagrams of other protocol versions are skipped
+        server.send_to(&[1, 0, 0, PUSH_ACK], gateway).unwrap();
+        server.send_to(&pull_resp, gateway).unwrap();
+        let ServerMessage::PullResp { token, body } = receive(&mut link) else {
+            panic!("expected PULL_RESP");
+        };
+        assert_eq!(token, [0xab, 0xcd]);
+        assert_eq!(parse_pull_resp(&body, 14).unwrap().payload, [1, 2, 3]);
+
+        link.send_tx_ack(token, "NONE").unwrap();
+        let (len, _) = server.recv_from(&mut buffer).unwrap();
+        assert_eq!(buffer[..4], [PROTOCOL_VERSION, 0xab, 0xcd, TX_ACK]);
+        assert_eq!(buffer[4..12], eui);
+        let ack: Value = serde_json::from_slice(&buffer[12..len]).unwrap();
+        assert_eq!(ack["txpk_ack"]["error"], "NONE");
+    }
+}
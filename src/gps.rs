@@ -0,0 +1,239 @@
+use anyhow::Result;
+use serde_json::Value;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+const EARTH_RADIUS_M: f64 = 6_371_000.0;
+
+#[derive(Clone, Copy)]
+pub struct Position {
+    /// Degrees, north and east positive.
+    pub latitude: f64,
+    pub longitude: f64,
+    /// Metres above mean sea level.
+    pub altitude: f64,
+}
+
+impl Position {
+    /// Great circle distance in metres.
+    pub fn distance(&self, other: &Position) -> f64 {
+        let (lat1, lat2) = (self.latitude.to_radians(), other.latitude.to_radians());
+        let dlat = lat2 - lat1;
+        let dlon = (other.longitude - self.longitude).to_radians();
+        let a = (dlat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (dlon / 2.0).sin().powi(2);
+        2.0 * EARTH_RADIUS_M * a.sqrt().asin()
+    }
+}
+
+pub enum GpsSource {
+    /// A gpsd address, e.g. `127.0.0.1:2947`.
+    Gpsd(String),
+    /// A serial device or a file of NMEA sentences. Files are followed like `tail -f`.
+    Nmea(PathBuf),
+}
+
+/// The latest fix from a GPS, kept up to date by a background thread.
+#[derive(Clone)]
+pub struct Gps {
+    fix: Arc<Mutex<Option<Position>>>,
+}
+
+impl Gps {
+    pub fn start(source: GpsSource) -> Result<Self> {
+        let gps = Gps { fix: Arc::new(Mutex::new(None)) };
+        let fix = gps.fix.clone();
+        match source {
+            GpsSource::Gpsd(address) => {
+                let mut stream = TcpStream::connect(&address)?;
+                stream.write_all(b"?WATCH={\"enable\":true,\"json\":true};\n")?;
+                thread::spawn(move || {
+                    for line in BufReader::new(stream).lines() {
+                        let Ok(line) = line else { break };
+                        if let Some(position) = parse_gpsd(&line) {
+                            *fix.lock().unwrap() = Some(position);
+                        }
+                    }
+                });
+            }
+            GpsSource::Nmea(path) => {
+                let mut reader = BufReader::new(File::open(path)?);
+                thread::spawn(move || {
+                    let mut line = String::new();
+                    loop {
+                        match reader.read_line(&mut line) {
+                            // end of a file, or a line still being written, wait for more
+                            Ok(0) => thread::sleep(Duration::from_millis(200)),
+                            Ok(_) if !line.ends_with('\n') => thread::sleep(Duration::from_millis(200)),
+                            Ok(_) => {
+                                if let Some(position) = parse_nmea(line.trim()) {
+                                    *fix.lock().unwrap() = Some(position);
+                                }
+                                line.clear();
+                            }
+                            Err(_) => break,
+                        }
+                    }
+                });
+            }
+        }
+        Ok(gps)
+    }
+
+    pub fn position(&self) -> Option<Position> {
+        *self.fix.lock().unwrap()
+    }
+}
+
+/// Reads a gpsd TPV report with at least a 2D fix.
+fn parse_gpsd(line: &str) -> Option<Position> {
+    let report: Value = serde_json::from_str(line).ok()?;
+    if report["class"] != "TPV" || report["mode"].as_u64()? < 2 {
+        return None;
+    }
+    Some(Position {
+        latitude: report["lat"].as_f64()?,
+        longitude: report["lon"].as_f64()?,
+        altitude: report["altMSL"].as_f64().or(report["alt"].as_f64()).unwrap_or(0.0),
+    })
+}
+
+/// Reads a GGA sentence from any talker (`$GPGGA`, `$GNGGA`, ...) with a valid fix, checking
+/// the checksum when one is present.
+pub fn parse_nmea(sentence: &str) -> Option<Position> {
+    let sentence = sentence.strip_prefix('$')?;
+    let (body, checksum) = match sentence.split_once('*') {
+        Some((body, checksum)) => (body, Some(checksum)),
+        None => (sentence, None),
+    };
+    if let Some(checksum) = checksum {
+        let expected = u8::from_str_radix(checksum.get(..2)?, 16).ok()?;
+        if body.bytes().fold(0, |sum, b| sum ^ b) != expected {
+            return None;
+        }
+    }
+    let fields: Vec<&str> = body.split(',').collect();
+    if fields.len() < 10 || !fields[0].ends_with("GGA") || fields[6].parse::<u8>().ok()? == 0 {
+        return None;
+    }
+    // ddmm.mmmm and dddmm.mmmm
+    let coordinate = |value: &str, degree_digits: usize, hemisphere: &str| -> Option<f64> {
+        let degrees: f64 = value.get(..degree_digits)?.parse().ok()?;
+        let minutes: f64 = value.get(degree_digits..)?.parse().ok()?;
+        let sign = if hemisphere == "S" || hemisphere == "W" { -1.0 } else { 1.0 };
+        Some(sign * (degrees + minutes / 60.0))
+    };
+    Some(Position {
+        latitude: coordinate(fields[2], 2, fields[3])?,
+        longitude: coordinate(fields[4], 3, fields[5])?,
+        altitude: fields[9].parse().unwrap_or(0.0),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GGA: &str = "$GPGGA,123519,4807.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,*47";
+
+    fn assert_close(a: f64, b: f64) {
+        assert!((a - b).abs() < 1e-9, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn nmea_gga() {
+        let position = parse_nmea(GGA).unwrap();
+        assert_close(position.latitude, 48.0 + 7.038 / 60.0);
+        assert_close(position.longitude, 11.0 + 31.0 / 60.0);
+        assert_close(position.altitude, 545.4);
+        // other talkers and sentences without a checksum
+        assert!(parse_nmea("$GNGGA,123519,4807.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,").is_some());
+        assert!(parse_nmea("$GPRMC,123519,A,4807.038,N,01131.000,E,022.4,084.4,230394,003.1,W*6A").is_none());
+    }
+
+    #[test]
+    fn nmea_checksum() {
+        assert!(parse_nmea(&GGA.replace("*47", "*48")).is_none());
+        assert!(parse_nmea(&GGA.replace("545.4", "545.5")).is_none());
+        assert!(parse_nmea(&GGA.replace("*47", "*")).is_none());
+        assert!(parse_nmea(&GGA[1..]).is_none());
+    }
+
+    #[test]
+    fn nmea_hemispheres() {
+        let sentence = |ns: &str, ew: &str| {
+            let body = format!("GPGGA,123519,3351.000,{},15112.000,{},1,08,0.9,25.0,M,,M,,", ns, ew);
+            let checksum = body.bytes().fold(0, |sum, b| sum ^ b);
+            parse_nmea(&format!("${}*{:02X}", body, checksum)).unwrap()
+        };
+        let (latitude, longitude) = (33.0 + 51.0 / 60.0, 151.0 + 12.0 / 60.0);
+        let hemispheres = [("N", "E", 1.0, 1.0), ("S", "E", -1.0, 1.0), ("N", "W", 1.0, -1.0), ("S", "W", -1.0, -1.0)];
+        for (ns, ew, lat_sign, lon_sign) in hemispheres {
+            let position = sentence(ns, ew);
+            assert_close(position.latitude, lat_sign * latitude);
+            assert_close(position.longitude, lon_sign * longitude);
+        }
+    }
+
+    #[test]
+    fn nmea_without_a_fix() {
+        assert!(parse_nmea("$GPGGA,123519,4807.038,N,01131.000,E,0,00,,,M,,M,,").is_none());
+        assert!(parse_nmea("$GPGGA,123519,,,,,1,08,0.9,545.4,M,46.9,M,,").is_none());
+    }
+
+    #[test]
+    fn gpsd_tpv() {
+        let tpv = r#"{"class":"TPV","device":"/dev/ttyACM0","mode":3,"lat":51.5007,"lon":-0.1246,"alt":60.1,"altMSL":14.2}"#;
+        let position = parse_gpsd(tpv).unwrap();
+        assert_close(position.latitude, 51.5007);
+        assert_close(position.longitude, -0.1246);
+        assert_close(position.altitude, 14.2);
+        let position = parse_gpsd(r#"{"class":"TPV","mode":2,"lat":1.5,"lon":2.5}"#).unwrap();
+        assert_close(position.altitude, 0.0);
+        // no fix, other reports and garbage
+        assert!(parse_gpsd(r#"{"class":"TPV","mode":1}"#).is_none());
+        assert!(parse_gpsd(r#"{"class":"SKY","mode":3,"lat":1.5,"lon":2.5}"#).is_none());
+        assert!(parse_gpsd("not json").is_none());
+    }
+
+    #[test]
+    fn distance() {
+        let at = |latitude, longitude| Position { latitude, longitude, altitude: 0.0 };
+        let london = at(51.5007, -0.1246);
+        let paris = at(48.8584, 2.2945);
+        assert!((london.distance(&paris) - 340_000.0).abs() < 2_000.0);
+        assert_close(london.distance(&paris), paris.distance(&london));
+        assert_close(london.distance(&london), 0.0);
+        // a degree of latitude and a quarter of the way around the equator
+        assert!((at(0.0, 0.0).distance(&at(1.0, 0.0)) - 111_195.0).abs() < 1.0);
+        assert!((at(0.0, 0.0).distance(&at(0.0, 90.0)) - EARTH_RADIUS_M * std::f64::consts::FRAC_PI_2).abs() < 1e-6);
+    }
+
+    #[test]
+    fn nmea_file_is_followed_by_line() {
+        let path = std::env::temp_dir().join(format!("rora-nmea-{}", std::process::id()));
+        // the altitude is cut off, parsing this alone would give 545 m
+        let sentence = "$GPGGA,123519,4807.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,\n";
+        let (start, rest) = sentence.split_at(sentence.find("545.").unwrap() + 4);
+        std::fs::write(&path, start).unwrap();
+        let gps = Gps::start(GpsSource::Nmea(path.clone())).unwrap();
+        thread::sleep(Duration::from_millis(300));
+        assert!(gps.position().is_none());
+
+        std::fs::OpenOptions::new().append(true).open(&path).unwrap().write_all(rest.as_bytes()).unwrap();
+        let mut position = None;
+        for _ in 0..20 {
+            position = gps.position();
+            if position.is_some() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        assert_close(position.expect("no fix").altitude, 545.4);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
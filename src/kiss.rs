@@ -0,0 +1,344 @@
+use anyhow::{anyhow, Result};
+use std::ffi::CStr;
+use std::fs::File;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::TcpListener;
+use std::os::unix::io::FromRawFd;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::lbt::ListenBeforeTalk;
+use crate::rfm96w::LoRa;
+
+// KISS framing (K3MC & KA9Q).
+const FEND: u8 = 0xc0;
+const FESC: u8 = 0xdb;
+const TFEND: u8 = 0xdc;
+const TFESC: u8 = 0xdd;
+
+// Commands, the low nibble of the first byte of a frame.
+const CMD_DATA: u8 = 0x00;
+const CMD_TXDELAY: u8 = 0x01;
+const CMD_PERSISTENCE: u8 = 0x02;
+const CMD_SLOTTIME: u8 = 0x03;
+const CMD_TXTAIL: u8 = 0x04;
+const CMD_FULLDUPLEX: u8 = 0x05;
+const CMD_RETURN: u8 = 0xff;
+
+const POLL_MS: i32 = 10;
+
+/// Where the TNC is offered to clients.
+pub struct KissConfig {
+    /// TCP address to listen on, e.g. `0.0.0.0:8001`.
+    pub tcp: Option<String>,
+    /// Open a pseudo-terminal.
+    pub pty: bool,
+    /// Symlink the pseudo-terminal here, e.g. for `kissattach`.
+    pub pty_link: Option<PathBuf>,
+}
+
+/// Wraps `data` in a KISS data frame for port 0.
+pub fn encode(data: &[u8]) -> Vec<u8> {
+    let mut frame = vec![FEND, CMD_DATA];
+    for &byte in data {
+        match byte {
+            FEND => frame.extend_from_slice(&[FESC, TFEND]),
+            FESC => frame.extend_from_slice(&[FESC, TFESC]),
+            _ => frame.push(byte),
+        }
+    }
+    frame.push(FEND);
+    frame
+}
+
+/// Largest frame the decoder keeps, the command byte and a full packet.
+const MAX_FRAME: usize = 1 + 255;
+
+/// Collects bytes from a client stream into frames.
+#[derive(Default)]
+struct Decoder {
+    frame: Vec<u8>,
+    escaped: bool,
+    /// The current frame outgrew `MAX_FRAME` and is skipped up to the next FEND.
+    overflow: bool,
+}
+
+impl Decoder {
+    /// Feeds one byte, returning a frame (command byte first) when it completes. Frames longer
+    /// than a packet are dropped.
+    fn push(&mut self, byte: u8) -> Option<Vec<u8>> {
+        let byte = match byte {
+            FEND => {
+                self.escaped = false;
+                if std::mem::take(&mut self.overflow) {
+                    println!("Dropped a frame over {} bytes.", MAX_FRAME - 1);
+                    return None;
+                }
+                if self.frame.is_empty() {
+                    return None;
+                }
+                return Some(std::mem::take(&mut self.frame));
+            }
+            FESC => {
+                self.escaped = true;
+                return None;
+            }
+            _ if self.escaped => {
+                self.escaped = false;
+                match byte {
+                    TFEND => FEND,
+                    TFESC => FESC,
+                    other => other,
+                }
+            }
+            _ => byte,
+        };
+        if self.overflow {
+            return None;
+        }
+        if self.frame.len() == MAX_FRAME {
+            self.frame.clear();
+            self.overflow = true;
+            return None;
+        }
+        self.frame.push(byte);
+        None
+    }
+}
+
+/// Applies a frame from a client to `lbt`, returning the payload if it is data to transmit.
+fn command<'a>(lbt: &mut ListenBeforeTalk, frame: &'a [u8]) -> Option<&'a [u8]> {
+    // the high nibble is the port, only port 0 exists
+    if frame[0] == CMD_RETURN || frame[0] >> 4 != 0 {
+        return None;
+    }
+    let value = frame.get(1).copied().unwrap_or(0);
+    match frame[0] & 0x0f {
+        CMD_DATA if frame.len() > 1 => return Some(&frame[1..]),
+        CMD_TXDELAY => lbt.tx_delay = Duration::from_millis(value as u64 * 10),
+        CMD_PERSISTENCE => lbt.persistence = value,
+        CMD_SLOTTIME => lbt.slot_time = Duration::from_millis(value as u64 * 10),
+        CMD_FULLDUPLEX => lbt.full_duplex = value != 0,
+        CMD_TXTAIL => {}
+        _ => {}
+    }
+    None
+}
+
+trait Stream: Read + Write {}
+impl<T: Read + Write> Stream for T {}
+
+struct Client {
+    stream: Box<dyn Stream>,
+    decoder: Decoder,
+    name: String,
+    /// The PTY stays open for the lifetime of the TNC, TCP clients are dropped on error.
+    permanent: bool,
+}
+
+/// Opens a pseudo-terminal in raw mode and returns the non-blocking master and the slave,
+/// which is kept open so the master does not see a hangup between clients.
+fn open_pty() -> Result<(File, File, PathBuf)> {
+    let (mut master, mut slave) = (0, 0);
+    // SAFETY: openpty only writes the two descriptors, the other arguments may be null.
+    let ret = unsafe {
+        libc::openpty(
+            &mut master,
+            &mut slave,
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    // SAFETY: both descriptors were just opened by openpty and are owned by the returned Files.
+    unsafe {
+        let mut termios = std::mem::zeroed();
+        if libc::tcgetattr(slave, &mut termios) == 0 {
+            libc::cfmakeraw(&mut termios);
+            libc::tcsetattr(slave, libc::TCSANOW, &termios);
+        }
+        let flags = libc::fcntl(master, libc::F_GETFL);
+        libc::fcntl(master, libc::F_SETFL, flags | libc::O_NONBLOCK);
+        let name = libc::ttyname(slave);
+        if name.is_null() {
+            return Err(anyhow!("No name for the PTY."));
+        }
+        let path = PathBuf::from(CStr::from_ptr(name).to_str()?);
+        Ok((File::from_raw_fd(master), File::from_raw_fd(slave), path))
+    }
+}
+
+/// Runs a KISS TNC: data frames from clients are sent on air, received packets go to every
+/// client, and the TXDELAY, P, SLOTTIME and FULLDUPLEX commands change the listen-before-talk
+/// settings. TXTAIL has no meaning for LoRa and is ignored.
+pub fn run(radio: &mut LoRa, config: &KissConfig) -> Result<()> {
+    let mut lbt = ListenBeforeTalk::default();
+    let mut clients: Vec<Client> = Vec::new();
+    let mut _slave = None;
+
+    if config.pty {
+        let (master, slave, path) = open_pty()?;
+        if let Some(link) = &config.pty_link {
+            let _ = std::fs::remove_file(link);
+            std::os::unix::fs::symlink(&path, link)?;
+            println!("KISS on {} ({}).", link.display(), path.display());
+        } else {
+            println!("KISS on {}.", path.display());
+        }
+        clients.push(Client {
+            stream: Box::new(master),
+            decoder: Decoder::default(),
+            name: path.display().to_string(),
+            permanent: true,
+        });
+        _slave = Some(slave);
+    }
+
+    let listener = match &config.tcp {
+        Some(address) => {
+            let listener = TcpListener::bind(address)?;
+            listener.set_nonblocking(true)?;
+            println!("KISS on tcp {}.", address);
+            Some(listener)
+        }
+        None => None,
+    };
+    if clients.is_empty() && listener.is_none() {
+        return Err(anyhow!("Neither a PTY nor a TCP port was requested."));
+    }
+
+    loop {
+        if let Some(listener) = &listener {
+            match listener.accept() {
+                Ok((stream, peer)) => {
+                    stream.set_nonblocking(true)?;
+                    stream.set_nodelay(true)?;
+                    println!("Client {} connected.", peer);
+                    clients.push(Client {
+                        stream: Box::new(stream),
+                        decoder: Decoder::default(),
+                        name: peer.to_string(),
+                        permanent: false,
+                    });
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        let mut outgoing = Vec::new();
+        clients.retain_mut(|client| match read_frames(client, &mut outgoing) {
+            Ok(()) => true,
+            Err(e) => {
+                println!("Client {} dropped: {}", client.name, e);
+                client.permanent
+            }
+        });
+
+        for frame in outgoing {
+            if let Some(payload) = command(&mut lbt, &frame) {
+                lbt.transmit(radio, payload)?;
+            }
+        }
+
+        if let Ok(size) = radio.poll_irq(Some(POLL_MS)) {
+            let crc_error = radio.crc_error();
+            let buffer = radio.read_packet()?;
+            if crc_error {
+                continue;
+            }
+            let frame = encode(&buffer[..size]);
+            clients.retain_mut(|client| match client.stream.write_all(&frame) {
+                Ok(()) => true,
+                // nobody reading the PTY or a slow TCP client, this frame is lost for them
+                Err(e) if e.kind() == ErrorKind::WouldBlock => true,
+                Err(e) => {
+                    println!("Client {} dropped: {}", client.name, e);
+                    client.permanent
+                }
+            });
+        }
+    }
+}
+
+/// Reads everything available from a client, collecting completed frames.
+fn read_frames(client: &mut Client, frames: &mut Vec<Vec<u8>>) -> io::Result<()> {
+    let mut buffer = [0u8; 512];
+    loop {
+        match client.stream.read(&mut buffer) {
+            Ok(0) => return Err(io::Error::new(ErrorKind::UnexpectedEof, "closed")),
+            Ok(len) => frames.extend(buffer[..len].iter().filter_map(|&b| client.decoder.push(b))),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(bytes: &[u8]) -> Vec<Vec<u8>> {
+        let mut decoder = Decoder::default();
+        bytes.iter().filter_map(|&b| decoder.push(b)).collect()
+    }
+
+    #[test]
+    fn special_bytes_are_escaped() {
+        let data = [0x01, FEND, 0x02, FESC, TFEND, TFESC];
+        let frame = encode(&data);
+        assert_eq!(frame, [FEND, CMD_DATA, 0x01, FESC, TFEND, 0x02, FESC, TFESC, TFEND, TFESC, FEND]);
+        assert_eq!(decode(&frame), vec![[&[CMD_DATA][..], &data].concat()]);
+    }
+
+    #[test]
+    fn frames_are_split_on_fend() {
+        // back to back frames share a FEND, repeated FENDs are ignored
+        let mut bytes = encode(b"one");
+        bytes.extend_from_slice(&encode(b"two")[1..]);
+        bytes.extend_from_slice(&[FEND, FEND]);
+        assert_eq!(decode(&bytes), vec![b"\x00one".to_vec(), b"\x00two".to_vec()]);
+        // an escape of anything else passes the byte through
+        assert_eq!(decode(&[FEND, CMD_DATA, FESC, 0x41, FEND]), vec![vec![CMD_DATA, 0x41]]);
+    }
+
+    #[test]
+    fn oversized_frames_are_dropped() {
+        assert_eq!(decode(&encode(&[FEND; 255])), vec![[&[CMD_DATA][..], &[FEND; 255]].concat()]);
+        let mut bytes = encode(&[0x55; 256]);
+        bytes.extend_from_slice(&encode(b"next"));
+        // the decoder recovers at the next frame
+        assert_eq!(decode(&bytes), vec![b"\x00next".to_vec()]);
+        let endless = vec![0x55; 100_000];
+        let mut decoder = Decoder::default();
+        assert!(endless.iter().all(|&b| decoder.push(b).is_none()));
+        assert!(decoder.frame.len() <= MAX_FRAME);
+    }
+
+    #[test]
+    fn parameter_commands_set_listen_before_talk() {
+        let mut lbt = ListenBeforeTalk::default();
+        let bytes = [
+            FEND, CMD_TXDELAY, 30, FEND, FEND, CMD_PERSISTENCE, 127, FEND,
+            FEND, CMD_SLOTTIME, 5, FEND, FEND, CMD_FULLDUPLEX, 1, FEND,
+        ];
+        for frame in decode(&bytes) {
+            assert_eq!(command(&mut lbt, &frame), None);
+        }
+        assert_eq!(lbt.tx_delay, Duration::from_millis(300));
+        assert_eq!(lbt.persistence, 127);
+        assert_eq!(lbt.slot_time, Duration::from_millis(50));
+        assert!(lbt.full_duplex);
+
+        // TXTAIL, other ports and RETURN change nothing
+        for frame in [[CMD_TXTAIL, 9], [0x11, 9], [CMD_RETURN, 9]] {
+            assert_eq!(command(&mut lbt, &frame), None);
+        }
+        assert_eq!(lbt.tx_delay, Duration::from_millis(300));
+        assert_eq!(command(&mut lbt, &[CMD_DATA, 1, 2]), Some(&[1, 2][..]));
+        assert_eq!(command(&mut lbt, &[CMD_DATA]), None);
+    }
+}
@@ -0,0 +1,75 @@
+#![allow(dead_code)]
+
+use anyhow::Result;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::rfm96w::LoRa;
+
+/// Listen-before-talk settings, p-persistent CSMA as used by KISS TNCs: while the channel is
+/// busy wait a slot, once it is clear transmit with probability `(persistence + 1) / 256`,
+/// otherwise wait a slot and listen again.
+#[derive(Clone, Copy)]
+pub struct ListenBeforeTalk {
+    /// Wait between winning the channel and starting to transmit.
+    pub tx_delay: Duration,
+    pub persistence: u8,
+    pub slot_time: Duration,
+    /// Skip channel sensing altogether.
+    pub full_duplex: bool,
+}
+
+impl Default for ListenBeforeTalk {
+    /// The usual KISS defaults: 500 ms TXDELAY, p = 63, 100 ms slots.
+    fn default() -> Self {
+        ListenBeforeTalk {
+            tx_delay: Duration::from_millis(500),
+            persistence: 63,
+            slot_time: Duration::from_millis(100),
+            full_duplex: false,
+        }
+    }
+}
+
+impl ListenBeforeTalk {
+    /// Waits for the channel using channel activity detection, then transmits up to 255
+    /// bytes and blocks until the packet is sent.
+    pub fn transmit(&self, radio: &mut LoRa, payload: &[u8]) -> Result<()> {
+        if !self.full_duplex {
+            let mut random = Xorshift::seeded();
+            loop {
+                if radio.channel_activity()? {
+                    spin_sleep::sleep(self.slot_time);
+                } else if random.next_byte() <= self.persistence {
+                    break;
+                } else {
+                    spin_sleep::sleep(self.slot_time);
+                }
+            }
+        }
+        spin_sleep::sleep(self.tx_delay);
+        let mut buffer = [0u8; 255];
+        let len = payload.len().min(255);
+        buffer[..len].copy_from_slice(&payload[..len]);
+        radio.transmit_payload_busy(buffer, len)?;
+        Ok(())
+    }
+}
+
+/// Enough randomness for backoff decisions, two nodes only need to not draw in lockstep.
+struct Xorshift(u32);
+
+impl Xorshift {
+    fn seeded() -> Self {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(1, |d| d.subsec_nanos());
+        Xorshift(nanos | 1)
+    }
+
+    fn next_byte(&mut self) -> u8 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        (self.0 >> 24) as u8
+    }
+}
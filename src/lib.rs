@@ -0,0 +1,43 @@
+use anyhow::Result;
+use rppal::{gpio::Gpio, spi::{Bus, Mode, SlaveSelect, Spi}};
+
+pub mod capture;
+pub mod cli;
+pub mod compress;
+pub mod daemon;
+pub mod datagram;
+pub mod dutycycle;
+pub mod fec;
+pub mod gateway;
+pub mod gps;
+pub mod kiss;
+pub mod lbt;
+pub mod mqtt;
+pub mod netif;
+pub mod power;
+#[cfg(feature = "python")]
+pub mod python;
+pub mod rangetest;
+pub mod register;
+pub mod rfm96w;
+pub mod scan;
+pub mod secure;
+pub mod snapshot;
+pub mod sniff;
+pub mod survey;
+pub mod transfer;
+
+use rfm96w::LoRa;
+
+//
+const LORA_CS_PIN: u8 = 7;
+const LORA_RESET_PIN: u8 = 25;
+
+/// Opens the radio on SPI0 with the chip select and reset pins of our board.
+pub fn open_radio() -> Result<LoRa> {
+    let spi = Spi::new(Bus::Spi0, SlaveSelect::Ss0,5_000_000, Mode::Mode0)?;
+    let cs_pin = Gpio::new()?.get(LORA_CS_PIN)?.into_output();
+    let reset_pin = Gpio::new()?.get(LORA_RESET_PIN)?.into_output();
+
+    LoRa::new(spi, cs_pin, reset_pin)
+}
@@ -1,45 +1,76 @@
 // general prog
-use anyhow::Result;
-// threads
-use std::io::{Write, BufReader, BufRead};
-use std::process::{Command, Stdio};
-use std::thread;
-use std::sync::{Arc, Mutex};
-use std::sync::mpsc;
+use anyhow::{anyhow, Result};
+use std::path::Path;
 use std::time::Duration;
 // radio specific stuff.
-use rfm96w::LoRa;
-use rppal::{gpio::Gpio, spi::{Bus, Mode, SlaveSelect, Spi}};
+use rora::rfm96w::{LoRa, TxPower};
+use rora::{datagram, kiss, mqtt, netif, rangetest, scan, sniff, survey, transfer};
 
-mod register;
-mod rfm96w;
+use rora::capture::Capture;
+use rora::cli::Options;
+use rora::compress::{Codec, Compressor, Lzss};
+use rora::datagram::Header;
+use rora::gateway::{Gateway, GatewayConfig};
+use rora::gps::{Gps, GpsSource};
+use rora::kiss::KissConfig;
+use rora::mqtt::MqttConfig;
+use rora::netif::{NetifConfig, PeerMap};
+use rora::rangetest::{RangeTestConfig, Setting};
+use rora::scan::{Method, ScanConfig};
+use rora::secure::SecureChannel;
+use rora::sniff::SniffConfig;
+use rora::snapshot::{self, Snapshot};
+use rora::survey::SurveyConfig;
+use rora::transfer::TransferConfig;
 
-// 
-const LORA_CS_PIN: u8 = 7;
-const LORA_RESET_PIN: u8 = 25;
-const G0_PIN: u8 = 5;
+//
 const PYTHON_HEADER: [u8;4] = [255,255,0,0];
+const DEFAULT_ADDRESS: u8 = 1;
 
-fn trim_trailing_zeros(data: &[u8]) -> &[u8] {
-    // Find the last non-zero byte in the array. `enumerate()` provides the index alongside the value.
-    // `rev()` reverses the iterator, so we search from the end towards the start.
-    // `find()` returns the first element satisfying the condition, which is the last non-zero byte due to reversal.
-    if let Some((pos, _)) = data.iter().enumerate().rev().find(|&(_, &x)| x != 0) {
-        // Slice the array from the start up to the position of the last non-zero byte, inclusive.
-        // We add 1 to include the non-zero byte itself in the slice.
-        &data[..=pos]
-    } else {
-        // If all bytes are zero, return an empty slice.
-        &[]
+fn main() -> Result<()> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let command = args.first().map(String::as_str);
+    let options = Options::new(args.get(1..).unwrap_or_default());
+    let command: fn(&mut LoRa, &Options) -> Result<()> = match command {
+        None | Some("echo") => echo,
+        Some("gateway") => gateway,
+        Some("send") => send,
+        Some("recv") => recv,
+        Some("send-file") => send_file,
+        Some("recv-file") => recv_file,
+        Some("kiss") => kiss,
+        Some("netif") => netif,
+        Some("mqtt") => mqtt,
+        Some("sniff") => sniff,
+        Some("scan") => scan,
+        Some("survey") => survey,
+        Some("rangetest") => rangetest,
+        // opens the radio itself, comparing two dumps needs none
+        Some("regs") => return regs(&options),
+        Some(other) => return Err(anyhow!("Unknown command: {}", other)),
+    };
+    command(&mut open_radio(&options)?, &options)
+}
+
+/// Opens the radio. Every command accepts `--pcap path` to capture all frames for Wireshark.
+fn open_radio(options: &Options) -> Result<LoRa> {
+    let mut radio = rora::open_radio()?;
+    if let Some(path) = options.get::<String>("pcap")? {
+        radio.set_capture(Some(Capture::create(path)?));
     }
+    Ok(radio)
 }
 
-fn main() -> Result<()> {
-    let spi = Spi::new(Bus::Spi0, SlaveSelect::Ss0,5_000_000, Mode::Mode0)?;
-    let cs_pin = Gpio::new()?.get(LORA_CS_PIN)?.into_output();
-    let reset_pin = Gpio::new()?.get(LORA_RESET_PIN)?.into_output();
+/// Handshakes with the python peer, then echoes every packet back to it.
+/// `rora echo [--compress] [--dictionary path]`
+fn echo(radio: &mut LoRa, options: &Options) -> Result<()> {
+    let codec: Box<dyn Codec> = match options.get::<String>("dictionary")? {
+        Some(path) => Box::new(Lzss::with_dictionary(&std::fs::read(path)?)),
+        None => Box::new(Lzss::new()),
+    };
+    let mut compressor = Compressor::new(codec);
+    let compress = options.flag("compress");
 
-    let mut radio = LoRa::new(spi, cs_pin, reset_pin)?;
     let message = "RORA";
     radio.transmit_payload(message.as_bytes())?;
 
@@ -68,37 +99,339 @@ fn main() -> Result<()> {
     }
 
     loop{
-        // g0 does fck all.
-        // if g0_pin.is_high() {
-        //     println!("G0 HIGH!");
-        // }else{
-        //     println!("G0 LOW!");
-        // }
         let poll = radio.poll_irq(None);
-        match poll {
-            Ok(size) => {
-                let buffer = radio.read_packet();
-                match buffer {
-                    Ok(b) => {
-                        //rx a buffer!
-                        println!("RX {} bytes.", size);
-                        // spin_sleep::sleep(Duration::from_millis(10));
+        if let Ok(size) = poll {
+            let buffer = radio.read_packet();
+            match buffer {
+                Ok(b) => {
+                    //rx a buffer!
+                    println!("RX {} bytes.", size);
+                    let payload = match compressor.receive(&b[..size]) {
+                        Ok(Some((_, payload))) => payload,
+                        Ok(None) => continue,
+                        Err(e) => {
+                            println!("Decode failed: {}", e);
+                            continue;
+                        }
+                    };
+                    let header = Header::from_bytes(&PYTHON_HEADER)?;
+                    if compress {
+                        compressor.send_bulk(radio, header, &payload)?;
+                    } else {
                         let mut echo: Vec<u8> = Vec::new();
                         echo.extend_from_slice(&PYTHON_HEADER);
-                        echo.extend_from_slice(&b[4..size]);
-                        radio.tx_bulk(&echo);
-                        println!("TX: {:?}", echo);
-
+                        echo.extend_from_slice(&payload);
+                        radio.tx_bulk(&echo)?;
                     }
-                    Err(_) => {
-                        println!("Read packet failed.");
-                    },
+                    println!("TX: {:?}", payload);
+
                 }
+                Err(_) => {
+                    println!("Read packet failed.");
+                },
+            }
+        }
+    }
+}
 
-            },
-            Err(_) => {},
+/// `rora gateway --server host:port [--eui hex] [--keepalive s] [--power dBm]`
+fn gateway(radio: &mut LoRa, options: &Options) -> Result<()> {
+    let gateway_eui = match options.get::<String>("eui")? {
+        Some(hex) => GatewayConfig::parse_eui(&hex)?,
+        None => GatewayConfig::eui_from_interface("eth0"),
+    };
+    let config = GatewayConfig {
+        server: options.get_or("server", "127.0.0.1:1700".to_string())?,
+        gateway_eui,
+        keepalive: Duration::from_secs(options.get_or("keepalive", 10)?),
+        tx_power: options.get_or("power", 14)?,
+    };
+    radio.set_tx_power(TxPower::pa_boost(config.tx_power))?;
+    Gateway::new(radio, config)?.run()
+}
+
+/// Builds the channel for `--keys`. A sender reserves its counter in `--counter`, by default
+/// `<keys>.counter`, so nonces are never reused across runs.
+fn secure_channel(options: &Options, transmit: bool) -> Result<Option<SecureChannel>> {
+    let Some(keys) = options.get::<String>("keys")? else {
+        return Ok(None);
+    };
+    let mut channel = SecureChannel::new(options.get_or("address", DEFAULT_ADDRESS)?);
+    channel.load_keys(&keys)?;
+    if transmit {
+        let counter = options.get::<String>("counter")?.unwrap_or(format!("{}.counter", keys));
+        channel.persist_counter(counter)?;
+    }
+    Ok(Some(channel))
+}
+
+/// Sends one datagram, sealed with the key of `--to` when `--keys` is given.
+/// `rora send <text> --to addr [--address addr] [--keys path [--counter path]]`
+fn send(radio: &mut LoRa, options: &Options) -> Result<()> {
+    let text = options.positional(0).ok_or_else(|| anyhow!("Missing text to send."))?;
+    let to = options.get::<u8>("to")?.ok_or_else(|| anyhow!("Missing --to address."))?;
+    match secure_channel(options, true)? {
+        Some(mut channel) => channel.send(radio, to, text.as_bytes()),
+        None => {
+            let header = Header { to, from: options.get_or("address", DEFAULT_ADDRESS)?, id: 0, flags: 0 };
+            if text.len() > datagram::MAX_PAYLOAD {
+                return Err(anyhow!("Text exceeds {} bytes.", datagram::MAX_PAYLOAD));
+            }
+            radio.tx_bulk(&[&header.to_bytes()[..], text.as_bytes()].concat())
         }
     }
+}
 
+/// Prints the datagrams addressed to us. With `--keys` only secured datagrams from known
+/// peers are accepted.
+/// `rora recv [--address addr] [--keys path] [--count n]`
+fn recv(radio: &mut LoRa, options: &Options) -> Result<()> {
+    let address = options.get_or("address", DEFAULT_ADDRESS)?;
+    let mut channel = secure_channel(options, false)?;
+    let count: Option<usize> = options.get("count")?;
+    let mut received = 0;
+    while count.is_none_or(|count| received < count) {
+        let size = radio.poll_irq(None)?;
+        let buffer = radio.read_packet()?;
+        if radio.crc_error() {
+            continue;
+        }
+        let Ok(header) = Header::from_bytes(&buffer[..size]) else { continue };
+        if !header.is_for(address) {
+            continue;
+        }
+        let payload = match &mut channel {
+            Some(channel) => match channel.receive(&buffer, size) {
+                Ok((_, plaintext)) => plaintext,
+                Err(e) => {
+                    println!("Dropped packet from {}: {}", header.from, e);
+                    continue;
+                }
+            },
+            None => buffer[datagram::HEADER_LEN..size].to_vec(),
+        };
+        println!("{} -> {}: {}", header.from, header.to, String::from_utf8_lossy(&payload));
+        received += 1;
+    }
     Ok(())
-}
\ No newline at end of file
+}
+
+fn transfer_config(options: &Options) -> Result<TransferConfig> {
+    Ok(TransferConfig {
+        address: options.get_or("address", DEFAULT_ADDRESS)?,
+        peer: options.get_or("to", datagram::BROADCAST)?,
+        block_size: options.get_or("block-size", 200)?,
+        timeout: Duration::from_millis(options.get_or("timeout", 3000)?),
+        retries: options.get_or("retries", 10)?,
+    })
+}
+
+/// `rora send-file <path> --to addr [--address addr] [--block-size n] [--timeout ms] [--retries n]`
+fn send_file(radio: &mut LoRa, options: &Options) -> Result<()> {
+    let path = options.positional(0).ok_or_else(|| anyhow!("Missing file to send."))?;
+    if options.get::<u8>("to")?.is_none() {
+        return Err(anyhow!("Missing --to address."));
+    }
+    transfer::send_file(radio, &transfer_config(options)?, Path::new(path))
+}
+
+/// `rora recv-file [dir] [--address addr] [--count n] [--timeout ms]`
+fn recv_file(radio: &mut LoRa, options: &Options) -> Result<()> {
+    let dir = Path::new(options.positional(0).unwrap_or("."));
+    transfer::receive_files(radio, &transfer_config(options)?, dir, options.get("count")?)
+}
+
+/// `rora kiss [--tcp addr | --no-tcp] [--pty] [--link path]`, TCP defaults to port 8001.
+fn kiss(radio: &mut LoRa, options: &Options) -> Result<()> {
+    let tcp = match options.get::<String>("tcp")? {
+        Some(address) => Some(address),
+        None if options.flag("no-tcp") => None,
+        None => Some("0.0.0.0:8001".to_string()),
+    };
+    let config = KissConfig {
+        tcp,
+        pty: options.flag("pty") || options.get::<String>("link")?.is_some(),
+        pty_link: options.get("link")?,
+    };
+    kiss::run(radio, &config)
+}
+
+/// `rora netif [--name lora0] [--ip 10.0.0.1/24] [--address n] [--mtu 1280] [--peers path]`,
+/// the radio address defaults to the last octet of `--ip`.
+fn netif(radio: &mut LoRa, options: &Options) -> Result<()> {
+    let ip: Option<String> = options.get("ip")?;
+    let last_octet = ip
+        .as_deref()
+        .and_then(|ip| ip.split('/').next())
+        .and_then(|ip| ip.parse::<std::net::Ipv4Addr>().ok())
+        .map(|ip| ip.octets()[3]);
+    let config = NetifConfig {
+        name: options.get_or("name", "lora0".to_string())?,
+        address: options.get("address")?.or(last_octet).unwrap_or(DEFAULT_ADDRESS),
+        ip,
+        mtu: options.get_or("mtu", 1280)?,
+        peers: match options.get::<String>("peers")? {
+            Some(path) => PeerMap::load(path)?,
+            None => PeerMap::default(),
+        },
+    };
+    netif::run(radio, &config)
+}
+
+/// `rora mqtt [--broker host:port] [--address n] [--rx-topic t] [--tx-topic t] [--raw-tx-topic t] [--hex]`
+fn mqtt(radio: &mut LoRa, options: &Options) -> Result<()> {
+    let config = MqttConfig {
+        broker: options.get_or("broker", "127.0.0.1:1883".to_string())?,
+        client_id: options.get_or("client-id", format!("rora-{}", std::process::id()))?,
+        address: options.get_or("address", DEFAULT_ADDRESS)?,
+        rx_topic: options.get_or("rx-topic", "rora/node/{from}/rx".to_string())?,
+        tx_topic: options.get_or("tx-topic", "rora/node/{to}/tx".to_string())?,
+        raw_tx_topic: options.get_or("raw-tx-topic", "rora/tx".to_string())?,
+        hex: options.flag("hex"),
+    };
+    mqtt::run(radio, &config)
+}
+
+/// Shows a live dashboard of everything received on the channel.
+/// `rora sniff [--step hz]`
+fn sniff(radio: &mut LoRa, options: &Options) -> Result<()> {
+    let config = SniffConfig { step: options.get_or("step", 200_000)? };
+    sniff::run(radio, &config)
+}
+
+/// Looks for activity on every combination of the given frequencies (MHz), spreading factors
+/// and bandwidths (Hz). Defaults to the current frequency, SF7 to SF12 and 125 kHz.
+/// `rora scan [--frequencies 433.175,433.375] [--sf 7,9] [--bw 125000,250000] [--rx] [--window ms] [--rounds n]`
+fn scan(radio: &mut LoRa, options: &Options) -> Result<()> {
+    let frequencies = match options.get_list::<f64>("frequencies")? {
+        Some(mhz) => mhz.iter().map(|f| (f * 1e6).round() as u64).collect(),
+        None => vec![radio.get_frequency()],
+    };
+    let config = ScanConfig {
+        frequencies,
+        spreading_factors: options.get_list("sf")?.unwrap_or_else(|| (7..=12).collect()),
+        bandwidths: options.get_list("bw")?.unwrap_or_else(|| vec![125_000]),
+        method: if options.flag("rx") { Method::Rx } else { Method::Cad },
+        window: options.get("window")?.map(Duration::from_millis),
+        rounds: options.get("rounds")?,
+    };
+    scan::run(radio, &config)
+}
+
+/// Sweeps the noise floor from `--start` to `--stop` (MHz) in `--step` Hz, by default across
+/// the 433 MHz ISM band.
+/// `rora survey [--start 433.05] [--stop 434.79] [--step 25000] [--samples n] [--settle ms] [--sweeps n] [--csv path|-] [--floor dBm] [--ceiling dBm]`
+fn survey(radio: &mut LoRa, options: &Options) -> Result<()> {
+    let mhz = |name, default: f64| -> Result<u64> { Ok((options.get_or(name, default)? * 1e6).round() as u64) };
+    let config = SurveyConfig {
+        start: mhz("start", 433.05)?,
+        stop: mhz("stop", 434.79)?,
+        step: options.get_or("step", 25_000)?,
+        samples: options.get_or("samples", 16)?,
+        settle: Duration::from_millis(options.get_or("settle", 2)?),
+        sweeps: options.get("sweeps")?,
+        csv: options.get("csv")?,
+        floor: options.get_or("floor", -130.0)?,
+        ceiling: options.get_or("ceiling", -90.0)?,
+    };
+    survey::run(radio, &config)
+}
+
+/// Beacons from `tx` to `rx` across every combination of `--sf`, `--bw`, `--cr` and `--power`,
+/// which default to the current settings and must match on both sides. The position comes
+/// from gpsd or an NMEA file or device.
+/// `rora rangetest tx|rx [--sf 7,12] [--bw 125000] [--cr 5] [--power 2,17] [--count 20] [--interval ms] [--size n] [--guard ms] [--gpsd host:port | --nmea path] [--csv path] [--json path]`
+fn rangetest(radio: &mut LoRa, options: &Options) -> Result<()> {
+    let matrix = Setting::matrix(
+        &options.get_list("sf")?.unwrap_or(vec![radio.get_spreading_factor()?]),
+        &options.get_list("bw")?.unwrap_or(vec![radio.get_signal_bandwidth()?]),
+        &options.get_list("cr")?.unwrap_or(vec![radio.get_coding_rate_4()?]),
+        &options.get_list("power")?.unwrap_or(vec![20]),
+    );
+    let gps = match (options.get::<String>("gpsd")?, options.get::<String>("nmea")?) {
+        (Some(address), _) => Some(Gps::start(GpsSource::Gpsd(address))?),
+        (None, Some(path)) => Some(Gps::start(GpsSource::Nmea(path.into()))?),
+        (None, None) => None,
+    };
+    let config = RangeTestConfig {
+        address: options.get_or("address", DEFAULT_ADDRESS)?,
+        matrix,
+        count: options.get_or("count", 20)?,
+        interval: Duration::from_millis(options.get_or("interval", 2000)?),
+        size: options.get_or("size", 32)?,
+        guard: Duration::from_millis(options.get_or("guard", 2000)?),
+        gps,
+        csv: options.get("csv")?,
+        json: options.get("json")?,
+    };
+    match options.positional(0) {
+        Some("tx") => rangetest::transmit(radio, &config),
+        Some("rx") => rangetest::receive(radio, &config),
+        _ => Err(anyhow!("Usage: rora rangetest tx|rx [options]")),
+    }
+}
+
+fn read_snapshot(path: &str) -> Result<Snapshot> {
+    Snapshot::from_json(&serde_json::from_str(&std::fs::read_to_string(path)?)?)
+}
+
+/// Dumps every register with its decoded fields, compares two dumps (or one dump with the
+/// chip) and writes a dump back. Diffs cover the configuration registers unless `--all`.
+/// `rora regs dump [--json] [--output path] | diff a.json [b.json] [--all] | load a.json`
+fn regs(options: &Options) -> Result<()> {
+    match (options.positional(0), options.positional(1)) {
+        (Some("dump"), _) => {
+            let snapshot = open_radio(options)?.dump_registers()?;
+            let text = if options.flag("json") || options.get::<String>("output")?.is_some() {
+                serde_json::to_string_pretty(&snapshot.to_json())? + "\n"
+            } else {
+                let mut text = String::new();
+                for (address, value) in snapshot.values.iter().enumerate() {
+                    let Some(value) = *value else { continue };
+                    let name = snapshot::name(address as u8).unwrap_or_default();
+                    let fields = snapshot::fields(address as u8, value);
+                    let fields = if fields.is_null() { String::new() } else { fields.to_string() };
+                    text += &format!("0x{:02x} {:<22} 0x{:02x} {}\n", address, name, value, fields);
+                }
+                text
+            };
+            match options.get::<String>("output")? {
+                Some(path) => std::fs::write(path, text)?,
+                None => print!("{}", text),
+            }
+            Ok(())
+        }
+        (Some("diff"), Some(before)) => {
+            let before = read_snapshot(before)?;
+            let after = match options.positional(2) {
+                Some(path) => read_snapshot(path)?,
+                None => open_radio(options)?.dump_registers()?,
+            };
+            let differences = before.diff(&after, options.flag("all"));
+            let byte = |value: Option<u8>| value.map_or("--".to_string(), |v| format!("0x{:02x}", v));
+            for d in &differences {
+                println!(
+                    "0x{:02x} {:<22} {} -> {}",
+                    d.address,
+                    snapshot::name(d.address).unwrap_or_default(),
+                    byte(d.before),
+                    byte(d.after)
+                );
+                if let (Some(b), Some(a)) = (d.before, d.after) {
+                    let (b, a) = (snapshot::fields(d.address, b), snapshot::fields(d.address, a));
+                    if let (Some(b), Some(a)) = (b.as_object(), a.as_object()) {
+                        for (field, value) in a {
+                            if b.get(field) != Some(value) {
+                                println!("     {}: {} -> {}", field, b[field], value);
+                            }
+                        }
+                    }
+                }
+            }
+            println!("{} registers differ.", differences.len());
+            Ok(())
+        }
+        (Some("load"), Some(path)) => open_radio(options)?.restore_registers(&read_snapshot(path)?),
+        _ => Err(anyhow!("Usage: rora regs dump|diff|load [options]")),
+    }
+}
@@ -0,0 +1,435 @@
+use anyhow::{anyhow, Result};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use serde_json::json;
+use std::io::{ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::datagram::{Header, HEADER_LEN};
+use crate::rfm96w::LoRa;
+
+// MQTT 3.1.1 control packet types, in the high nibble of the first byte.
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const SUBSCRIBE: u8 = 0x82;
+const PINGREQ: u8 = 0xc0;
+const DISCONNECT: u8 = 0xe0;
+
+const KEEPALIVE: Duration = Duration::from_secs(30);
+const POLL_MS: i32 = 10;
+
+/// Settings of the MQTT bridge. Topics may contain `{from}` and `{to}`, which are replaced
+/// by the datagram addresses, so every node gets its own topics.
+pub struct MqttConfig {
+    /// Broker address, e.g. `127.0.0.1:1883`.
+    pub broker: String,
+    pub client_id: String,
+    /// Radio address used as `from` for downlinks.
+    pub address: u8,
+    /// Where received packets are published, e.g. `rora/node/{from}/rx`.
+    pub rx_topic: String,
+    /// Downlinks for one node, e.g. `rora/node/{to}/tx`, where `{to}` is required. The payload
+    /// is sent behind a datagram header addressed to the node.
+    pub tx_topic: String,
+    /// Downlinks sent on air as they are, without a header.
+    pub raw_tx_topic: String,
+    /// Encoding of the payload in published messages, `base64` or `hex`.
+    pub hex: bool,
+}
+
+/// A minimal MQTT 3.1.1 client: QoS 0 publish and subscribe over plain TCP.
+pub struct Client {
+    stream: TcpStream,
+    buffer: Vec<u8>,
+    last_sent: Instant,
+}
+
+fn encode_length(mut length: usize, out: &mut Vec<u8>) {
+    loop {
+        let mut byte = (length % 128) as u8;
+        length /= 128;
+        if length > 0 {
+            byte |= 0x80;
+        }
+        out.push(byte);
+        if length == 0 {
+            break;
+        }
+    }
+}
+
+fn encode_string(value: &str, out: &mut Vec<u8>) {
+    out.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    out.extend_from_slice(value.as_bytes());
+}
+
+/// Removes the first complete packet from `buffer` and returns its type and body, `None` if
+/// more bytes are needed.
+fn take_packet(buffer: &mut Vec<u8>) -> Result<Option<(u8, Vec<u8>)>> {
+    let mut length = 0;
+    let mut multiplier = 1;
+    let mut index = 1;
+    loop {
+        let Some(&byte) = buffer.get(index) else {
+            return Ok(None);
+        };
+        length += (byte & 0x7f) as usize * multiplier;
+        multiplier *= 128;
+        index += 1;
+        if byte & 0x80 == 0 {
+            break;
+        }
+        if index > 4 {
+            return Err(anyhow!("Malformed remaining length."));
+        }
+    }
+    if buffer.len() < index + length {
+        return Ok(None);
+    }
+    let packet_type = buffer[0] & 0xf0;
+    let body = buffer[index..index + length].to_vec();
+    buffer.drain(..index + length);
+    Ok(Some((packet_type, body)))
+}
+
+impl Client {
+    pub fn connect(broker: &str, client_id: &str) -> Result<Self> {
+        let stream = TcpStream::connect(broker)?;
+        stream.set_nodelay(true)?;
+        stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+        let mut client = Client {
+            stream,
+            buffer: Vec::new(),
+            last_sent: Instant::now(),
+        };
+
+        let mut body = Vec::new();
+        encode_string("MQTT", &mut body);
+        // protocol level 4, clean session
+        body.extend_from_slice(&[4, 0x02]);
+        body.extend_from_slice(&(KEEPALIVE.as_secs() as u16).to_be_bytes());
+        encode_string(client_id, &mut body);
+        client.send(CONNECT, &body)?;
+
+        // wait for the CONNACK up to the read timeout, `send` leaves the stream non-blocking
+        client.stream.set_nonblocking(false)?;
+        match client.next_packet()? {
+            Some((CONNACK, body)) if body.get(1) == Some(&0) => {}
+            Some((CONNACK, body)) => return Err(anyhow!("Broker refused connection: {:?}", body.get(1))),
+            _ => return Err(anyhow!("No CONNACK from broker.")),
+        }
+        client.stream.set_nonblocking(true)?;
+        Ok(client)
+    }
+
+    fn send(&mut self, first_byte: u8, body: &[u8]) -> Result<()> {
+        let mut packet = vec![first_byte];
+        encode_length(body.len(), &mut packet);
+        packet.extend_from_slice(body);
+        // the stream may be non-blocking, but packets are small and must not be split
+        self.stream.set_nonblocking(false)?;
+        self.stream.write_all(&packet)?;
+        self.stream.set_nonblocking(true)?;
+        self.last_sent = Instant::now();
+        Ok(())
+    }
+
+    pub fn publish(&mut self, topic: &str, payload: &[u8]) -> Result<()> {
+        let mut body = Vec::new();
+        encode_string(topic, &mut body);
+        body.extend_from_slice(payload);
+        self.send(PUBLISH, &body)
+    }
+
+    pub fn subscribe(&mut self, packet_id: u16, filter: &str) -> Result<()> {
+        let mut body = packet_id.to_be_bytes().to_vec();
+        encode_string(filter, &mut body);
+        body.push(0);
+        self.send(SUBSCRIBE, &body)
+    }
+
+    /// Sends a PINGREQ if nothing was sent for most of the keepalive interval.
+    pub fn keep_alive(&mut self) -> Result<()> {
+        if self.last_sent.elapsed() > KEEPALIVE * 3 / 4 {
+            self.send(PINGREQ, &[])?;
+        }
+        Ok(())
+    }
+
+    pub fn disconnect(&mut self) -> Result<()> {
+        self.send(DISCONNECT, &[])
+    }
+
+    /// Returns the next complete packet as its type and body, or `None` if none is buffered
+    /// and nothing can be read without blocking.
+    fn next_packet(&mut self) -> Result<Option<(u8, Vec<u8>)>> {
+        loop {
+            if let Some(packet) = take_packet(&mut self.buffer)? {
+                return Ok(Some(packet));
+            }
+            let mut chunk = [0u8; 1024];
+            match self.stream.read(&mut chunk) {
+                Ok(0) => return Err(anyhow!("Broker closed the connection.")),
+                Ok(len) => self.buffer.extend_from_slice(&chunk[..len]),
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => return Ok(None),
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    /// Returns the next message received on a subscription, as topic and payload.
+    pub fn poll_message(&mut self) -> Result<Option<(String, Vec<u8>)>> {
+        while let Some((packet_type, body)) = self.next_packet()? {
+            if packet_type != PUBLISH || body.len() < 2 {
+                // SUBACK, PINGRESP and the like need no handling
+                continue;
+            }
+            let topic_len = u16::from_be_bytes([body[0], body[1]]) as usize;
+            let topic = String::from_utf8_lossy(body.get(2..2 + topic_len).unwrap_or_default()).into_owned();
+            // QoS 0 only, so there is no packet identifier
+            return Ok(Some((topic, body.get(2 + topic_len..).unwrap_or_default().to_vec())));
+        }
+        Ok(None)
+    }
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Extracts the node address from a topic matching a template with a `{to}` placeholder.
+fn address_from_topic(template: &str, topic: &str) -> Option<u8> {
+    let (prefix, suffix) = template.split_once("{to}")?;
+    topic.strip_prefix(prefix)?.strip_suffix(suffix)?.parse().ok()
+}
+
+/// Connects to the broker and subscribes to both transmit topics.
+fn connect(config: &MqttConfig) -> Result<Client> {
+    // without the placeholder no downlink could ever be addressed to a node
+    if !config.tx_topic.contains("{to}") {
+        return Err(anyhow!("The tx topic {} has no {{to}} placeholder.", config.tx_topic));
+    }
+    let mut client = Client::connect(&config.broker, &config.client_id)?;
+    client.subscribe(1, &config.tx_topic.replace("{to}", "+"))?;
+    client.subscribe(2, &config.raw_tx_topic)?;
+    Ok(client)
+}
+
+/// Bridges the radio to an MQTT broker: every received packet is published as JSON with its
+/// link metadata, and messages on the transmit topics are sent on air.
+pub fn run(radio: &mut LoRa, config: &MqttConfig) -> Result<()> {
+    let mut client = connect(config)?;
+    println!("Connected to {}.", config.broker);
+    let mut id: u8 = 0;
+
+    loop {
+        client.keep_alive()?;
+        while let Some((topic, payload)) = client.poll_message()? {
+            let packet = if topic == config.raw_tx_topic {
+                payload
+            } else if let Some(to) = address_from_topic(&config.tx_topic, &topic) {
+                let header = Header { to, from: config.address, id, flags: 0 };
+                id = id.wrapping_add(1);
+                let mut packet = header.to_bytes().to_vec();
+                packet.extend_from_slice(&payload);
+                packet
+            } else {
+                continue;
+            };
+            if packet.len() > 255 {
+                println!("Dropped {} byte message from {}, 255 is the limit.", packet.len(), topic);
+                continue;
+            }
+            let mut buffer = [0u8; 255];
+            buffer[..packet.len()].copy_from_slice(&packet);
+            radio.transmit_payload_busy(buffer, packet.len())?;
+            println!("TX {} bytes from {}.", packet.len(), topic);
+        }
+
+        let Ok(size) = radio.poll_irq(Some(POLL_MS)) else {
+            continue;
+        };
+        let crc_ok = !radio.crc_error();
+        let buffer = radio.read_packet()?;
+        let data = &buffer[..size];
+        let header = Header::from_bytes(data).ok();
+        let topic = match header {
+            Some(h) => config.rx_topic.replace("{from}", &h.from.to_string()).replace("{to}", &h.to.to_string()),
+            None => config.rx_topic.replace("{from}", "unknown").replace("{to}", "unknown"),
+        };
+        let encode = |bytes: &[u8]| if config.hex { hex(bytes) } else { BASE64.encode(bytes) };
+        let mut message = json!({
+            "data": encode(data),
+            "size": size,
+            "crc_ok": crc_ok,
+            "rssi": radio.get_packet_rssi()?,
+            "snr": radio.get_packet_snr()?,
+            "frequency_error": radio.get_frequency_error()?,
+            "timestamp": SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u64,
+        });
+        if let Some(h) = header {
+            message["from"] = json!(h.from);
+            message["to"] = json!(h.to);
+            message["id"] = json!(h.id);
+            message["flags"] = json!(h.flags);
+            message["payload"] = json!(encode(&data[HEADER_LEN..]));
+        }
+        client.publish(&topic, message.to_string().as_bytes())?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encoded(length: usize) -> Vec<u8> {
+        let mut out = Vec::new();
+        encode_length(length, &mut out);
+        out
+    }
+
+    #[test]
+    fn remaining_length() {
+        // the boundaries of one to four bytes, MQTT 3.1.1 table 2.4
+        assert_eq!(encoded(0), [0x00]);
+        assert_eq!(encoded(127), [0x7f]);
+        assert_eq!(encoded(128), [0x80, 0x01]);
+        assert_eq!(encoded(16_383), [0xff, 0x7f]);
+        assert_eq!(encoded(16_384), [0x80, 0x80, 0x01]);
+        assert_eq!(encoded(2_097_152), [0x80, 0x80, 0x80, 0x01]);
+        assert_eq!(encoded(268_435_455), [0xff, 0xff, 0xff, 0x7f]);
+    }
+
+    #[test]
+    fn packets_are_taken_once_complete() {
+        for length in [0, 5, 127, 128, 300, 20_000] {
+            let body: Vec<u8> = (0..length).map(|i| i as u8).collect();
+            let mut packet = vec![PUBLISH];
+            encode_length(length, &mut packet);
+            packet.extend_from_slice(&body);
+
+            // every prefix is incomplete
+            for end in 0..packet.len() {
+                assert_eq!(take_packet(&mut packet[..end].to_vec()).unwrap(), None);
+            }
+            let mut buffer = packet.clone();
+            buffer.extend_from_slice(&[PINGREQ, 0]);
+            assert_eq!(take_packet(&mut buffer).unwrap(), Some((PUBLISH, body)));
+            assert_eq!(take_packet(&mut buffer).unwrap(), Some((PINGREQ, Vec::new())));
+            assert!(buffer.is_empty());
+        }
+    }
+
+    #[test]
+    fn fifth_length_byte_is_malformed() {
+        let mut buffer = vec![PUBLISH, 0xff, 0xff, 0xff, 0xff, 0x01];
+        assert!(take_packet(&mut buffer).is_err());
+        // already known from the fourth byte
+        assert!(take_packet(&mut vec![PUBLISH, 0x80, 0x80, 0x80, 0x80]).is_err());
+    }
+
+    #[test]
+    fn address_from_tx_topic() {
+        let template = "rora/node/{to}/tx";
+        assert_eq!(address_from_topic(template, "rora/node/7/tx"), Some(7));
+        assert_eq!(address_from_topic(template, "rora/node/255/tx"), Some(255));
+        for topic in ["rora/node/256/tx", "rora/node//tx", "rora/node/7/rx", "rora/gw/7/tx", "rora/node/7"] {
+            assert_eq!(address_from_topic(template, topic), None, "{}", topic);
+        }
+        assert_eq!(address_from_topic("downlink/{to}", "downlink/12"), Some(12));
+        assert_eq!(address_from_topic("rora/tx", "rora/tx"), None);
+    }
+
+    fn config(broker: String, tx_topic: &str) -> MqttConfig {
+        MqttConfig {
+            broker,
+            client_id: "rora-test".to_string(),
+            address: 1,
+            rx_topic: "rora/node/{from}/rx".to_string(),
+            tx_topic: tx_topic.to_string(),
+            raw_tx_topic: "rora/raw/tx".to_string(),
+            hex: false,
+        }
+    }
+
+    /// Reads one packet as the broker, blocking until it is complete.
+    fn read_packet(stream: &mut TcpStream, buffer: &mut Vec<u8>) -> (u8, Vec<u8>) {
+        loop {
+            if let Some(packet) = take_packet(buffer).unwrap() {
+                return packet;
+            }
+            let mut chunk = [0u8; 256];
+            let len = stream.read(&mut chunk).unwrap();
+            assert!(len > 0, "client closed the connection");
+            buffer.extend_from_slice(&chunk[..len]);
+        }
+    }
+
+    fn string(body: &[u8]) -> String {
+        let len = u16::from_be_bytes([body[0], body[1]]) as usize;
+        String::from_utf8(body[2..2 + len].to_vec()).unwrap()
+    }
+
+    #[test]
+    fn subscribes_and_receives_from_a_broker() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let broker = listener.local_addr().unwrap().to_string();
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buffer = Vec::new();
+
+            let (packet_type, body) = read_packet(&mut stream, &mut buffer);
+            assert_eq!(packet_type, CONNECT);
+            assert_eq!(string(&body), "MQTT");
+            assert_eq!(body[6..8], [4, 0x02]);
+            assert_eq!(string(&body[10..]), "rora-test");
+            stream.write_all(&[CONNACK, 2, 0, 0]).unwrap();
+
+            let mut filters = Vec::new();
+            for packet_id in [1u16, 2] {
+                let (packet_type, body) = read_packet(&mut stream, &mut buffer);
+                assert_eq!(packet_type, SUBSCRIBE & 0xf0);
+                assert_eq!(body[..2], packet_id.to_be_bytes());
+                // the filter is followed by the requested QoS
+                assert_eq!(body.last(), Some(&0));
+                filters.push(string(&body[2..]));
+            }
+            // a SUBACK is skipped by poll_message, then a downlink arrives
+            stream.write_all(&[0x90, 3, 0, 1, 0]).unwrap();
+            let mut publish = Vec::new();
+            encode_string("rora/node/7/tx", &mut publish);
+            publish.extend_from_slice(b"hello");
+            let mut packet = vec![PUBLISH];
+            encode_length(publish.len(), &mut packet);
+            packet.extend_from_slice(&publish);
+            stream.write_all(&packet).unwrap();
+
+            assert_eq!(read_packet(&mut stream, &mut buffer).0, DISCONNECT);
+            filters
+        });
+
+        let config = config(broker, "rora/node/{to}/tx");
+        let mut client = connect(&config).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let (topic, payload) = loop {
+            if let Some(message) = client.poll_message().unwrap() {
+                break message;
+            }
+            assert!(Instant::now() < deadline, "no downlink");
+            std::thread::sleep(Duration::from_millis(1));
+        };
+        assert_eq!((topic.as_str(), payload.as_slice()), ("rora/node/7/tx", &b"hello"[..]));
+        assert_eq!(address_from_topic(&config.tx_topic, &topic), Some(7));
+        client.disconnect().unwrap();
+        assert_eq!(server.join().unwrap(), ["rora/node/+/tx", "rora/raw/tx"]);
+    }
+
+    #[test]
+    fn tx_topic_needs_an_address() {
+        // rejected before connecting, nothing listens on this port
+        let error = connect(&config("127.0.0.1:1".to_string(), "rora/node/tx")).err().unwrap();
+        assert!(error.to_string().contains("{to}"));
+    }
+}
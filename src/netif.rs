@@ -0,0 +1,524 @@
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::Ipv4Addr;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::process::Command;
+use std::time::{Duration, Instant};
+
+use crate::datagram::{Header, BROADCAST, HEADER_LEN, MAX_PAYLOAD};
+use crate::rfm96w::LoRa;
+
+const TUNSETIFF: libc::c_ulong = 0x4004_54ca;
+const IFF_TUN: libc::c_short = 0x0001;
+const IFF_NO_PI: libc::c_short = 0x1000;
+
+// First byte after the datagram header.
+const WHOLE: u8 = 0x00;
+const FRAGMENT: u8 = 0x01;
+const FRAGMENT_HEADER_LEN: usize = 4;
+const FRAGMENT_PAYLOAD: usize = MAX_PAYLOAD - FRAGMENT_HEADER_LEN;
+
+// Compression rules, the first byte of a reassembled datagram.
+const RULE_UNCOMPRESSED: u8 = 0x00;
+const RULE_IPV4: u8 = 0x01;
+
+// Control bits of the IPv4 rule, for each field that could not be elided.
+const IPV4_TOS: u8 = 0x01;
+const IPV4_DONT_FRAGMENT: u8 = 0x02;
+const IPV4_ID: u8 = 0x04;
+const IPV4_SOURCE: u8 = 0x08;
+const IPV4_DESTINATION: u8 = 0x10;
+
+const POLL_MS: i32 = 5;
+const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Settings of the IP interface.
+pub struct NetifConfig {
+    pub name: String,
+    /// Radio address of this node.
+    pub address: u8,
+    /// Interface address and prefix, e.g. `10.0.0.1/24`, applied with `ip` if given.
+    pub ip: Option<String>,
+    pub mtu: usize,
+    pub peers: PeerMap,
+}
+
+/// Maps IPv4 addresses to radio addresses. Addresses not in the map use their last octet,
+/// so `10.0.0.7` is node `7` unless configured otherwise.
+#[derive(Default)]
+pub struct PeerMap {
+    peers: HashMap<Ipv4Addr, u8>,
+    /// The reverse of `peers`, the first line wins if an address is listed twice.
+    addresses: HashMap<u8, Ipv4Addr>,
+}
+
+impl PeerMap {
+    /// Loads a map with one `ip radio-address` line per peer, `#` starts a comment.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let mut map = PeerMap::default();
+        for line in std::fs::read_to_string(path)?.lines() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            match line.split_whitespace().collect::<Vec<_>>()[..] {
+                [ip, address] => map.insert(ip.parse()?, address.parse()?),
+                _ => return Err(anyhow!("Bad peer line: {}", line)),
+            }
+        }
+        Ok(map)
+    }
+
+    pub fn insert(&mut self, ip: Ipv4Addr, address: u8) {
+        self.peers.insert(ip, address);
+        self.addresses.entry(address).or_insert(ip);
+    }
+
+    fn radio_address(&self, ip: Ipv4Addr) -> u8 {
+        if ip.is_broadcast() || ip.is_multicast() {
+            return BROADCAST;
+        }
+        self.peers.get(&ip).copied().unwrap_or(ip.octets()[3])
+    }
+
+    /// Returns true if `ip` is what the receiver will derive from `address` and `network`.
+    fn derivable(&self, ip: Ipv4Addr, address: u8, network: Ipv4Addr) -> bool {
+        self.ip_address(address, network) == ip
+    }
+
+    /// The inverse of `radio_address`, `network` supplies the upper octets.
+    fn ip_address(&self, address: u8, network: Ipv4Addr) -> Ipv4Addr {
+        if let Some(ip) = self.addresses.get(&address) {
+            return *ip;
+        }
+        let [a, b, c, _] = network.octets();
+        Ipv4Addr::new(a, b, c, address)
+    }
+}
+
+/// Parses the interface address, e.g. `10.0.0.1/24`. Addresses not in the peer map are
+/// derived from the first three octets, so only /24 networks are supported.
+pub fn parse_interface_address(address: &str) -> Result<Ipv4Addr> {
+    match address.split_once('/') {
+        Some((ip, "24")) => Ok(ip.parse()?),
+        _ => Err(anyhow!("{} is not a /24 address, e.g. 10.0.0.1/24.", address)),
+    }
+}
+
+fn checksum(header: &[u8]) -> u16 {
+    let mut sum: u32 = header
+        .chunks(2)
+        .map(|w| u16::from_be_bytes([w[0], *w.get(1).unwrap_or(&0)]) as u32)
+        .sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// SCHC style compression of the IPv4 header: version, header length, total length and
+/// checksum are always rebuilt, fragment offset must be zero, and the addresses are left out
+/// when the receiver can derive them from the radio addresses. Anything else is sent as is.
+fn compress(packet: &[u8], header: &Header, peers: &PeerMap, network: Ipv4Addr) -> Vec<u8> {
+    let simple = packet.len() >= 20
+        && packet[0] == 0x45
+        && u16::from_be_bytes([packet[6], packet[7]]) & 0x3fff == 0;
+    if !simple {
+        let mut out = vec![RULE_UNCOMPRESSED];
+        out.extend_from_slice(packet);
+        return out;
+    }
+
+    let source = Ipv4Addr::new(packet[12], packet[13], packet[14], packet[15]);
+    let destination = Ipv4Addr::new(packet[16], packet[17], packet[18], packet[19]);
+    let mut control = 0;
+    let mut fields = Vec::new();
+    if packet[1] != 0 {
+        control |= IPV4_TOS;
+        fields.push(packet[1]);
+    }
+    if packet[6] & 0x40 != 0 {
+        control |= IPV4_DONT_FRAGMENT;
+    } else {
+        // the id only matters to hosts that may fragment
+        control |= IPV4_ID;
+        fields.extend_from_slice(&packet[4..6]);
+    }
+    if !peers.derivable(source, header.from, network) {
+        control |= IPV4_SOURCE;
+        fields.extend_from_slice(&packet[12..16]);
+    }
+    if header.to == BROADCAST || !peers.derivable(destination, header.to, network) {
+        control |= IPV4_DESTINATION;
+        fields.extend_from_slice(&packet[16..20]);
+    }
+
+    let mut out = vec![RULE_IPV4, control, packet[9], packet[8]];
+    out.extend_from_slice(&fields);
+    out.extend_from_slice(&packet[20..]);
+    out
+}
+
+fn decompress(data: &[u8], header: &Header, peers: &PeerMap, network: Ipv4Addr) -> Result<Vec<u8>> {
+    let (&rule, rest) = data.split_first().ok_or_else(|| anyhow!("Empty datagram."))?;
+    match rule {
+        RULE_UNCOMPRESSED => Ok(rest.to_vec()),
+        RULE_IPV4 => {
+            if rest.len() < 3 {
+                return Err(anyhow!("Short IPv4 rule."));
+            }
+            let (control, protocol, ttl) = (rest[0], rest[1], rest[2]);
+            let mut fields = rest[3..].iter().copied();
+            let mut take = |n: usize| -> Result<Vec<u8>> {
+                let taken: Vec<u8> = fields.by_ref().take(n).collect();
+                if taken.len() == n { Ok(taken) } else { Err(anyhow!("Short IPv4 rule.")) }
+            };
+            let tos = if control & IPV4_TOS != 0 { take(1)?[0] } else { 0 };
+            let id = if control & IPV4_ID != 0 { take(2)? } else { vec![0, 0] };
+            let source = if control & IPV4_SOURCE != 0 {
+                take(4)?
+            } else {
+                peers.ip_address(header.from, network).octets().to_vec()
+            };
+            let destination = if control & IPV4_DESTINATION != 0 {
+                take(4)?
+            } else {
+                peers.ip_address(header.to, network).octets().to_vec()
+            };
+            let payload: Vec<u8> = fields.collect();
+
+            let total = (20 + payload.len()) as u16;
+            let mut packet = vec![0x45, tos];
+            packet.extend_from_slice(&total.to_be_bytes());
+            packet.extend_from_slice(&id);
+            packet.extend_from_slice(&[if control & IPV4_DONT_FRAGMENT != 0 { 0x40 } else { 0 }, 0]);
+            packet.extend_from_slice(&[ttl, protocol, 0, 0]);
+            packet.extend_from_slice(&source);
+            packet.extend_from_slice(&destination);
+            let sum = checksum(&packet);
+            packet[10..12].copy_from_slice(&sum.to_be_bytes());
+            packet.extend_from_slice(&payload);
+            Ok(packet)
+        }
+        _ => Err(anyhow!("Unknown compression rule {}.", rule)),
+    }
+}
+
+/// Fragments waiting for the rest of their datagram.
+struct Reassembly {
+    fragments: Vec<Option<Vec<u8>>>,
+    started: Instant,
+}
+
+/// Carries the packets of the interface, the radio or a simulated one in tests.
+pub trait Link {
+    /// Sends one packet of at most 255 bytes, header included.
+    fn send_packet(&mut self, packet: &[u8]) -> Result<()>;
+    /// Waits up to `timeout_ms` for a packet, `None` on timeout or a CRC error.
+    fn receive_packet(&mut self, timeout_ms: i32) -> Result<Option<Vec<u8>>>;
+}
+
+impl Link for LoRa {
+    fn send_packet(&mut self, packet: &[u8]) -> Result<()> {
+        self.tx_bulk(packet)
+    }
+
+    fn receive_packet(&mut self, timeout_ms: i32) -> Result<Option<Vec<u8>>> {
+        let Some(size) = self.poll_packet(timeout_ms)? else {
+            return Ok(None);
+        };
+        let crc_error = self.crc_error();
+        let packet = self.read_packet()?;
+        Ok((!crc_error).then(|| packet[..size].to_vec()))
+    }
+}
+
+/// Two radios on the same channel for tests, everything one sends the other receives. Once
+/// the other radio is dropped, receiving fails like a radio that stopped answering.
+#[cfg(test)]
+pub(crate) struct SimRadio {
+    tx: std::sync::mpsc::Sender<Vec<u8>>,
+    rx: std::sync::mpsc::Receiver<Vec<u8>>,
+}
+
+#[cfg(test)]
+impl SimRadio {
+    pub(crate) fn pair() -> (SimRadio, SimRadio) {
+        let (a_tx, b_rx) = std::sync::mpsc::channel();
+        let (b_tx, a_rx) = std::sync::mpsc::channel();
+        (SimRadio { tx: a_tx, rx: a_rx }, SimRadio { tx: b_tx, rx: b_rx })
+    }
+}
+
+#[cfg(test)]
+impl Link for SimRadio {
+    fn send_packet(&mut self, packet: &[u8]) -> Result<()> {
+        assert!(packet.len() <= 255, "{} byte packet", packet.len());
+        // the other end may already be gone, like a radio nobody listens to
+        let _ = self.tx.send(packet.to_vec());
+        Ok(())
+    }
+
+    fn receive_packet(&mut self, timeout_ms: i32) -> Result<Option<Vec<u8>>> {
+        use std::sync::mpsc::RecvTimeoutError;
+        match self.rx.recv_timeout(Duration::from_millis(timeout_ms as u64)) {
+            Ok(packet) => Ok(Some(packet)),
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            Err(RecvTimeoutError::Disconnected) => Err(anyhow!("Simulated radio disconnected.")),
+        }
+    }
+}
+
+/// The radio side of the interface: compression, fragmentation and reassembly.
+struct Node<'a> {
+    address: u8,
+    peers: &'a PeerMap,
+    network: Ipv4Addr,
+    tag: u8,
+    reassembly: HashMap<(u8, u8), Reassembly>,
+}
+
+impl<'a> Node<'a> {
+    fn new(address: u8, peers: &'a PeerMap, network: Ipv4Addr) -> Self {
+        Node { address, peers, network, tag: 0, reassembly: HashMap::new() }
+    }
+
+    /// Compresses an IP packet read from the interface and sends it.
+    fn transmit<L: Link>(&mut self, link: &mut L, packet: &[u8]) -> Result<()> {
+        // only IPv4 destinations can be mapped, everything else is broadcast
+        let to = if packet.len() >= 20 && packet[0] >> 4 == 4 {
+            self.peers.radio_address(Ipv4Addr::new(packet[16], packet[17], packet[18], packet[19]))
+        } else {
+            BROADCAST
+        };
+        let header = Header { to, from: self.address, id: self.tag, flags: 0 };
+        let data = compress(packet, &header, self.peers, self.network);
+        self.tag = self.tag.wrapping_add(1);
+        send(link, header, &data)
+    }
+
+    /// Handles a received packet, returns the IP packet once its datagram is complete.
+    fn receive(&mut self, packet: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.reassembly.retain(|_, r| r.started.elapsed() < REASSEMBLY_TIMEOUT);
+        if packet.len() <= HEADER_LEN + 1 {
+            return Ok(None);
+        }
+        let header = Header::from_bytes(packet)?;
+        if !header.is_for(self.address) || header.from == self.address {
+            return Ok(None);
+        }
+        let body = &packet[HEADER_LEN..];
+        let datagram = match body[0] {
+            WHOLE => body[1..].to_vec(),
+            FRAGMENT if body.len() > FRAGMENT_HEADER_LEN => {
+                let (index, count) = (body[2] as usize, body[3] as usize);
+                let entry = self.reassembly.entry((header.from, body[1])).or_insert_with(|| Reassembly {
+                    fragments: vec![None; count],
+                    started: Instant::now(),
+                });
+                if index < entry.fragments.len() {
+                    entry.fragments[index] = Some(body[FRAGMENT_HEADER_LEN..].to_vec());
+                }
+                if entry.fragments.iter().any(Option::is_none) {
+                    return Ok(None);
+                }
+                let entry = self.reassembly.remove(&(header.from, body[1])).unwrap();
+                entry.fragments.into_iter().flatten().flatten().collect()
+            }
+            _ => return Ok(None),
+        };
+        decompress(&datagram, &header, self.peers, self.network)
+            .map(Some)
+            .map_err(|e| anyhow!("Dropped datagram from {}: {}", header.from, e))
+    }
+}
+
+/// Opens a TUN device without packet information headers.
+fn open_tun(name: &str) -> Result<File> {
+    let file = OpenOptions::new().read(true).write(true).open("/dev/net/tun")?;
+    // struct ifreq: the name, then the flags in the union
+    let mut ifreq = [0u8; 40];
+    let len = name.len().min(15);
+    ifreq[..len].copy_from_slice(&name.as_bytes()[..len]);
+    ifreq[16..18].copy_from_slice(&(IFF_TUN | IFF_NO_PI).to_ne_bytes());
+    // SAFETY: ifreq is large enough for struct ifreq and outlives the call.
+    unsafe {
+        if libc::ioctl(file.as_raw_fd(), TUNSETIFF as _, ifreq.as_mut_ptr()) < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        let flags = libc::fcntl(file.as_raw_fd(), libc::F_GETFL);
+        libc::fcntl(file.as_raw_fd(), libc::F_SETFL, flags | libc::O_NONBLOCK);
+    }
+    Ok(file)
+}
+
+fn ip(args: &[&str]) -> Result<()> {
+    let status = Command::new("ip").args(args).status()?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(anyhow!("ip {} failed.", args.join(" ")))
+    }
+}
+
+/// Bridges a TUN interface to the radio. IP packets are compressed, split into fragments
+/// that fit a LoRa packet and addressed by the peer map; received fragments are reassembled,
+/// decompressed and written back to the interface.
+pub fn run<L: Link>(radio: &mut L, config: &NetifConfig) -> Result<()> {
+    let network = match &config.ip {
+        Some(address) => parse_interface_address(address)?,
+        None => Ipv4Addr::UNSPECIFIED,
+    };
+    let mut tun = open_tun(&config.name)?;
+    ip(&["link", "set", "dev", &config.name, "mtu", &config.mtu.to_string()])?;
+    if let Some(address) = &config.ip {
+        ip(&["addr", "add", address, "dev", &config.name])?;
+    }
+    ip(&["link", "set", "dev", &config.name, "up"])?;
+    println!("{} up, node {}, MTU {}.", config.name, config.address, config.mtu);
+
+    let mut node = Node::new(config.address, &config.peers, network);
+    let mut buffer = vec![0u8; config.mtu.max(1500)];
+    loop {
+        loop {
+            let len = match tun.read(&mut buffer) {
+                Ok(len) => len,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e.into()),
+            };
+            node.transmit(radio, &buffer[..len])?;
+        }
+
+        let Some(packet) = radio.receive_packet(POLL_MS)? else {
+            continue;
+        };
+        match node.receive(&packet) {
+            Ok(Some(ip_packet)) => {
+                if let Err(e) = tun.write_all(&ip_packet) {
+                    println!("TUN write failed: {}", e);
+                }
+            }
+            Ok(None) => {}
+            Err(e) => println!("{}", e),
+        }
+    }
+}
+
+/// Sends a compressed datagram, in fragments if it does not fit in one packet.
+fn send<L: Link>(link: &mut L, header: Header, data: &[u8]) -> Result<()> {
+    let mut packet = header.to_bytes().to_vec();
+    if data.len() < MAX_PAYLOAD {
+        packet.push(WHOLE);
+        packet.extend_from_slice(data);
+        return link.send_packet(&packet);
+    }
+    let count = data.len().div_ceil(FRAGMENT_PAYLOAD);
+    if count > u8::MAX as usize {
+        return Err(anyhow!("Datagram too large."));
+    }
+    for (index, chunk) in data.chunks(FRAGMENT_PAYLOAD).enumerate() {
+        packet.truncate(HEADER_LEN);
+        packet.extend_from_slice(&[FRAGMENT, header.id, index as u8, count as u8]);
+        packet.extend_from_slice(chunk);
+        link.send_packet(&packet)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An IPv4 packet with a valid checksum, `flags` being the high byte of the flags field.
+    /// The id is only kept without don't fragment.
+    fn ipv4(source: [u8; 4], destination: [u8; 4], flags: u8, payload: &[u8]) -> Vec<u8> {
+        let total = (20 + payload.len()) as u16;
+        let mut packet = vec![0x45, 0];
+        packet.extend_from_slice(&total.to_be_bytes());
+        let id = if flags & 0x40 == 0 { [0x12, 0x34] } else { [0, 0] };
+        packet.extend_from_slice(&id);
+        packet.extend_from_slice(&[flags, 0, 64, 17, 0, 0]);
+        packet.extend_from_slice(&source);
+        packet.extend_from_slice(&destination);
+        let sum = checksum(&packet);
+        packet[10..12].copy_from_slice(&sum.to_be_bytes());
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    /// Sends `packet` from `a` and returns what `b` writes to its interface.
+    fn exchange(a: &mut Node, b: &mut Node, packet: &[u8]) -> (usize, Vec<Vec<u8>>) {
+        let (mut a_radio, mut b_radio) = SimRadio::pair();
+        a.transmit(&mut a_radio, packet).unwrap();
+        let mut sent = 0;
+        let mut delivered = Vec::new();
+        while let Some(received) = b_radio.receive_packet(0).unwrap() {
+            sent += 1;
+            delivered.extend(b.receive(&received).unwrap());
+        }
+        (sent, delivered)
+    }
+
+    #[test]
+    fn ping_over_simulated_radio() {
+        let peers = PeerMap::default();
+        let network = parse_interface_address("10.0.0.1/24").unwrap();
+        let mut a = Node::new(1, &peers, network);
+        let mut b = Node::new(2, &peers, network);
+
+        // don't fragment set, so the id and both addresses are elided
+        let packet = ipv4([10, 0, 0, 1], [10, 0, 0, 2], 0x40, b"ping");
+        let (sent, delivered) = exchange(&mut a, &mut b, &packet);
+        assert_eq!((sent, delivered), (1, vec![packet.clone()]));
+        let (_, delivered) = exchange(&mut b, &mut a, &ipv4([10, 0, 0, 2], [10, 0, 0, 1], 0, b"pong"));
+        assert_eq!(delivered, vec![ipv4([10, 0, 0, 2], [10, 0, 0, 1], 0, b"pong")]);
+
+        // a packet for another node is not delivered
+        let mut c = Node::new(3, &peers, network);
+        assert!(exchange(&mut a, &mut c, &packet).1.is_empty());
+    }
+
+    #[test]
+    fn large_packets_are_fragmented() {
+        // a peer outside the network, mapped to node 2
+        let mut peers = PeerMap::default();
+        peers.insert(Ipv4Addr::new(192, 168, 1, 9), 2);
+        let network = parse_interface_address("10.0.0.1/24").unwrap();
+        let mut a = Node::new(1, &peers, network);
+        let mut b = Node::new(2, &peers, network);
+        let payload: Vec<u8> = (0..1200).map(|i| i as u8).collect();
+        let packet = ipv4([10, 0, 0, 1], [192, 168, 1, 9], 0, &payload);
+        let (sent, delivered) = exchange(&mut a, &mut b, &packet);
+        assert!(sent > 4);
+        assert_eq!(delivered, vec![packet]);
+        assert!(b.reassembly.is_empty());
+    }
+
+    #[test]
+    fn peer_map_lookups_are_deterministic() {
+        let path = std::env::temp_dir().join(format!("rora-peers-{}", std::process::id()));
+        std::fs::write(&path, "10.0.0.20 5 # gateway\n10.0.0.30 5\n10.0.0.40 6\n").unwrap();
+        let peers = PeerMap::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let network = Ipv4Addr::new(10, 0, 0, 0);
+        for _ in 0..10 {
+            assert_eq!(peers.ip_address(5, network), Ipv4Addr::new(10, 0, 0, 20));
+        }
+        assert_eq!(peers.radio_address(Ipv4Addr::new(10, 0, 0, 30)), 5);
+        assert_eq!(peers.ip_address(7, network), Ipv4Addr::new(10, 0, 0, 7));
+        assert_eq!(peers.radio_address(Ipv4Addr::new(10, 0, 0, 7)), 7);
+        assert_eq!(peers.radio_address(Ipv4Addr::BROADCAST), BROADCAST);
+    }
+
+    #[test]
+    fn only_slash_24_is_accepted() {
+        assert_eq!(parse_interface_address("10.0.0.1/24").unwrap(), Ipv4Addr::new(10, 0, 0, 1));
+        for address in ["10.0.0.1", "10.0.0.1/16", "10.0.0.1/32", "10.0.0/24"] {
+            assert!(parse_interface_address(address).is_err(), "{}", address);
+        }
+    }
+}
@@ -0,0 +1,173 @@
+use std::time::Duration;
+
+use crate::rfm96w::RadioMode;
+
+/// Supply currents in milliamps from the SX1276 datasheet (table 6).
+pub const SLEEP_MA: f64 = 0.0002;
+pub const STANDBY_MA: f64 = 1.6;
+pub const SYNTHESIZER_MA: f64 = 5.8;
+pub const RX_MA: f64 = 10.8;
+pub const RX_LNA_BOOST_MA: f64 = 11.5;
+/// Transmit current at every power `TxPower` accepts, in dBm, for RFO and PA_BOOST. The
+/// datasheet only gives 7 and 13 dBm on RFO and 17 and 20 dBm on PA_BOOST, the rest are
+/// estimates between and below those that keep the current rising with the power.
+const TX_RFO_MA: [(i32, f64); 19] = [
+    (-3, 13.0), (-2, 13.5), (-1, 14.0), (0, 14.5), (1, 15.0), (2, 15.5), (3, 16.2),
+    (4, 17.0), (5, 17.8), (6, 18.9), (7, 20.0), (8, 21.3), (9, 22.6), (10, 24.0),
+    (11, 25.5), (12, 27.2), (13, 29.0), (14, 31.0), (15, 33.0),
+];
+const TX_PA_BOOST_MA: [(i32, f64); 19] = [
+    (2, 24.0), (3, 25.0), (4, 26.0), (5, 27.0), (6, 28.0), (7, 30.0), (8, 32.0),
+    (9, 34.0), (10, 36.0), (11, 39.0), (12, 42.0), (13, 45.0), (14, 50.0), (15, 60.0),
+    (16, 73.0), (17, 87.0), (18, 95.0), (19, 107.0), (20, 120.0),
+];
+
+/// Time the radio spent in each mode, see `LoRa::usage`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Usage {
+    pub sleep: Duration,
+    pub standby: Duration,
+    pub synthesizer: Duration,
+    pub tx: Duration,
+    /// RX continuous and RX single.
+    pub rx: Duration,
+    pub cad: Duration,
+}
+
+/// Charge drawn by the radio over a period of `Usage`.
+#[derive(Clone, Copy, Debug)]
+pub struct Estimate {
+    pub elapsed: Duration,
+    pub charge_mah: f64,
+    pub average_ma: f64,
+}
+
+impl Usage {
+    pub fn add(&mut self, mode: RadioMode, time: Duration) {
+        match mode {
+            RadioMode::Sleep | RadioMode::LongRangeMode => self.sleep += time,
+            RadioMode::Stdby => self.standby += time,
+            RadioMode::FsTx => self.synthesizer += time,
+            RadioMode::Tx => self.tx += time,
+            RadioMode::RxContinuous | RadioMode::RxSingle => self.rx += time,
+            RadioMode::Cad => self.cad += time,
+        }
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.sleep + self.standby + self.synthesizer + self.tx + self.rx + self.cad
+    }
+
+    /// Combines the time in each mode with the transmit and receive current, e.g. from
+    /// `tx_current_ma`. CAD is counted at the receive current.
+    pub fn estimate(&self, tx_ma: f64, rx_ma: f64) -> Estimate {
+        let ms = [
+            (self.sleep, SLEEP_MA),
+            (self.standby, STANDBY_MA),
+            (self.synthesizer, SYNTHESIZER_MA),
+            (self.tx, tx_ma),
+            (self.rx + self.cad, rx_ma),
+        ]
+        .iter()
+        .map(|(time, ma)| time.as_secs_f64() * ma)
+        .sum::<f64>();
+        let elapsed = self.elapsed();
+        Estimate {
+            elapsed,
+            charge_mah: ms / 3600.0,
+            average_ma: if elapsed.is_zero() { 0.0 } else { ms / elapsed.as_secs_f64() },
+        }
+    }
+}
+
+impl Estimate {
+    /// How long a battery of `capacity_mah` lasts at the average current.
+    pub fn battery_life(&self, capacity_mah: f64) -> Option<Duration> {
+        (self.average_ma > 0.0).then(|| Duration::from_secs_f64(capacity_mah / self.average_ma * 3600.0))
+    }
+}
+
+/// Transmit current at an output power, powers outside the output's range are clamped to it.
+pub fn tx_current_ma(pa_boost: bool, dbm: i32) -> f64 {
+    let table = if pa_boost { &TX_PA_BOOST_MA } else { &TX_RFO_MA };
+    let index = (dbm - table[0].0).clamp(0, table.len() as i32 - 1);
+    table[index as usize].1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tx_current_tables() {
+        for table in [TX_RFO_MA, TX_PA_BOOST_MA] {
+            for pair in table.windows(2) {
+                assert_eq!(pair[1].0, pair[0].0 + 1, "one entry per dBm");
+                assert!(pair[1].1 > pair[0].1, "{} dBm draws less than {} dBm", pair[1].0, pair[0].0);
+            }
+            assert!(table[0].1 > SYNTHESIZER_MA);
+        }
+        // the range `TxPower` accepts
+        assert_eq!((TX_RFO_MA[0].0, TX_RFO_MA[18].0), (-3, 15));
+        assert_eq!((TX_PA_BOOST_MA[0].0, TX_PA_BOOST_MA[18].0), (2, 20));
+    }
+
+    #[test]
+    fn tx_current_matches_the_datasheet() {
+        assert_eq!(tx_current_ma(false, 7), 20.0);
+        assert_eq!(tx_current_ma(false, 13), 29.0);
+        assert_eq!(tx_current_ma(true, 17), 87.0);
+        assert_eq!(tx_current_ma(true, 20), 120.0);
+        // clamped to the ends of the table
+        assert_eq!(tx_current_ma(false, -10), 13.0);
+        assert_eq!(tx_current_ma(false, 20), 33.0);
+        assert_eq!(tx_current_ma(true, 0), 24.0);
+        assert_eq!(tx_current_ma(true, 30), 120.0);
+    }
+
+    #[test]
+    fn usage_by_mode() {
+        let mut usage = Usage::default();
+        usage.add(RadioMode::Sleep, Duration::from_secs(3000));
+        usage.add(RadioMode::LongRangeMode, Duration::from_secs(540));
+        usage.add(RadioMode::Stdby, Duration::from_secs(36));
+        usage.add(RadioMode::FsTx, Duration::from_millis(100));
+        usage.add(RadioMode::Tx, Duration::from_secs(18));
+        usage.add(RadioMode::RxContinuous, Duration::from_secs(3));
+        usage.add(RadioMode::RxSingle, Duration::from_secs(2));
+        usage.add(RadioMode::Cad, Duration::from_millis(900));
+        assert_eq!(usage.sleep, Duration::from_secs(3540));
+        assert_eq!(usage.rx, Duration::from_secs(5));
+        assert_eq!(usage.elapsed(), Duration::from_secs(3600));
+    }
+
+    #[test]
+    fn estimate_and_battery_life() {
+        let usage = Usage {
+            sleep: Duration::from_secs(3564),
+            standby: Duration::from_secs(18),
+            tx: Duration::from_secs(9),
+            rx: Duration::from_secs(6),
+            cad: Duration::from_secs(3),
+            ..Usage::default()
+        };
+        let estimate = usage.estimate(tx_current_ma(true, 20), RX_MA);
+        let charge_mas = 3564.0 * SLEEP_MA + 18.0 * STANDBY_MA + 9.0 * 120.0 + 9.0 * RX_MA;
+        assert_eq!(estimate.elapsed, Duration::from_secs(3600));
+        assert!((estimate.charge_mah - charge_mas / 3600.0).abs() < 1e-9);
+        // an hour, so the average current is the charge per hour
+        assert!((estimate.average_ma - estimate.charge_mah).abs() < 1e-9);
+
+        let life = estimate.battery_life(2000.0).unwrap();
+        assert!((life.as_secs_f64() / 3600.0 - 2000.0 / estimate.average_ma).abs() < 1e-6);
+        // twice the battery lasts twice as long
+        assert!((estimate.battery_life(4000.0).unwrap().as_secs_f64() - 2.0 * life.as_secs_f64()).abs() < 1e-3);
+    }
+
+    #[test]
+    fn nothing_recorded() {
+        let estimate = Usage::default().estimate(120.0, RX_MA);
+        assert_eq!((estimate.charge_mah, estimate.average_ma), (0.0, 0.0));
+        assert!(estimate.battery_life(2000.0).is_none());
+    }
+}
@@ -0,0 +1,366 @@
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+use std::sync::{Mutex, PoisonError};
+
+use crate::compress::{Compressor as RustCompressor, Lzss};
+use crate::datagram;
+use crate::fec;
+use crate::rfm96w::{self, HeaderMode, LnaGain, TxPower};
+use crate::secure::{self, Key};
+
+/// A received packet and its link metadata.
+#[pyclass(frozen, get_all)]
+pub struct Packet {
+    data: Py<PyBytes>,
+    rssi: f32,
+    snr: f32,
+    frequency_error: i64,
+    crc_ok: bool,
+}
+
+#[pymethods]
+impl Packet {
+    fn __repr__(&self, py: Python<'_>) -> String {
+        format!(
+            "Packet({} bytes, rssi={:.0}, snr={:.1}, crc_ok={})",
+            self.data.bind(py).as_bytes().len(),
+            self.rssi,
+            self.snr,
+            self.crc_ok
+        )
+    }
+}
+
+/// The RFM96W on the board's default pins. It may be used from any thread, e.g. by
+/// `receive_async` in an executor, but by one call at a time.
+#[pyclass(name = "LoRa")]
+pub struct LoRa {
+    /// Only for `Sync`, pyo3's borrow checking already makes every access exclusive.
+    radio: Mutex<rfm96w::LoRa>,
+}
+
+impl LoRa {
+    fn radio(&mut self) -> &mut rfm96w::LoRa {
+        self.radio.get_mut().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[pymethods]
+impl LoRa {
+    #[new]
+    fn new() -> anyhow::Result<Self> {
+        Ok(LoRa { radio: Mutex::new(crate::open_radio()?) })
+    }
+
+    /// Frequency in hertz.
+    #[getter]
+    fn frequency(&mut self) -> u64 {
+        self.radio().get_frequency()
+    }
+
+    #[setter]
+    fn set_frequency(&mut self, hz: u64) -> anyhow::Result<()> {
+        self.radio().set_frequency_hz(hz)
+    }
+
+    #[getter]
+    fn spreading_factor(&mut self) -> anyhow::Result<u8> {
+        self.radio().get_spreading_factor()
+    }
+
+    #[setter]
+    fn set_spreading_factor(&mut self, sf: u8) -> anyhow::Result<()> {
+        self.radio().set_spreading_factor(sf)
+    }
+
+    /// Signal bandwidth in hertz.
+    #[getter]
+    fn bandwidth(&mut self) -> anyhow::Result<i64> {
+        self.radio().get_signal_bandwidth()
+    }
+
+    #[setter]
+    fn set_bandwidth(&mut self, hz: i64) -> anyhow::Result<()> {
+        self.radio().set_signal_bandwidth(hz)
+    }
+
+    /// Coding rate denominator, 5 to 8 for 4/5 to 4/8.
+    #[getter]
+    fn coding_rate(&mut self) -> anyhow::Result<u8> {
+        self.radio().get_coding_rate_4()
+    }
+
+    #[setter]
+    fn set_coding_rate(&mut self, denominator: u8) -> anyhow::Result<()> {
+        self.radio().set_coding_rate_4(denominator)
+    }
+
+    #[pyo3(signature = (dbm, pa_boost = true))]
+    fn set_tx_power(&mut self, dbm: i32, pa_boost: bool) -> anyhow::Result<()> {
+        let power = if pa_boost { TxPower::pa_boost(dbm) } else { TxPower::rfo(dbm) };
+        self.radio().set_tx_power(power)
+    }
+
+    /// Fixed LNA gain from 1 (maximum) to 6, `None` while the AGC sets it.
+    #[getter]
+    fn lna_gain(&mut self) -> anyhow::Result<Option<u8>> {
+        Ok(match self.radio().get_lna_gain()? {
+            LnaGain::Agc => None,
+            gain => Some(gain as u8),
+        })
+    }
+
+    #[setter]
+    fn set_lna_gain(&mut self, gain: Option<u8>) -> anyhow::Result<()> {
+        let gain = match gain {
+            None => LnaGain::Agc,
+            Some(1) => LnaGain::G1,
+            Some(2) => LnaGain::G2,
+            Some(3) => LnaGain::G3,
+            Some(4) => LnaGain::G4,
+            Some(5) => LnaGain::G5,
+            Some(6) => LnaGain::G6,
+            Some(other) => return Err(anyhow::anyhow!("LNA gain {} is not between 1 and 6.", other)),
+        };
+        self.radio().set_lna_gain(gain)
+    }
+
+    /// Transmit power in dBm.
+    #[getter]
+    fn tx_power(&mut self) -> anyhow::Result<i32> {
+        Ok(self.radio().tx_power()?.dbm)
+    }
+
+    fn set_preamble_length(&mut self, length: i64) -> anyhow::Result<()> {
+        self.radio().set_preamble_length(length)
+    }
+
+    fn set_crc(&mut self, enabled: bool) -> anyhow::Result<()> {
+        self.radio().set_crc(enabled)
+    }
+
+    fn set_invert_iq(&mut self, inverted: bool) -> anyhow::Result<()> {
+        self.radio().set_invert_iq(inverted)
+    }
+
+    /// Fixed payload length of implicit header mode, `None` for an explicit header.
+    #[getter]
+    fn implicit_length(&mut self) -> Option<u8> {
+        match self.radio().get_header_mode() {
+            HeaderMode::Explicit => None,
+            HeaderMode::Implicit(length) => Some(length),
+        }
+    }
+
+    #[setter]
+    fn set_implicit_length(&mut self, length: Option<u8>) -> anyhow::Result<()> {
+        self.radio().set_header_mode(length.map_or(HeaderMode::Explicit, HeaderMode::Implicit))
+    }
+
+    /// Sends up to 255 bytes and blocks until they are on air.
+    fn transmit(&mut self, py: Python<'_>, data: &[u8]) -> PyResult<()> {
+        if data.len() > 255 {
+            return Err(PyValueError::new_err("payload exceeds 255 bytes"));
+        }
+        let mut buffer = [0u8; 255];
+        buffer[..data.len()].copy_from_slice(data);
+        let radio = self.radio();
+        py.allow_threads(|| radio.transmit_payload_busy(buffer, data.len()))?;
+        Ok(())
+    }
+
+    /// Waits for a packet, for at most `timeout_ms` if given. Returns `None` on timeout. The
+    /// GIL is released while waiting, so other threads (or `receive_async`) keep running.
+    #[pyo3(signature = (timeout_ms = None))]
+    fn receive(&mut self, py: Python<'_>, timeout_ms: Option<i32>) -> PyResult<Option<Packet>> {
+        let radio = self.radio();
+        let received = py.allow_threads(|| -> anyhow::Result<Option<_>> {
+            let size = match timeout_ms {
+                Some(timeout_ms) => match radio.poll_packet(timeout_ms)? {
+                    Some(size) => size,
+                    None => return Ok(None),
+                },
+                None => radio.poll_irq(None)?,
+            };
+            let crc_ok = !radio.crc_error();
+            let buffer = radio.read_packet()?;
+            Ok(Some((
+                buffer[..size].to_vec(),
+                radio.get_packet_rssi()?,
+                radio.get_packet_snr()?,
+                radio.get_frequency_error()?,
+                crc_ok,
+            )))
+        })?;
+        Ok(received.map(|(data, rssi, snr, frequency_error, crc_ok)| Packet {
+            data: PyBytes::new(py, &data).unbind(),
+            rssi,
+            snr,
+            frequency_error,
+            crc_ok,
+        }))
+    }
+}
+
+/// The RadioHead style datagram header.
+#[pyclass(frozen, get_all, eq)]
+#[derive(Clone, PartialEq)]
+pub struct Header {
+    to: u8,
+    #[pyo3(name = "from_")]
+    from: u8,
+    id: u8,
+    flags: u8,
+}
+
+#[pymethods]
+impl Header {
+    #[new]
+    #[pyo3(signature = (to, from_, id = 0, flags = 0))]
+    fn new(to: u8, from_: u8, id: u8, flags: u8) -> Self {
+        Header { to, from: from_, id, flags }
+    }
+
+    fn __repr__(&self) -> String {
+        format!("Header(to={}, from_={}, id={}, flags={:#04x})", self.to, self.from, self.id, self.flags)
+    }
+}
+
+impl From<datagram::Header> for Header {
+    fn from(h: datagram::Header) -> Self {
+        Header { to: h.to, from: h.from, id: h.id, flags: h.flags }
+    }
+}
+
+impl From<&Header> for datagram::Header {
+    fn from(h: &Header) -> Self {
+        datagram::Header { to: h.to, from: h.from, id: h.id, flags: h.flags }
+    }
+}
+
+/// AEAD encryption with pre-shared keys per peer, see `rora::secure`.
+#[pyclass(unsendable)]
+pub struct SecureChannel {
+    channel: secure::SecureChannel,
+}
+
+#[pymethods]
+impl SecureChannel {
+    #[new]
+    fn new(address: u8) -> Self {
+        SecureChannel { channel: secure::SecureChannel::new(address) }
+    }
+
+    /// `cipher` is `"aes128"` or `"chacha20"`, `key` is hex.
+    fn add_peer(&mut self, address: u8, cipher: &str, key: &str) -> anyhow::Result<()> {
+        self.channel.add_peer(address, Key::parse(cipher, key)?);
+        Ok(())
+    }
+
+    /// Keeps the transmit counter in `path`, required before `seal`.
+    fn persist_counter(&mut self, path: &str) -> anyhow::Result<()> {
+        self.channel.persist_counter(path)
+    }
+
+    #[pyo3(signature = (to, payload, flags = 0))]
+    fn seal<'py>(&mut self, py: Python<'py>, to: u8, payload: &[u8], flags: u8) -> anyhow::Result<Bound<'py, PyBytes>> {
+        Ok(PyBytes::new(py, &self.channel.seal(to, flags, payload)?))
+    }
+
+    fn open<'py>(&mut self, py: Python<'py>, packet: &[u8]) -> anyhow::Result<(Header, Bound<'py, PyBytes>)> {
+        let (header, plaintext) = self.channel.open(packet)?;
+        Ok((header.into(), PyBytes::new(py, &plaintext)))
+    }
+}
+
+/// LZSS compression negotiated by a header flag, see `rora::compress`.
+#[pyclass(unsendable)]
+pub struct Compressor {
+    compressor: RustCompressor,
+}
+
+#[pymethods]
+impl Compressor {
+    /// Plain LZSS, or static dictionary mode if `dictionary` is given. `"telemetry"` selects
+    /// the built in JSON telemetry dictionary.
+    #[new]
+    #[pyo3(signature = (dictionary = None))]
+    fn new(dictionary: Option<&Bound<'_, PyAny>>) -> PyResult<Self> {
+        let codec = match dictionary {
+            None => Lzss::new(),
+            Some(d) if d.extract::<&str>().is_ok_and(|s| s == "telemetry") => Lzss::telemetry(),
+            Some(d) => Lzss::with_dictionary(d.extract::<&[u8]>()?),
+        };
+        Ok(Compressor { compressor: RustCompressor::new(Box::new(codec)) })
+    }
+
+    fn encode<'py>(&self, py: Python<'py>, header: &Header, payload: &[u8]) -> Bound<'py, PyBytes> {
+        PyBytes::new(py, &self.compressor.encode(header.into(), payload))
+    }
+
+    fn decode<'py>(&self, py: Python<'py>, message: &[u8]) -> anyhow::Result<(Header, Bound<'py, PyBytes>)> {
+        let (header, payload) = self.compressor.decode(message)?;
+        Ok((header.into(), PyBytes::new(py, &payload)))
+    }
+}
+
+/// Erasure coding across the packets of a transfer, see `rora::fec`.
+#[pyclass(unsendable)]
+pub struct FecEncoder {
+    encoder: fec::FecEncoder,
+}
+
+#[pymethods]
+impl FecEncoder {
+    #[new]
+    #[pyo3(signature = (overhead_percent = 50))]
+    fn new(overhead_percent: usize) -> Self {
+        FecEncoder { encoder: fec::FecEncoder::new(overhead_percent) }
+    }
+
+    fn encode<'py>(&mut self, py: Python<'py>, header: &Header, data: &[u8]) -> anyhow::Result<Vec<Bound<'py, PyBytes>>> {
+        Ok(self
+            .encoder
+            .encode(header.into(), data)?
+            .iter()
+            .map(|packet| PyBytes::new(py, packet))
+            .collect())
+    }
+}
+
+#[pyclass(unsendable)]
+pub struct FecDecoder {
+    decoder: fec::FecDecoder,
+}
+
+#[pymethods]
+impl FecDecoder {
+    #[new]
+    fn new() -> Self {
+        FecDecoder { decoder: fec::FecDecoder::new() }
+    }
+
+    /// Returns the header and data once enough fragments of a transfer arrived.
+    fn push<'py>(&mut self, py: Python<'py>, packet: &[u8]) -> anyhow::Result<Option<(Header, Bound<'py, PyBytes>)>> {
+        Ok(self
+            .decoder
+            .push(packet)?
+            .map(|(header, data)| (header.into(), PyBytes::new(py, &data))))
+    }
+}
+
+#[pymodule]
+fn _rora(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<LoRa>()?;
+    m.add_class::<Packet>()?;
+    m.add_class::<Header>()?;
+    m.add_class::<SecureChannel>()?;
+    m.add_class::<Compressor>()?;
+    m.add_class::<FecEncoder>()?;
+    m.add_class::<FecDecoder>()?;
+    m.add("BROADCAST", datagram::BROADCAST)?;
+    m.add("MAX_PAYLOAD", datagram::MAX_PAYLOAD)?;
+    m.add("MAX_PLAINTEXT", secure::MAX_PLAINTEXT)?;
+    Ok(())
+}
@@ -0,0 +1,469 @@
+use anyhow::{anyhow, Result};
+use serde_json::{json, Value};
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::datagram::{Header, BROADCAST, HEADER_LEN};
+use crate::gps::{Gps, Position};
+use crate::rfm96w::{LoRa, TxPower};
+
+const MAGIC: &[u8; 2] = b"RT";
+/// Beacon body without a position: magic, sequence, setting index, sequence in the block,
+/// SF, bandwidth, CR, power and flags.
+const BEACON_LEN: usize = 17;
+const POSITION_LEN: usize = 10;
+const FLAG_POSITION: u8 = 0x01;
+const POLL_MS: i32 = 10;
+
+/// One entry of the test matrix. Both sides must be given the same matrix.
+#[derive(Clone, Copy)]
+pub struct Setting {
+    pub spreading_factor: u8,
+    pub bandwidth: i64,
+    /// Denominator of the coding rate, `5` for `4/5`.
+    pub coding_rate: u8,
+    /// Transmit power in dBm, only applied by the transmitter.
+    pub power: i32,
+}
+
+impl Setting {
+    /// Every combination of the given values.
+    pub fn matrix(spreading_factors: &[u8], bandwidths: &[i64], coding_rates: &[u8], powers: &[i32]) -> Vec<Setting> {
+        let mut matrix = Vec::new();
+        for &spreading_factor in spreading_factors {
+            for &bandwidth in bandwidths {
+                for &coding_rate in coding_rates {
+                    for &power in powers {
+                        matrix.push(Setting { spreading_factor, bandwidth, coding_rate, power });
+                    }
+                }
+            }
+        }
+        matrix
+    }
+
+    fn apply(&self, radio: &mut LoRa, transmitter: bool) -> Result<()> {
+        radio.set_spreading_factor(self.spreading_factor)?;
+        radio.set_signal_bandwidth(self.bandwidth)?;
+        radio.set_coding_rate_4(self.coding_rate)?;
+        if transmitter {
+            radio.set_tx_power(TxPower::pa_boost(self.power))?;
+        }
+        Ok(())
+    }
+
+    fn describe(&self) -> String {
+        format!(
+            "SF{} BW {} CR 4/{} {} dBm",
+            self.spreading_factor, self.bandwidth, self.coding_rate, self.power
+        )
+    }
+}
+
+pub struct RangeTestConfig {
+    pub address: u8,
+    pub matrix: Vec<Setting>,
+    /// Beacons per setting.
+    pub count: u16,
+    /// Time between the starts of two beacons.
+    pub interval: Duration,
+    /// Beacons are padded to this many bytes.
+    pub size: usize,
+    /// Pause between two settings, so the receiver can follow.
+    pub guard: Duration,
+    /// Position sent in beacons by the transmitter, used for distances by the receiver.
+    pub gps: Option<Gps>,
+    /// Receiver output, one line per packet.
+    pub csv: Option<PathBuf>,
+    pub json: Option<PathBuf>,
+}
+
+struct Beacon {
+    sequence: u32,
+    index: u8,
+    block_sequence: u16,
+    position: Option<Position>,
+}
+
+impl Beacon {
+    fn encode(&self, address: u8, setting: &Setting, size: usize) -> Vec<u8> {
+        let header = Header { to: BROADCAST, from: address, id: self.sequence as u8, flags: 0 };
+        let mut packet = header.to_bytes().to_vec();
+        packet.extend_from_slice(MAGIC);
+        packet.extend_from_slice(&self.sequence.to_be_bytes());
+        packet.push(self.index);
+        packet.extend_from_slice(&self.block_sequence.to_be_bytes());
+        packet.push(setting.spreading_factor);
+        packet.extend_from_slice(&(setting.bandwidth as u32).to_be_bytes());
+        packet.push(setting.coding_rate);
+        packet.push(setting.power as i8 as u8);
+        match self.position {
+            Some(p) => {
+                packet.push(FLAG_POSITION);
+                packet.extend_from_slice(&((p.latitude * 1e7) as i32).to_be_bytes());
+                packet.extend_from_slice(&((p.longitude * 1e7) as i32).to_be_bytes());
+                packet.extend_from_slice(&(p.altitude as i16).to_be_bytes());
+            }
+            None => packet.push(0),
+        }
+        packet.resize(packet.len().max(size.min(255)), 0);
+        packet
+    }
+
+    fn decode(packet: &[u8]) -> Option<Beacon> {
+        let body = packet.get(HEADER_LEN..)?;
+        if body.len() < BEACON_LEN || &body[..2] != MAGIC {
+            return None;
+        }
+        let position = if body[16] & FLAG_POSITION != 0 {
+            let p = body.get(BEACON_LEN..BEACON_LEN + POSITION_LEN)?;
+            Some(Position {
+                latitude: i32::from_be_bytes([p[0], p[1], p[2], p[3]]) as f64 / 1e7,
+                longitude: i32::from_be_bytes([p[4], p[5], p[6], p[7]]) as f64 / 1e7,
+                altitude: i16::from_be_bytes([p[8], p[9]]) as f64,
+            })
+        } else {
+            None
+        };
+        Some(Beacon {
+            sequence: u32::from_be_bytes([body[2], body[3], body[4], body[5]]),
+            index: body[6],
+            block_sequence: u16::from_be_bytes([body[7], body[8]]),
+            position,
+        })
+    }
+}
+
+/// Sends `count` sequence numbered beacons for every setting of the matrix, one every
+/// `interval`, with the current GPS position if there is one.
+pub fn transmit(radio: &mut LoRa, config: &RangeTestConfig) -> Result<()> {
+    if config.matrix.is_empty() || config.matrix.len() > u8::MAX as usize {
+        return Err(anyhow!("The matrix must have 1 to 255 settings."));
+    }
+    if config.count == 0 {
+        return Err(anyhow!("The count must be at least 1."));
+    }
+    let mut sequence = 0;
+    for (index, setting) in config.matrix.iter().enumerate() {
+        setting.apply(radio, true)?;
+        println!("Setting {}: {}", index, setting.describe());
+        spin_sleep::sleep(config.guard);
+        let start = Instant::now();
+        for block_sequence in 0..config.count {
+            let slot = start + config.interval * block_sequence as u32;
+            spin_sleep::sleep(slot.saturating_duration_since(Instant::now()));
+            let beacon = Beacon {
+                sequence,
+                index: index as u8,
+                block_sequence,
+                position: config.gps.as_ref().and_then(Gps::position),
+            };
+            let packet = beacon.encode(config.address, setting, config.size);
+            let mut buffer = [0u8; 255];
+            buffer[..packet.len()].copy_from_slice(&packet);
+            radio.transmit_payload_busy(buffer, packet.len())?;
+            println!("TX {} ({}/{})", sequence, block_sequence + 1, config.count);
+            sequence += 1;
+        }
+    }
+    Ok(())
+}
+
+/// Received beacons of one setting.
+#[derive(Default)]
+struct Block {
+    received: HashSet<u16>,
+    crc_errors: u64,
+    rssi: Vec<f32>,
+    snr: Vec<f32>,
+    frequency_error: Vec<i64>,
+    last_block_sequence: Option<u16>,
+}
+
+/// Nearest rank percentile of sorted samples.
+fn percentile(sorted: &[f32], p: f32) -> Option<f32> {
+    let rank = ((p / 100.0 * sorted.len() as f32).ceil() as usize).clamp(1, sorted.len().max(1));
+    sorted.get(rank - 1).copied()
+}
+
+impl Block {
+    fn summary(&self, index: usize, setting: &Setting, count: u16) -> Value {
+        let mut rssi = self.rssi.clone();
+        rssi.sort_by(f32::total_cmp);
+        let mut snr = self.snr.clone();
+        snr.sort_by(f32::total_cmp);
+        let received = self.received.len();
+        let mean_frequency_error = if self.frequency_error.is_empty() {
+            None
+        } else {
+            Some(self.frequency_error.iter().sum::<i64>() / self.frequency_error.len() as i64)
+        };
+        json!({
+            "summary": true,
+            "setting": index,
+            "sf": setting.spreading_factor,
+            "bw": setting.bandwidth,
+            "cr": setting.coding_rate,
+            "power": setting.power,
+            "sent": count,
+            "received": received,
+            "crc_errors": self.crc_errors,
+            "per": 1.0 - received as f64 / count as f64,
+            "rssi_p10": percentile(&rssi, 10.0),
+            "rssi_p50": percentile(&rssi, 50.0),
+            "rssi_p90": percentile(&rssi, 90.0),
+            "snr_p50": percentile(&snr, 50.0),
+            "frequency_error_mean": mean_frequency_error,
+        })
+    }
+}
+
+const CSV_HEADER: &str =
+    "timestamp,setting,sf,bw,cr,power,sequence,crc_ok,rssi,snr,frequency_error,lost,latitude,longitude,altitude,distance";
+
+/// Per packet log as CSV and JSON lines.
+struct Log {
+    csv: Option<File>,
+    json: Option<File>,
+}
+
+impl Log {
+    fn record(&mut self, row: &Value) -> Result<()> {
+        if let Some(csv) = &mut self.csv {
+            let fields: Vec<String> = CSV_HEADER
+                .split(',')
+                .map(|key| match &row[key] {
+                    Value::Null => String::new(),
+                    Value::String(s) => s.clone(),
+                    other => other.to_string(),
+                })
+                .collect();
+            writeln!(csv, "{}", fields.join(","))?;
+        }
+        if let Some(json) = &mut self.json {
+            writeln!(json, "{}", row)?;
+        }
+        Ok(())
+    }
+
+    fn summary(&mut self, summary: &Value) -> Result<()> {
+        if let Some(json) = &mut self.json {
+            writeln!(json, "{}", summary)?;
+        }
+        Ok(())
+    }
+}
+
+/// Follows the transmitter through the matrix and logs every beacon. The receiver moves to
+/// the next setting after the last beacon of a block is due, or after a whole block without
+/// any beacon. It waits on the first setting until the first beacon arrives.
+pub fn receive(radio: &mut LoRa, config: &RangeTestConfig) -> Result<()> {
+    if config.matrix.is_empty() {
+        return Err(anyhow!("The matrix must have at least one setting."));
+    }
+    if config.count == 0 {
+        return Err(anyhow!("The count must be at least 1."));
+    }
+    let mut log = Log {
+        csv: config.csv.as_ref().map(File::create).transpose()?,
+        json: config.json.as_ref().map(File::create).transpose()?,
+    };
+    if let Some(csv) = &mut log.csv {
+        writeln!(csv, "{}", CSV_HEADER)?;
+    }
+    let block_time = config.interval * config.count as u32 + config.guard;
+    let mut blocks: Vec<Block> = config.matrix.iter().map(|_| Block::default()).collect();
+    let mut index = 0;
+    let mut deadline: Option<Instant> = None;
+    config.matrix[index].apply(radio, false)?;
+    println!("Setting {}: {}", index, config.matrix[index].describe());
+
+    while index < config.matrix.len() {
+        if deadline.is_some_and(|d| Instant::now() >= d) {
+            finish(index, &blocks[index], config, &mut log)?;
+            index += 1;
+            if index == config.matrix.len() {
+                break;
+            }
+            config.matrix[index].apply(radio, false)?;
+            println!("Setting {}: {}", index, config.matrix[index].describe());
+            deadline = Some(Instant::now() + block_time);
+        }
+
+        let Ok(size) = radio.poll_irq(Some(POLL_MS)) else {
+            continue;
+        };
+        let crc_ok = !radio.crc_error();
+        let buffer = radio.read_packet()?;
+        let rssi = radio.get_packet_rssi()?;
+        let snr = radio.get_packet_snr()?;
+        let frequency_error = radio.get_frequency_error()?;
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u64;
+
+        let beacon = if crc_ok { Beacon::decode(&buffer[..size]) } else { None };
+        let Some(beacon) = beacon else {
+            if !crc_ok {
+                blocks[index].crc_errors += 1;
+                let setting = &config.matrix[index];
+                log.record(&json!({
+                    "timestamp": timestamp, "setting": index, "sf": setting.spreading_factor,
+                    "bw": setting.bandwidth, "cr": setting.coding_rate, "power": setting.power,
+                    "crc_ok": false, "rssi": rssi, "snr": snr, "frequency_error": frequency_error,
+                }))?;
+            }
+            continue;
+        };
+
+        // settings differing only in power sound the same, the beacon says which block it is
+        let beacon_index = beacon.index as usize;
+        if beacon_index < index || beacon_index >= config.matrix.len() {
+            continue;
+        }
+        while index < beacon_index {
+            finish(index, &blocks[index], config, &mut log)?;
+            index += 1;
+        }
+
+        let setting = &config.matrix[index];
+        let block = &mut blocks[index];
+        let lost = match block.last_block_sequence {
+            Some(last) => beacon.block_sequence.saturating_sub(last + 1),
+            None => beacon.block_sequence,
+        };
+        block.last_block_sequence = Some(beacon.block_sequence);
+        block.received.insert(beacon.block_sequence);
+        block.rssi.push(rssi);
+        block.snr.push(snr);
+        block.frequency_error.push(frequency_error);
+
+        let here = config.gps.as_ref().and_then(Gps::position);
+        let distance = beacon.position.zip(here).map(|(there, here)| here.distance(&there).round());
+        log.record(&json!({
+            "timestamp": timestamp, "setting": index, "sf": setting.spreading_factor,
+            "bw": setting.bandwidth, "cr": setting.coding_rate, "power": setting.power,
+            "sequence": beacon.sequence, "crc_ok": true, "rssi": rssi, "snr": snr,
+            "frequency_error": frequency_error, "lost": lost,
+            "latitude": beacon.position.map(|p| p.latitude),
+            "longitude": beacon.position.map(|p| p.longitude),
+            "altitude": beacon.position.map(|p| p.altitude),
+            "distance": distance,
+        }))?;
+        println!(
+            "RX {} ({}/{}) rssi {:.0} dBm snr {:.2} dB{}",
+            beacon.sequence,
+            beacon.block_sequence + 1,
+            config.count,
+            rssi,
+            snr,
+            distance.map_or(String::new(), |d| format!(" {} m", d))
+        );
+
+        // the next block starts a guard time after the last beacon of this one
+        let remaining = config.count.saturating_sub(beacon.block_sequence + 1) as u32;
+        deadline = Some(Instant::now() + config.interval * remaining + config.interval / 2);
+    }
+
+    println!("\n{:<4} {:<30} {:>8} {:>6} {:>8} {:>8} {:>8} {:>8}", "", "setting", "received", "PER", "rssi p10", "p50", "p90", "snr p50");
+    for (index, (block, setting)) in blocks.iter().zip(&config.matrix).enumerate() {
+        let summary = block.summary(index, setting, config.count);
+        let number = |key: &str| summary[key].as_f64().map_or("-".to_string(), |v| format!("{:.1}", v));
+        println!(
+            "{:<4} {:<30} {:>8} {:>5.1}% {:>8} {:>8} {:>8} {:>8}",
+            index,
+            setting.describe(),
+            format!("{}/{}", block.received.len(), config.count),
+            summary["per"].as_f64().unwrap_or(1.0) * 100.0,
+            number("rssi_p10"),
+            number("rssi_p50"),
+            number("rssi_p90"),
+            number("snr_p50"),
+        );
+    }
+    Ok(())
+}
+
+fn finish(index: usize, block: &Block, config: &RangeTestConfig, log: &mut Log) -> Result<()> {
+    let summary = block.summary(index, &config.matrix[index], config.count);
+    println!(
+        "Setting {} done: {}/{} received, {} CRC errors.",
+        index,
+        block.received.len(),
+        config.count,
+        block.crc_errors
+    );
+    log.summary(&summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SETTING: Setting = Setting { spreading_factor: 9, bandwidth: 125_000, coding_rate: 5, power: -4 };
+
+    #[test]
+    fn beacon_without_position() {
+        let beacon = Beacon { sequence: 0x01020304, index: 7, block_sequence: 300, position: None };
+        let packet = beacon.encode(3, &SETTING, 0);
+        assert_eq!(packet.len(), HEADER_LEN + BEACON_LEN);
+        assert_eq!(packet[..HEADER_LEN], [BROADCAST, 3, 0x04, 0]);
+        // SF, bandwidth, CR, power and flags follow the sequence numbers
+        assert_eq!(packet[HEADER_LEN + 9..], [9, 0, 1, 0xe8, 0x48, 5, 0xfc, 0]);
+
+        let decoded = Beacon::decode(&packet).unwrap();
+        assert_eq!((decoded.sequence, decoded.index, decoded.block_sequence), (0x01020304, 7, 300));
+        assert!(decoded.position.is_none());
+    }
+
+    #[test]
+    fn beacon_with_position() {
+        let position = Position { latitude: -33.8567844, longitude: 151.2152967, altitude: 42.9 };
+        let beacon = Beacon { sequence: 1, index: 0, block_sequence: 1, position: Some(position) };
+        let packet = beacon.encode(3, &SETTING, 0);
+        assert_eq!(packet.len(), HEADER_LEN + BEACON_LEN + POSITION_LEN);
+
+        let decoded = Beacon::decode(&packet).unwrap().position.unwrap();
+        assert!((decoded.latitude - position.latitude).abs() < 1e-7);
+        assert!((decoded.longitude - position.longitude).abs() < 1e-7);
+        // whole metres
+        assert_eq!(decoded.altitude, 42.0);
+
+        // the flag without the position that should follow it
+        assert!(Beacon::decode(&packet[..packet.len() - 1]).is_none());
+    }
+
+    #[test]
+    fn beacons_are_padded() {
+        let beacon = Beacon { sequence: 1, index: 0, block_sequence: 1, position: None };
+        assert_eq!(beacon.encode(3, &SETTING, 64).len(), 64);
+        assert_eq!(beacon.encode(3, &SETTING, 1000).len(), 255);
+        // padding is ignored when decoding
+        assert_eq!(Beacon::decode(&beacon.encode(3, &SETTING, 255)).unwrap().sequence, 1);
+    }
+
+    #[test]
+    fn other_packets_are_not_beacons() {
+        let packet = Beacon { sequence: 1, index: 0, block_sequence: 1, position: None }.encode(3, &SETTING, 0);
+        assert!(Beacon::decode(&packet[..packet.len() - 1]).is_none());
+        let mut other = packet.clone();
+        other[HEADER_LEN] = b'X';
+        assert!(Beacon::decode(&other).is_none());
+        assert!(Beacon::decode(&packet[..HEADER_LEN]).is_none());
+        assert!(Beacon::decode(&[]).is_none());
+    }
+
+    #[test]
+    fn nearest_rank_percentile() {
+        let samples: Vec<f32> = (1..=10).map(|v| v as f32).collect();
+        assert_eq!(percentile(&samples, 50.0), Some(5.0));
+        assert_eq!(percentile(&samples, 90.0), Some(9.0));
+        assert_eq!(percentile(&samples, 91.0), Some(10.0));
+        assert_eq!(percentile(&samples, 100.0), Some(10.0));
+        // the lowest rank is the first sample
+        assert_eq!(percentile(&samples, 0.0), Some(1.0));
+        assert_eq!(percentile(&[-97.5], 10.0), Some(-97.5));
+        assert_eq!(percentile(&[], 50.0), None);
+    }
+}
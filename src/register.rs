@@ -1,7 +1,16 @@
 #![allow(dead_code)]
 
+//! The SX1276/77/78/79 register map. `Register` is the LoRa page, `FskRegister` the FSK/OOK
+//! page of addresses 0x01 to 0x3f, which is selected by clearing `LongRangeMode` (or by
+//! setting `AccessSharedReg` while in LoRa mode). Addresses from 0x40 up are the same page
+//! for both modems and are listed in `Register`, except `RegPllHop` and `RegBitRateFrac`,
+//! which only the FSK modem uses and are in `FskRegister`. The modules below give the fields
+//! of the LoRa page and the shared registers as masks and shifts, with enums for fields that
+//! take a set of values; the FSK page has no field definitions. Registers that setters read,
+//! modify and write back also have a `RegisterFields` struct.
 
-#[derive(Clone, Copy)]
+/// LoRa registers, including the undocumented ones from the errata and application notes.
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Register {
     RegFifo = 0x00,
     RegOpMode = 0x01,
@@ -16,51 +25,265 @@ pub enum Register {
     RegFifoTxBaseAddr = 0x0e,
     RegFifoRxBaseAddr = 0x0f,
     RegFifoRxCurrentAddr = 0x10,
+    RegIrqFlagsMask = 0x11,
     RegIrqFlags = 0x12,
     RegRxNbBytes = 0x13,
+    RegRxHeaderCntValueMsb = 0x14,
+    RegRxHeaderCntValueLsb = 0x15,
+    RegRxPacketCntValueMsb = 0x16,
+    RegRxPacketCntValueLsb = 0x17,
+    RegModemStat = 0x18,
     RegPktSnrValue = 0x19,
     RegPktRssiValue = 0x1a,
+    RegRssiValue = 0x1b,
+    RegHopChannel = 0x1c,
     RegModemConfig1 = 0x1d,
     RegModemConfig2 = 0x1e,
+    RegSymbTimeoutLsb = 0x1f,
     RegPreambleMsb = 0x20,
     RegPreambleLsb = 0x21,
     RegPayloadLength = 0x22,
+    RegMaxPayloadLength = 0x23,
+    RegHopPeriod = 0x24,
+    RegFifoRxByteAddr = 0x25,
     RegModemConfig3 = 0x26,
+    /// Data rate offset for the receiver, undocumented in the main datasheet.
+    RegPpmCorrection = 0x27,
     RegFreqErrorMsb = 0x28,
     RegFreqErrorMid = 0x29,
     RegFreqErrorLsb = 0x2a,
     RegRssiWideband = 0x2c,
+    /// IF frequency, see the errata note section 2.3 (spurious reception).
+    RegIfFreq2 = 0x2f,
+    RegIfFreq1 = 0x30,
     RegDetectionOptimize = 0x31,
-    RegHighBWOptimize1 = 0x36,
     RegInvertiq = 0x33,
+    /// Sensitivity optimisation for 500 kHz, see the errata note section 2.1.
+    RegHighBWOptimize1 = 0x36,
     RegDetectionThreshold = 0x37,
     RegSyncWord = 0x39,
     RegHighBWOptimize2 = 0x3a,
     RegInvertiq2 = 0x3b,
     RegDioMapping1 = 0x40,
+    RegDioMapping2 = 0x41,
     RegVersion = 0x42,
+    RegTcxo = 0x4b,
     RegPaDac = 0x4d,
+    /// Temperature measured during the last IQ calibration.
+    RegFormerTemp = 0x5b,
+    RegAgcRef = 0x61,
+    RegAgcThresh1 = 0x62,
+    RegAgcThresh2 = 0x63,
+    RegAgcThresh3 = 0x64,
+    RegPll = 0x70,
 }
+
+/// FSK/OOK registers. The first bytes and the shared registers from 0x40 have the same
+/// address as in `Register`.
 #[derive(Clone, Copy)]
-pub enum PaConfig {
-    PaBoost = 0x80,
-    PaOutputRfoPin = 0,
+pub enum FskRegister {
+    RegFifo = 0x00,
+    RegOpMode = 0x01,
+    RegBitrateMsb = 0x02,
+    RegBitrateLsb = 0x03,
+    RegFdevMsb = 0x04,
+    RegFdevLsb = 0x05,
+    RegFrfMsb = 0x06,
+    RegFrfMid = 0x07,
+    RegFrfLsb = 0x08,
+    RegPaConfig = 0x09,
+    RegPaRamp = 0x0a,
+    RegOcp = 0x0b,
+    RegLna = 0x0c,
+    RegRxConfig = 0x0d,
+    RegRssiConfig = 0x0e,
+    RegRssiCollision = 0x0f,
+    RegRssiThresh = 0x10,
+    RegRssiValue = 0x11,
+    RegRxBw = 0x12,
+    RegAfcBw = 0x13,
+    RegOokPeak = 0x14,
+    RegOokFix = 0x15,
+    RegOokAvg = 0x16,
+    RegAfcFei = 0x1a,
+    RegAfcMsb = 0x1b,
+    RegAfcLsb = 0x1c,
+    RegFeiMsb = 0x1d,
+    RegFeiLsb = 0x1e,
+    RegPreambleDetect = 0x1f,
+    RegRxTimeout1 = 0x20,
+    RegRxTimeout2 = 0x21,
+    RegRxTimeout3 = 0x22,
+    RegRxDelay = 0x23,
+    RegOsc = 0x24,
+    RegPreambleMsb = 0x25,
+    RegPreambleLsb = 0x26,
+    RegSyncConfig = 0x27,
+    RegSyncValue1 = 0x28,
+    RegSyncValue2 = 0x29,
+    RegSyncValue3 = 0x2a,
+    RegSyncValue4 = 0x2b,
+    RegSyncValue5 = 0x2c,
+    RegSyncValue6 = 0x2d,
+    RegSyncValue7 = 0x2e,
+    RegSyncValue8 = 0x2f,
+    RegPacketConfig1 = 0x30,
+    RegPacketConfig2 = 0x31,
+    RegPayloadLength = 0x32,
+    RegNodeAdrs = 0x33,
+    RegBroadcastAdrs = 0x34,
+    RegFifoThresh = 0x35,
+    RegSeqConfig1 = 0x36,
+    RegSeqConfig2 = 0x37,
+    RegTimerResol = 0x38,
+    RegTimer1Coef = 0x39,
+    RegTimer2Coef = 0x3a,
+    RegImageCal = 0x3b,
+    /// Die temperature, only updated in FSK mode.
+    RegTemp = 0x3c,
+    RegLowBat = 0x3d,
+    RegIrqFlags1 = 0x3e,
+    RegIrqFlags2 = 0x3f,
+    RegPllHop = 0x44,
+    RegBitRateFrac = 0x5d,
 }
 
+/// Bits of `RegIrqFlags` and `RegIrqFlagsMask`.
 #[derive(Clone, Copy)]
+#[allow(clippy::upper_case_acronyms, clippy::enum_variant_names)]
 pub enum IRQ {
+    IrqCadDetectedMask = 0x01,
+    IrqFhssChangeChannelMask = 0x02,
+    IrqCadDoneMask = 0x04,
     IrqTxDoneMask = 0x08,
+    IrqValidHeaderMask = 0x10,
     IrqPayloadCrcErrorMask = 0x20,
     IrqRxDoneMask = 0x40,
+    IrqRxTimeoutMask = 0x80,
 }
 
 impl Register {
+    pub const ALL: [Register; 60] = [
+        Register::RegFifo,
+        Register::RegOpMode,
+        Register::RegFrfMsb,
+        Register::RegFrfMid,
+        Register::RegFrfLsb,
+        Register::RegPaConfig,
+        Register::RegPaRamp,
+        Register::RegOcp,
+        Register::RegLna,
+        Register::RegFifoAddrPtr,
+        Register::RegFifoTxBaseAddr,
+        Register::RegFifoRxBaseAddr,
+        Register::RegFifoRxCurrentAddr,
+        Register::RegIrqFlagsMask,
+        Register::RegIrqFlags,
+        Register::RegRxNbBytes,
+        Register::RegRxHeaderCntValueMsb,
+        Register::RegRxHeaderCntValueLsb,
+        Register::RegRxPacketCntValueMsb,
+        Register::RegRxPacketCntValueLsb,
+        Register::RegModemStat,
+        Register::RegPktSnrValue,
+        Register::RegPktRssiValue,
+        Register::RegRssiValue,
+        Register::RegHopChannel,
+        Register::RegModemConfig1,
+        Register::RegModemConfig2,
+        Register::RegSymbTimeoutLsb,
+        Register::RegPreambleMsb,
+        Register::RegPreambleLsb,
+        Register::RegPayloadLength,
+        Register::RegMaxPayloadLength,
+        Register::RegHopPeriod,
+        Register::RegFifoRxByteAddr,
+        Register::RegModemConfig3,
+        Register::RegPpmCorrection,
+        Register::RegFreqErrorMsb,
+        Register::RegFreqErrorMid,
+        Register::RegFreqErrorLsb,
+        Register::RegRssiWideband,
+        Register::RegIfFreq2,
+        Register::RegIfFreq1,
+        Register::RegDetectionOptimize,
+        Register::RegInvertiq,
+        Register::RegHighBWOptimize1,
+        Register::RegDetectionThreshold,
+        Register::RegSyncWord,
+        Register::RegHighBWOptimize2,
+        Register::RegInvertiq2,
+        Register::RegDioMapping1,
+        Register::RegDioMapping2,
+        Register::RegVersion,
+        Register::RegTcxo,
+        Register::RegPaDac,
+        Register::RegFormerTemp,
+        Register::RegAgcRef,
+        Register::RegAgcThresh1,
+        Register::RegAgcThresh2,
+        Register::RegAgcThresh3,
+        Register::RegPll,
+    ];
+
     pub fn addr(self) -> u8 {
         self as u8
     }
+
+    pub fn from_addr(addr: u8) -> Option<Self> {
+        Self::ALL.into_iter().find(|r| r.addr() == addr)
+    }
 }
 
-impl PaConfig {
+/// Configuration registers, which only change when written. Everything else is status, FIFO
+/// or changed by the chip itself (`RegOpMode` after TX or RX single, `RegLna` under AGC) and
+/// must always be read from the chip.
+pub const CONFIGURATION: [Register; 37] = [
+    Register::RegFrfMsb,
+    Register::RegFrfMid,
+    Register::RegFrfLsb,
+    Register::RegPaConfig,
+    Register::RegPaRamp,
+    Register::RegOcp,
+    Register::RegFifoTxBaseAddr,
+    Register::RegFifoRxBaseAddr,
+    Register::RegIrqFlagsMask,
+    Register::RegModemConfig1,
+    Register::RegModemConfig2,
+    Register::RegSymbTimeoutLsb,
+    Register::RegPreambleMsb,
+    Register::RegPreambleLsb,
+    Register::RegPayloadLength,
+    Register::RegMaxPayloadLength,
+    Register::RegHopPeriod,
+    Register::RegModemConfig3,
+    Register::RegPpmCorrection,
+    Register::RegIfFreq2,
+    Register::RegIfFreq1,
+    Register::RegDetectionOptimize,
+    Register::RegInvertiq,
+    Register::RegHighBWOptimize1,
+    Register::RegDetectionThreshold,
+    Register::RegSyncWord,
+    Register::RegHighBWOptimize2,
+    Register::RegInvertiq2,
+    Register::RegDioMapping1,
+    Register::RegDioMapping2,
+    Register::RegTcxo,
+    Register::RegPaDac,
+    Register::RegAgcRef,
+    Register::RegAgcThresh1,
+    Register::RegAgcThresh2,
+    Register::RegAgcThresh3,
+    Register::RegPll,
+];
+
+/// Returns true if `addr` is one of the `CONFIGURATION` registers.
+pub fn is_configuration(addr: u8) -> bool {
+    CONFIGURATION.iter().any(|r| r.addr() == addr)
+}
+
+impl FskRegister {
     pub fn addr(self) -> u8 {
         self as u8
     }
@@ -72,15 +295,17 @@ impl IRQ {
     }
 }
 
+/// Modulation shaping in bits 6-5 of `RegPaRamp` (FSK only).
 #[derive(Clone, Copy)]
 pub enum FskDataModulationShaping {
-    None = 1,
-    GaussianBt1d0 = 2,
-    GaussianBt0d5 = 10,
-    GaussianBt0d3 = 11
+    None = 0b00,
+    GaussianBt1d0 = 0b01,
+    GaussianBt0d5 = 0b10,
+    GaussianBt0d3 = 0b11,
 }
 
-#[derive(Clone, Copy)]
+/// Rise and fall time of the PA ramp in bits 3-0 of `RegPaRamp`, used by both modems.
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum FskRampUpRamDown {
     #[allow(non_camel_case_types)]
     _3d4ms = 0b000,
@@ -99,4 +324,462 @@ pub enum FskRampUpRamDown {
     _15us = 0b1101,
     _12us = 0b1110,
     _10us = 0b1111
-}
\ No newline at end of file
+}
+
+impl FskRampUpRamDown {
+    const ALL: [FskRampUpRamDown; 16] = [
+        FskRampUpRamDown::_3d4ms,
+        FskRampUpRamDown::_2ms,
+        FskRampUpRamDown::_1ms,
+        FskRampUpRamDown::_500us,
+        FskRampUpRamDown::_250us,
+        FskRampUpRamDown::_125us,
+        FskRampUpRamDown::_100us,
+        FskRampUpRamDown::_62us,
+        FskRampUpRamDown::_50us,
+        FskRampUpRamDown::_40us,
+        FskRampUpRamDown::_31us,
+        FskRampUpRamDown::_25us,
+        FskRampUpRamDown::_20us,
+        FskRampUpRamDown::_15us,
+        FskRampUpRamDown::_12us,
+        FskRampUpRamDown::_10us,
+    ];
+
+    pub fn micros(self) -> u32 {
+        [3400, 2000, 1000, 500, 250, 125, 100, 62, 50, 40, 31, 25, 20, 15, 12, 10][self as usize]
+    }
+
+    pub fn from_bits(bits: u8) -> Self {
+        Self::ALL[(bits & pa_ramp::PA_RAMP_MASK) as usize]
+    }
+}
+
+/// Signal bandwidth in bits 7-4 of `RegModemConfig1`.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Bandwidth {
+    Bw7k8 = 0,
+    Bw10k4 = 1,
+    Bw15k6 = 2,
+    Bw20k8 = 3,
+    Bw31k25 = 4,
+    Bw41k7 = 5,
+    Bw62k5 = 6,
+    Bw125k = 7,
+    Bw250k = 8,
+    Bw500k = 9,
+}
+
+impl Bandwidth {
+    const ALL: [Bandwidth; 10] = [
+        Bandwidth::Bw7k8,
+        Bandwidth::Bw10k4,
+        Bandwidth::Bw15k6,
+        Bandwidth::Bw20k8,
+        Bandwidth::Bw31k25,
+        Bandwidth::Bw41k7,
+        Bandwidth::Bw62k5,
+        Bandwidth::Bw125k,
+        Bandwidth::Bw250k,
+        Bandwidth::Bw500k,
+    ];
+
+    pub fn hz(self) -> i64 {
+        match self {
+            Bandwidth::Bw7k8 => 7_800,
+            Bandwidth::Bw10k4 => 10_400,
+            Bandwidth::Bw15k6 => 15_600,
+            Bandwidth::Bw20k8 => 20_800,
+            Bandwidth::Bw31k25 => 31_250,
+            Bandwidth::Bw41k7 => 41_700,
+            Bandwidth::Bw62k5 => 62_500,
+            Bandwidth::Bw125k => 125_000,
+            Bandwidth::Bw250k => 250_000,
+            Bandwidth::Bw500k => 500_000,
+        }
+    }
+
+    pub fn from_hz(hz: i64) -> Option<Self> {
+        Self::ALL.into_iter().find(|bw| bw.hz() == hz)
+    }
+
+    /// Reads the field value, `None` for the reserved values.
+    pub fn from_bits(bits: u8) -> Option<Self> {
+        Self::ALL.get(bits as usize).copied()
+    }
+}
+
+/// A register as a struct of named fields, read and written with `LoRa::read`, `LoRa::write`
+/// and `LoRa::modify`. `from_bits` and `bits` round trip every bit, reserved ones included, so
+/// a modification only touches the fields it changes.
+pub trait RegisterFields: Copy {
+    const REGISTER: Register;
+    fn from_bits(bits: u8) -> Self;
+    fn bits(&self) -> u8;
+}
+
+fn bit(on: bool, mask: u8) -> u8 {
+    if on { mask } else { 0 }
+}
+
+/// `RegModemConfig1`.
+#[derive(Clone, Copy, PartialEq)]
+pub struct ModemConfig1 {
+    /// The raw field, so a reserved value is written back as it was read.
+    bandwidth: u8,
+    /// Coding rate `4/(4 + coding_rate)`, 1 to 4.
+    pub coding_rate: u8,
+    pub implicit_header: bool,
+}
+
+impl ModemConfig1 {
+    /// The signal bandwidth, reserved values read as 500 kHz.
+    pub fn bandwidth(&self) -> Bandwidth {
+        Bandwidth::from_bits(self.bandwidth).unwrap_or(Bandwidth::Bw500k)
+    }
+
+    pub fn set_bandwidth(&mut self, bandwidth: Bandwidth) {
+        self.bandwidth = bandwidth as u8;
+    }
+}
+
+impl RegisterFields for ModemConfig1 {
+    const REGISTER: Register = Register::RegModemConfig1;
+
+    fn from_bits(bits: u8) -> Self {
+        ModemConfig1 {
+            bandwidth: bits >> modem_config_1::BW_SHIFT,
+            coding_rate: (bits & modem_config_1::CODING_RATE_MASK) >> modem_config_1::CODING_RATE_SHIFT,
+            implicit_header: bits & modem_config_1::IMPLICIT_HEADER_MODE_ON != 0,
+        }
+    }
+
+    fn bits(&self) -> u8 {
+        (self.bandwidth << modem_config_1::BW_SHIFT)
+            | ((self.coding_rate << modem_config_1::CODING_RATE_SHIFT) & modem_config_1::CODING_RATE_MASK)
+            | bit(self.implicit_header, modem_config_1::IMPLICIT_HEADER_MODE_ON)
+    }
+}
+
+/// `RegModemConfig2`.
+#[derive(Clone, Copy, PartialEq)]
+pub struct ModemConfig2 {
+    /// 6 to 12.
+    pub spreading_factor: u8,
+    pub tx_continuous: bool,
+    pub crc_on: bool,
+    /// Bits 9-8 of the RX timeout.
+    pub symb_timeout_msb: u8,
+}
+
+impl RegisterFields for ModemConfig2 {
+    const REGISTER: Register = Register::RegModemConfig2;
+
+    fn from_bits(bits: u8) -> Self {
+        ModemConfig2 {
+            spreading_factor: bits >> modem_config_2::SPREADING_FACTOR_SHIFT,
+            tx_continuous: bits & modem_config_2::TX_CONTINUOUS_MODE != 0,
+            crc_on: bits & modem_config_2::RX_PAYLOAD_CRC_ON != 0,
+            symb_timeout_msb: bits & modem_config_2::SYMB_TIMEOUT_MSB_MASK,
+        }
+    }
+
+    fn bits(&self) -> u8 {
+        ((self.spreading_factor << modem_config_2::SPREADING_FACTOR_SHIFT) & modem_config_2::SPREADING_FACTOR_MASK)
+            | bit(self.tx_continuous, modem_config_2::TX_CONTINUOUS_MODE)
+            | bit(self.crc_on, modem_config_2::RX_PAYLOAD_CRC_ON)
+            | (self.symb_timeout_msb & modem_config_2::SYMB_TIMEOUT_MSB_MASK)
+    }
+}
+
+/// `RegModemConfig3`.
+#[derive(Clone, Copy, PartialEq)]
+pub struct ModemConfig3 {
+    pub low_data_rate_optimize: bool,
+    pub agc_auto: bool,
+    reserved: u8,
+}
+
+impl RegisterFields for ModemConfig3 {
+    const REGISTER: Register = Register::RegModemConfig3;
+
+    fn from_bits(bits: u8) -> Self {
+        ModemConfig3 {
+            low_data_rate_optimize: bits & modem_config_3::LOW_DATA_RATE_OPTIMIZE != 0,
+            agc_auto: bits & modem_config_3::AGC_AUTO_ON != 0,
+            reserved: bits & !(modem_config_3::LOW_DATA_RATE_OPTIMIZE | modem_config_3::AGC_AUTO_ON),
+        }
+    }
+
+    fn bits(&self) -> u8 {
+        self.reserved
+            | bit(self.low_data_rate_optimize, modem_config_3::LOW_DATA_RATE_OPTIMIZE)
+            | bit(self.agc_auto, modem_config_3::AGC_AUTO_ON)
+    }
+}
+
+/// `RegLna`.
+#[derive(Clone, Copy, PartialEq)]
+pub struct Lna {
+    /// 1 (maximum) to 6 (-48 dB), only used while the AGC is off.
+    pub gain: u8,
+    pub boost_lf: u8,
+    pub boost_hf: u8,
+    reserved: u8,
+}
+
+impl RegisterFields for Lna {
+    const REGISTER: Register = Register::RegLna;
+
+    fn from_bits(bits: u8) -> Self {
+        Lna {
+            gain: bits >> lna::LNA_GAIN_SHIFT,
+            boost_lf: (bits & lna::LNA_BOOST_LF_MASK) >> lna::LNA_BOOST_LF_SHIFT,
+            boost_hf: bits & lna::LNA_BOOST_HF_MASK,
+            reserved: bits & !(lna::LNA_GAIN_MASK | lna::LNA_BOOST_LF_MASK | lna::LNA_BOOST_HF_MASK),
+        }
+    }
+
+    fn bits(&self) -> u8 {
+        ((self.gain << lna::LNA_GAIN_SHIFT) & lna::LNA_GAIN_MASK)
+            | ((self.boost_lf << lna::LNA_BOOST_LF_SHIFT) & lna::LNA_BOOST_LF_MASK)
+            | (self.boost_hf & lna::LNA_BOOST_HF_MASK)
+            | self.reserved
+    }
+}
+
+/// `RegDetectionOptimize`, whose upper bits are reserved.
+#[derive(Clone, Copy, PartialEq)]
+pub struct DetectionOptimize {
+    /// `detection::OPTIMIZE_SF6` or `detection::OPTIMIZE_SF7_TO_SF12`.
+    pub optimize: u8,
+    reserved: u8,
+}
+
+impl RegisterFields for DetectionOptimize {
+    const REGISTER: Register = Register::RegDetectionOptimize;
+
+    fn from_bits(bits: u8) -> Self {
+        DetectionOptimize {
+            optimize: bits & detection::OPTIMIZE_MASK,
+            reserved: bits & !detection::OPTIMIZE_MASK,
+        }
+    }
+
+    fn bits(&self) -> u8 {
+        self.reserved | (self.optimize & detection::OPTIMIZE_MASK)
+    }
+}
+
+/// Fields of `RegOpMode`.
+pub mod op_mode {
+    pub const LONG_RANGE_MODE: u8 = 0x80;
+    /// Gives access to the FSK page while in LoRa mode.
+    pub const ACCESS_SHARED_REG: u8 = 0x40;
+    /// Selects the low frequency port registers (below 525 MHz).
+    pub const LOW_FREQUENCY_MODE_ON: u8 = 0x08;
+    /// The `RadioMode`.
+    pub const MODE_MASK: u8 = 0x07;
+}
+
+/// Fields of `RegPaConfig`.
+pub mod pa_config {
+    /// Output on PA_BOOST instead of RFO.
+    pub const PA_SELECT: u8 = 0x80;
+    pub const MAX_POWER_MASK: u8 = 0x70;
+    pub const MAX_POWER_SHIFT: u8 = 4;
+    pub const OUTPUT_POWER_MASK: u8 = 0x0f;
+}
+
+/// Fields of `RegPaRamp`.
+pub mod pa_ramp {
+    pub const MODULATION_SHAPING_MASK: u8 = 0x60;
+    pub const MODULATION_SHAPING_SHIFT: u8 = 5;
+    pub const PA_RAMP_MASK: u8 = 0x0f;
+}
+
+/// Fields of `RegOcp`.
+pub mod ocp {
+    pub const OCP_ON: u8 = 0x20;
+    pub const OCP_TRIM_MASK: u8 = 0x1f;
+}
+
+/// Fields of `RegLna`.
+pub mod lna {
+    pub const LNA_GAIN_MASK: u8 = 0xe0;
+    pub const LNA_GAIN_SHIFT: u8 = 5;
+    pub const LNA_BOOST_LF_MASK: u8 = 0x18;
+    pub const LNA_BOOST_LF_SHIFT: u8 = 3;
+    pub const LNA_BOOST_HF_MASK: u8 = 0x03;
+    /// 150% LNA current on the high frequency port.
+    pub const LNA_BOOST_HF_ON: u8 = 0x03;
+}
+
+/// Fields of `RegModemStat`.
+pub mod modem_stat {
+    pub const RX_CODING_RATE_MASK: u8 = 0xe0;
+    pub const RX_CODING_RATE_SHIFT: u8 = 5;
+    pub const MODEM_CLEAR: u8 = 0x10;
+    pub const HEADER_INFO_VALID: u8 = 0x08;
+    pub const RX_ONGOING: u8 = 0x04;
+    pub const SIGNAL_SYNCHRONIZED: u8 = 0x02;
+    pub const SIGNAL_DETECTED: u8 = 0x01;
+}
+
+/// Fields of `RegHopChannel`.
+pub mod hop_channel {
+    pub const PLL_TIMEOUT: u8 = 0x80;
+    /// The received header announced a payload CRC.
+    pub const CRC_ON_PAYLOAD: u8 = 0x40;
+    pub const FHSS_PRESENT_CHANNEL_MASK: u8 = 0x3f;
+}
+
+/// Fields of `RegModemConfig1`.
+pub mod modem_config_1 {
+    pub const BW_MASK: u8 = 0xf0;
+    pub const BW_SHIFT: u8 = 4;
+    /// Coding rate `4/(4 + value)`.
+    pub const CODING_RATE_MASK: u8 = 0x0e;
+    pub const CODING_RATE_SHIFT: u8 = 1;
+    pub const IMPLICIT_HEADER_MODE_ON: u8 = 0x01;
+}
+
+/// Fields of `RegModemConfig2`.
+pub mod modem_config_2 {
+    pub const SPREADING_FACTOR_MASK: u8 = 0xf0;
+    pub const SPREADING_FACTOR_SHIFT: u8 = 4;
+    pub const TX_CONTINUOUS_MODE: u8 = 0x08;
+    pub const RX_PAYLOAD_CRC_ON: u8 = 0x04;
+    /// Bits 9-8 of the RX timeout in symbols, the rest is in `RegSymbTimeoutLsb`.
+    pub const SYMB_TIMEOUT_MSB_MASK: u8 = 0x03;
+}
+
+/// Fields of `RegModemConfig3`.
+pub mod modem_config_3 {
+    /// Mandated when the symbol time exceeds 16 ms.
+    pub const LOW_DATA_RATE_OPTIMIZE: u8 = 0x08;
+    /// LNA gain set by the AGC instead of `RegLna`.
+    pub const AGC_AUTO_ON: u8 = 0x04;
+}
+
+/// Values of `RegDetectionOptimize` and `RegDetectionThreshold`. The upper bits of
+/// `RegDetectionOptimize` are reserved and keep their reset value.
+pub mod detection {
+    pub const OPTIMIZE_MASK: u8 = 0x07;
+    pub const OPTIMIZE_SF6: u8 = 0x05;
+    pub const OPTIMIZE_SF7_TO_SF12: u8 = 0x03;
+    pub const THRESHOLD_SF6: u8 = 0x0c;
+    pub const THRESHOLD_SF7_TO_SF12: u8 = 0x0a;
+}
+
+/// Values of `RegInvertiq` and `RegInvertiq2`, see AN1200.23. Inverted IQ sets the RX bit
+/// and clears the TX bit, the reset value is the reverse.
+pub mod invert_iq {
+    pub const INVERT_IQ_RX: u8 = 0x40;
+    pub const INVERT_IQ_TX: u8 = 0x01;
+    pub const INVERTED: u8 = 0x66;
+    pub const NORMAL: u8 = 0x27;
+    pub const INVERTED_2: u8 = 0x19;
+    pub const NORMAL_2: u8 = 0x1d;
+}
+
+/// Values of `RegHighBWOptimize1` and `RegHighBWOptimize2` from the errata note: the first
+/// pair for 500 kHz below and above 525 MHz, the last for every other bandwidth.
+pub mod high_bw_optimize {
+    pub const OPTIMIZE_1_500K: u8 = 0x02;
+    pub const OPTIMIZE_2_500K_LF: u8 = 0x7f;
+    pub const OPTIMIZE_2_500K_HF: u8 = 0x64;
+    pub const OPTIMIZE_1_DEFAULT: u8 = 0x03;
+    pub const OPTIMIZE_2_DEFAULT: u8 = 0x65;
+}
+
+/// Fields of `RegDioMapping1`, two bits per pin, with the LoRa mappings of DIO0.
+pub mod dio_mapping_1 {
+    pub const DIO0_MASK: u8 = 0xc0;
+    pub const DIO1_MASK: u8 = 0x30;
+    pub const DIO2_MASK: u8 = 0x0c;
+    pub const DIO3_MASK: u8 = 0x03;
+    pub const DIO0_RX_DONE: u8 = 0x00;
+    pub const DIO0_TX_DONE: u8 = 0x40;
+    pub const DIO0_CAD_DONE: u8 = 0x80;
+}
+
+/// Fields of `RegDioMapping2`.
+pub mod dio_mapping_2 {
+    pub const DIO4_MASK: u8 = 0xc0;
+    pub const DIO5_MASK: u8 = 0x30;
+    /// Interrupt on preamble detect instead of RSSI (FSK only).
+    pub const MAP_PREAMBLE_DETECT: u8 = 0x01;
+}
+
+/// Fields of `RegTcxo`.
+pub mod tcxo {
+    /// External clipped sine on XTA instead of a crystal.
+    pub const TCXO_INPUT_ON: u8 = 0x10;
+}
+
+/// Values of `RegPaDac`, the reserved upper bits keep their reset value.
+pub mod pa_dac {
+    pub const PA_DAC_MASK: u8 = 0x07;
+    pub const DEFAULT: u8 = 0x84;
+    /// +20 dBm on PA_BOOST, see section 5.4.3.
+    pub const BOOST_20DBM: u8 = 0x87;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_round_trip<T: RegisterFields>() {
+        for bits in 0..=u8::MAX {
+            assert_eq!(T::from_bits(bits).bits(), bits, "{:?} {:#04x}", T::REGISTER, bits);
+        }
+    }
+
+    #[test]
+    fn fields_round_trip_every_value() {
+        assert_round_trip::<ModemConfig1>();
+        assert_round_trip::<ModemConfig2>();
+        assert_round_trip::<ModemConfig3>();
+        assert_round_trip::<Lna>();
+        assert_round_trip::<DetectionOptimize>();
+    }
+
+    #[test]
+    fn reserved_bandwidth_is_kept() {
+        for code in 10..=15u8 {
+            let mut r = ModemConfig1::from_bits(code << modem_config_1::BW_SHIFT);
+            assert!(r.bandwidth() == Bandwidth::Bw500k);
+            // changing another field writes the reserved value back untouched
+            r.coding_rate = 1;
+            assert_eq!(r.bits(), (code << modem_config_1::BW_SHIFT) | 0x02);
+            r.set_bandwidth(Bandwidth::Bw125k);
+            assert_eq!(r.bits(), 0x72);
+        }
+        for bandwidth in Bandwidth::ALL {
+            let r = ModemConfig1::from_bits((bandwidth as u8) << modem_config_1::BW_SHIFT);
+            assert!(r.bandwidth() == bandwidth);
+        }
+    }
+
+    #[test]
+    fn fields_are_decoded() {
+        // reset values from the datasheet
+        let r = ModemConfig1::from_bits(0x72);
+        assert!(r.bandwidth() == Bandwidth::Bw125k);
+        assert_eq!((r.coding_rate, r.implicit_header), (1, false));
+        let r = ModemConfig2::from_bits(0x70);
+        assert_eq!((r.spreading_factor, r.tx_continuous, r.crc_on, r.symb_timeout_msb), (7, false, false, 0));
+        let r = ModemConfig2::from_bits(0xc7);
+        assert_eq!((r.spreading_factor, r.tx_continuous, r.crc_on, r.symb_timeout_msb), (12, false, true, 3));
+        let r = ModemConfig3::from_bits(0x0c);
+        assert_eq!((r.low_data_rate_optimize, r.agc_auto), (true, true));
+        let r = Lna::from_bits(0x20);
+        assert_eq!((r.gain, r.boost_lf, r.boost_hf), (1, 0, 0));
+        let r = Lna::from_bits(0xdb);
+        assert_eq!((r.gain, r.boost_lf, r.boost_hf), (6, 3, lna::LNA_BOOST_HF_ON));
+        let r = DetectionOptimize::from_bits(0xc3);
+        assert_eq!(r.optimize, detection::OPTIMIZE_SF7_TO_SF12);
+        assert_eq!(DetectionOptimize { optimize: detection::OPTIMIZE_SF6, ..r }.bits(), 0xc5);
+    }
+}
@@ -1,15 +1,20 @@
 #![allow(dead_code)]
 
-use register::{PaConfig, Register, IRQ};
+use register::{
+    detection, dio_mapping_1, high_bw_optimize, hop_channel, invert_iq, lna, ocp, op_mode, pa_config, pa_dac, pa_ramp, Bandwidth,
+    DetectionOptimize, FskRampUpRamDown, Lna, ModemConfig1, ModemConfig2, ModemConfig3, Register, RegisterFields,
+    IRQ,
+};
 use rppal::spi::Spi;
 use rppal::gpio::OutputPin;
-use spin_sleep;
 use anyhow::{Result,anyhow};
-use std::time::Duration;
-use bit_field::BitField;
+use std::time::{Duration, Instant};
 
 
+use crate::capture::{Capture, Crc, Direction, LinkInfo};
+use crate::power::{self, Estimate, Usage};
 use crate::register;
+use crate::snapshot::Snapshot;
 
 
 
@@ -18,21 +23,54 @@ use crate::register;
 // const LORA_CS_PIN: u8 = 7;
 // const LORA_RESET_PIN: u8 = 25;
 const FREQUENCY: i64 = 433;
+/// Crystal oscillator frequency in hertz.
+pub(crate) const FXOSC: u64 = 32_000_000;
 const VERSION_CHECK: u8 = 0x12;
 const TX_CHUNK_SIZE: usize = 255;
+/// Over-current protection limits in milliamps, `OcpTrim` covers 45 to 240 mA.
+const OCP_MIN_MA: u8 = 45;
+const OCP_MAX_MA: u8 = 240;
+const OCP_PA_BOOST_MIN_MA: u8 = 100;
+/// `RxSingle` timeouts in symbols that `RegSymbTimeout` can hold, see `receive_single`.
+pub const SYMBOL_TIMEOUT: std::ops::RangeInclusive<u16> = 4..=1023;
+/// Margin on the expected length of a receive window before it counts as stuck.
+const RX_SINGLE_MARGIN: Duration = Duration::from_millis(50);
+
+/// Symbol time in microseconds.
+pub(crate) fn symbol_us(spreading_factor: u8, bandwidth: i64) -> u64 {
+    (1_000_000u64 << spreading_factor) / bandwidth as u64
+}
+
+/// Payload symbols of a packet of 255 bytes with an explicit header and CRC (AN1200.13).
+pub(crate) fn max_payload_symbols(spreading_factor: u8, bandwidth: i64, denominator: u8) -> u64 {
+    let sf = spreading_factor as i64;
+    let de = if symbol_us(spreading_factor, bandwidth) > 16_000 { 2 } else { 0 };
+    let bits = 8 * 255 - 4 * sf + 28 + 16;
+    8 + (bits as u64).div_ceil(4 * (sf - de) as u64) * denominator as u64
+}
+
+/// `OcpTrim` for a limit of at least `ma`, 45 to 240 mA (datasheet 5.4.4).
+fn ocp_trim(ma: u8) -> u8 {
+    match ma {
+        0..=120 => (ma.max(OCP_MIN_MA) - 45).div_ceil(5),
+        121..=OCP_MAX_MA => (ma as u16 + 30).div_ceil(10) as u8,
+        _ => ocp::OCP_TRIM_MASK,
+    }
+}
 
 
 
 /// Modes of the radio and their corresponding register values.
 #[derive(Clone, Copy)]
 pub enum RadioMode {
-    LongRangeMode = 0x80,
+    LongRangeMode = op_mode::LONG_RANGE_MODE as isize,
     Sleep = 0x00,
     Stdby = 0x01,
     FsTx = 0x02,
     Tx = 0x03,
     RxContinuous = 0x05,
     RxSingle = 0x06,
+    Cad = 0x07,
 }
 
 impl RadioMode {
@@ -42,15 +80,131 @@ impl RadioMode {
     }
 }
 
+/// LoRa header modes. An implicit header leaves the length, coding rate and CRC presence out
+/// of every packet, so both ends must agree on them beforehand.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HeaderMode {
+    Explicit,
+    /// Every packet carries exactly this many bytes.
+    Implicit(u8),
+}
+
+/// The two transmitter outputs, only one of which is wired to the antenna on a given module.
+/// The RFM96W uses PA_BOOST.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PaOutput {
+    /// -3 to +15 dBm.
+    Rfo,
+    /// +2 to +17 dBm, +18 to +20 dBm in high power mode.
+    PaBoost,
+}
+
+/// Receiver gain, set by the AGC or fixed from G1 (maximum) to G6 (-48 dB). A fixed lower gain
+/// keeps a receiver next to a strong transmitter out of saturation.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LnaGain {
+    Agc,
+    G1 = 1,
+    G2 = 2,
+    G3 = 3,
+    G4 = 4,
+    G5 = 5,
+    G6 = 6,
+}
+
+impl LnaGain {
+    const FIXED: [LnaGain; 6] = [LnaGain::G1, LnaGain::G2, LnaGain::G3, LnaGain::G4, LnaGain::G5, LnaGain::G6];
+}
+
+/// Transmitter output, power and PA ramp time, see `LoRa::set_tx_power`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TxPower {
+    pub output: PaOutput,
+    pub dbm: i32,
+    pub ramp: FskRampUpRamDown,
+}
+
+impl TxPower {
+    /// PA_BOOST at `dbm` with the reset ramp time of 40 µs.
+    pub fn pa_boost(dbm: i32) -> Self {
+        TxPower { output: PaOutput::PaBoost, dbm, ramp: FskRampUpRamDown::_40us }
+    }
+
+    /// RFO at `dbm` with the reset ramp time of 40 µs.
+    pub fn rfo(dbm: i32) -> Self {
+        TxPower { output: PaOutput::Rfo, dbm, ramp: FskRampUpRamDown::_40us }
+    }
+
+    /// `RegPaConfig` and `RegPaDac` for the power (SX1276 datasheet 5.4.2 and 5.4.3).
+    pub(crate) fn registers(&self) -> Result<(u8, u8)> {
+        let out_of_range = || anyhow!("{} dBm is out of range for {:?}.", self.dbm, self.output);
+        match self.output {
+            // Pout = 10.8 + 0.6 * MaxPower - (15 - OutputPower), MaxPower 7 and 2 give whole dBm
+            PaOutput::Rfo => match self.dbm {
+                0..=15 => Ok(((7 << pa_config::MAX_POWER_SHIFT) | self.dbm as u8, pa_dac::DEFAULT)),
+                -3..=-1 => Ok(((2 << pa_config::MAX_POWER_SHIFT) | (self.dbm + 3) as u8, pa_dac::DEFAULT)),
+                _ => Err(out_of_range()),
+            },
+            // Pout = 17 - (15 - OutputPower), 3 dB more with the high power DAC
+            PaOutput::PaBoost => {
+                let pa = pa_config::PA_SELECT | pa_config::MAX_POWER_MASK;
+                match self.dbm {
+                    2..=17 => Ok((pa | (self.dbm - 2) as u8, pa_dac::DEFAULT)),
+                    18..=20 => Ok((pa | (self.dbm - 5) as u8, pa_dac::BOOST_20DBM)),
+                    _ => Err(out_of_range()),
+                }
+            }
+        }
+    }
+
+    /// Over-current limit in milliamps: a fifth above the transmit current, and never below
+    /// the 100 mA reset value on PA_BOOST, whose current the estimates in `power` may
+    /// understate at low power.
+    pub(crate) fn ocp_ma(&self) -> u8 {
+        let current = power::tx_current_ma(self.output == PaOutput::PaBoost, self.dbm) * 1.2;
+        let floor = if self.output == PaOutput::PaBoost { OCP_PA_BOOST_MIN_MA } else { OCP_MIN_MA };
+        current.ceil().clamp(floor as f64, OCP_MAX_MA as f64) as u8
+    }
+
+    /// Decodes the registers, rounding RFO settings that fall between whole dBm.
+    fn from_registers(pa: u8, dac: u8, ramp: u8) -> Self {
+        let output_power = (pa & pa_config::OUTPUT_POWER_MASK) as f32;
+        let (output, dbm) = if pa & pa_config::PA_SELECT != 0 {
+            let high_power = dac & pa_dac::PA_DAC_MASK == pa_dac::BOOST_20DBM & pa_dac::PA_DAC_MASK;
+            (PaOutput::PaBoost, 17.0 - (15.0 - output_power) + if high_power { 3.0 } else { 0.0 })
+        } else {
+            let max_power = ((pa & pa_config::MAX_POWER_MASK) >> pa_config::MAX_POWER_SHIFT) as f32;
+            (PaOutput::Rfo, 10.8 + 0.6 * max_power - (15.0 - output_power))
+        };
+        TxPower { output, dbm: dbm.round() as i32, ramp: FskRampUpRamDown::from_bits(ramp) }
+    }
+}
+
 
 // Add a lifetime parameter `'a` and a generic type parameter `D` bounded by the `DelayMs<u8>` trait
 pub struct LoRa{
     spi: Spi,
     cs: OutputPin,
     reset: OutputPin,
-    frequency: i64,
-    explicit_header: bool,
+    frequency: u64,
+    header_mode: HeaderMode,
+    irq_flags: u8,
     mode: RadioMode, // Assuming RadioMode is defined elsewhere
+    capture: Option<Capture>,
+    /// Last value written to or read from each configuration register, see `enable_shadow`.
+    shadow: Option<[Option<u8>; 0x80]>,
+    /// Time spent in each mode before `mode_since`, when the current mode was entered.
+    usage: Usage,
+    mode_since: Instant,
+    idle_sleep: Option<Duration>,
+}
+
+/// A configuration register whose value on the chip differs from the shadow copy.
+#[derive(Clone, Copy, Debug)]
+pub struct Mismatch {
+    pub address: u8,
+    pub expected: u8,
+    pub actual: u8,
 }
 
 impl LoRa {
@@ -59,15 +213,18 @@ impl LoRa {
             spi,
             cs,
             reset,
-            frequency: FREQUENCY,
-            explicit_header: false,
+            frequency: FREQUENCY as u64 * 1_000_000,
+            header_mode: HeaderMode::Explicit,
+            irq_flags: 0,
             mode: RadioMode::Sleep,
+            capture: None,
+            shadow: None,
+            usage: Usage::default(),
+            mode_since: Instant::now(),
+            idle_sleep: None,
         };
 
-        lora.reset.set_low();
-        spin_sleep::sleep(Duration::from_millis(10));
-        lora.reset.set_high();
-        spin_sleep::sleep(Duration::from_millis(10));
+        lora.reset();
 
         let version = lora.read_register(Register::RegVersion.addr())?;
 
@@ -88,11 +245,10 @@ impl LoRa {
             lora.set_spreading_factor(7)?;
 
             lora.set_crc(true)?;
-            lora.set_tx_power(20, 1)?;
+            lora.set_tx_power(TxPower::pa_boost(20))?;
 
-            let lna = lora.read_register(Register::RegLna.addr())?;
-            lora.write_register(Register::RegLna.addr(), lna | 0x03)?;
-            lora.write_register(Register::RegModemConfig3.addr(), 0x04)?;
+            lora.set_lna_boost(true)?;
+            lora.set_lna_gain(LnaGain::Agc)?;
 
             lora.set_mode(RadioMode::Stdby)?;
             lora.cs.set_high();
@@ -102,7 +258,93 @@ impl LoRa {
         }
     }
 
+    /// Pulses the reset pin, which returns every register to its reset value and clears the
+    /// shadow copy.
+    pub fn reset(&mut self) {
+        self.reset.set_low();
+        spin_sleep::sleep(Duration::from_millis(10));
+        self.reset.set_high();
+        spin_sleep::sleep(Duration::from_millis(10));
+        if let Some(shadow) = &mut self.shadow {
+            *shadow = [None; 0x80];
+        }
+    }
+
+    /// Keeps a copy of the configuration registers (`register::CONFIGURATION`) so reading them
+    /// needs no SPI transfer, which turns read-modify-writes into plain writes. The copy
+    /// assumes the LoRa register page, which is the only one this driver uses.
+    pub fn enable_shadow(&mut self, enabled: bool) {
+        self.shadow = enabled.then_some([None; 0x80]);
+    }
+
+    /// Compares the shadow copy to the chip and returns every register that differs, e.g. after
+    /// a brownout or an unexpected reset put them back to their reset values.
+    pub fn verify(&mut self) -> Result<Vec<Mismatch>> {
+        let Some(shadow) = self.shadow else {
+            return Ok(Vec::new());
+        };
+        let mut mismatches = Vec::new();
+        for (address, expected) in shadow.iter().enumerate() {
+            let Some(expected) = *expected else { continue };
+            let actual = self.spi_read(address as u8)?;
+            if actual != expected {
+                mismatches.push(Mismatch { address: address as u8, expected, actual });
+            }
+        }
+        Ok(mismatches)
+    }
+
+    /// Reads every register from the chip, bypassing the shadow copy. The FIFO is skipped as
+    /// reading it moves the FIFO pointer.
+    pub fn dump_registers(&mut self) -> Result<Snapshot> {
+        let mut values = [None; 0x80];
+        for address in 0x01..0x80u8 {
+            values[address as usize] = Some(self.spi_read(address)?);
+        }
+        Ok(Snapshot { values })
+    }
+
+    /// Writes the configuration registers of a snapshot (`register::CONFIGURATION`) in sleep
+    /// mode and leaves the radio in standby. Status registers and the mode are not restored.
+    pub fn restore_registers(&mut self, snapshot: &Snapshot) -> Result<()> {
+        self.set_mode(RadioMode::Sleep)?;
+        for register in register::CONFIGURATION {
+            if let Some(value) = snapshot.values[register.addr() as usize] {
+                self.write_register(register.addr(), value)?;
+            }
+        }
+        if let Some(frequency) = snapshot.frequency() {
+            self.frequency = frequency;
+        }
+        self.header_mode = if self.read::<ModemConfig1>()?.implicit_header {
+            HeaderMode::Implicit(self.read_register(Register::RegPayloadLength.addr())?)
+        } else {
+            HeaderMode::Explicit
+        };
+        self.set_mode(RadioMode::Stdby)
+    }
+
     pub fn read_register(&mut self, reg: u8) -> Result<u8> {
+        let cached = register::is_configuration(reg)
+            .then(|| self.shadow.as_ref().and_then(|shadow| shadow[reg as usize]))
+            .flatten();
+        if let Some(value) = cached {
+            return Ok(value);
+        }
+        let value = self.spi_read(reg)?;
+        self.remember(reg, value);
+        Ok(value)
+    }
+
+    fn remember(&mut self, reg: u8, value: u8) {
+        if let Some(shadow) = &mut self.shadow {
+            if register::is_configuration(reg) {
+                shadow[reg as usize] = Some(value);
+            }
+        }
+    }
+
+    fn spi_read(&mut self, reg: u8) -> Result<u8> {
         self.cs.set_low();
         // Prepare the write buffer with the register address, ensuring the MSB is 0 for a read operation
         let write_buffer = [reg & 0x7f, 0];
@@ -121,116 +363,217 @@ impl LoRa {
         let buffer = [reg | 0x80, byte];
         self.spi.write(&buffer).map_err(anyhow::Error::msg)?;
         self.cs.set_high();
+        self.remember(reg, byte);
         Ok(())
     }
+
+    /// Reads a register as its fields.
+    pub fn read<R: RegisterFields>(&mut self) -> Result<R> {
+        Ok(R::from_bits(self.read_register(R::REGISTER.addr())?))
+    }
+
+    /// Writes all fields of a register.
+    pub fn write<R: RegisterFields>(&mut self, value: R) -> Result<()> {
+        self.write_register(R::REGISTER.addr(), value.bits())
+    }
+
+    /// Reads a register, lets `f` change some of its fields and writes it back, e.g.
+    /// `radio.modify(|r: &mut ModemConfig2| r.crc_on = true)`.
+    pub fn modify<R: RegisterFields>(&mut self, f: impl FnOnce(&mut R)) -> Result<()> {
+        let mut value = self.read::<R>()?;
+        f(&mut value);
+        self.write(value)
+    }
     
     /// Sets the state of the radio. Default mode after initiation is `Standby`.
     pub fn set_mode(&mut self, mode: RadioMode) -> Result<()> {
-
-        // Set the default explicit always.. I dont ever change it. This removes the need for this if statement over and over.
-        // if self.explicit_header {
-        //     self.set_explicit_header_mode()?;
-        // } else {
-        //     self.set_implicit_header_mode()?;
-        // }
         self.write_register(
             Register::RegOpMode.addr(),
             RadioMode::LongRangeMode.addr() | mode.addr(),
         )?;
 
+        self.enter(mode);
+        Ok(())
+    }
+
+    /// Records a mode change for `usage`, including the ones the chip makes by itself.
+    fn enter(&mut self, mode: RadioMode) {
+        let now = Instant::now();
+        self.usage.add(self.mode, now - self.mode_since);
+        self.mode_since = now;
         self.mode = mode;
+    }
+
+    /// Puts the radio in sleep mode, where it draws next to nothing. Registers keep their
+    /// values but the FIFO is lost.
+    pub fn sleep(&mut self) -> Result<()> {
+        self.set_mode(RadioMode::Sleep)
+    }
+
+    /// Returns from sleep to standby with the configuration intact.
+    pub fn wake(&mut self) -> Result<()> {
+        self.set_mode(RadioMode::Stdby)
+    }
+
+    /// Lets `sleep_if_idle` put the radio to sleep once it spent this long in standby. `None`
+    /// keeps it in standby, which is the default.
+    pub fn set_idle_sleep(&mut self, after: Option<Duration>) {
+        self.idle_sleep = after;
+    }
+
+    /// Sleeps if the radio has been idle for the time given to `set_idle_sleep` and returns
+    /// whether it did. Meant to be called whenever the node has nothing to do.
+    pub fn sleep_if_idle(&mut self) -> Result<bool> {
+        self.sync_tx_mode()?;
+        let idle = matches!(self.mode, RadioMode::Stdby)
+            && self.idle_sleep.is_some_and(|after| self.mode_since.elapsed() >= after);
+        if idle {
+            self.sleep()?;
+        }
+        Ok(idle)
+    }
+
+    /// Time spent in each mode since the radio was opened or `reset_usage` was called.
+    pub fn usage(&mut self) -> Result<Usage> {
+        self.sync_tx_mode()?;
+        let mut usage = self.usage;
+        usage.add(self.mode, self.mode_since.elapsed());
+        Ok(usage)
+    }
+
+    /// After `transmit_payload` the chip returns to standby by itself, which only shows in
+    /// the TxDone flag. Catches up with it so the radio is not taken to be transmitting.
+    fn sync_tx_mode(&mut self) -> Result<()> {
+        if matches!(self.mode, RadioMode::Tx) {
+            self.tx_done()?;
+        }
         Ok(())
     }
 
-    /// Sets the transmit power and pin. Levels can range from 0-14 when the output
-    /// pin = 0(RFO), and form 0-20 when output pin = 1(PaBoost). Power is in dB.
-    /// Default value is `17`.
-    pub fn set_tx_power(
-        &mut self,
-        mut level: i32,
-        output_pin: u8,
-    ) -> Result<()> {
-        if PaConfig::PaOutputRfoPin.addr() == output_pin {
-            // RFO
-            if level < 0 {
-                level = 0;
-            } else if level > 14 {
-                level = 14;
-            }
-            self.write_register(Register::RegPaConfig.addr(), (0x70 | level) as u8)
+    pub fn reset_usage(&mut self) {
+        self.usage = Usage::default();
+        self.mode_since = Instant::now();
+    }
+
+    /// Estimates the charge drawn since `reset_usage` from the time in each mode, at the
+    /// current transmit power and LNA boost.
+    pub fn energy(&mut self) -> Result<Estimate> {
+        let tx = self.tx_power()?;
+        let tx_ma = power::tx_current_ma(tx.output == PaOutput::PaBoost, tx.dbm);
+        let rx_ma = if self.get_lna_boost()? {
+            power::RX_LNA_BOOST_MA
         } else {
-            // PA BOOST
-            if level > 17 {
-                if level > 20 {
-                    level = 20;
-                }
-                // subtract 3 from level, so 18 - 20 maps to 15 - 17
-                level -= 3;
+            power::RX_MA
+        };
+        Ok(self.usage()?.estimate(tx_ma, rx_ma))
+    }
 
-                // High Power +20 dBm Operation (Semtech SX1276/77/78/79 5.4.3.)
-                self.write_register(Register::RegPaDac.addr(), 0x87)?;
-                self.set_ocp(140)?;
-            } else {
-                if level < 2 {
-                    level = 2;
-                }
-                //Default value PA_HF/LF or +17dBm
-                self.write_register(Register::RegPaDac.addr(), 0x84)?;
-                self.set_ocp(100)?;
-            }
-            level -= 2;
-            self.write_register(
-                Register::RegPaConfig.addr(),
-                PaConfig::PaBoost.addr() | level as u8,
-            )
+    /// Sets the output, power and PA ramp time. Over-current protection follows the power,
+    /// see `TxPower::ocp_ma`. Default power is PA_BOOST at `20` dBm.
+    pub fn set_tx_power(&mut self, power: TxPower) -> Result<()> {
+        let (pa, dac) = power.registers()?;
+        self.set_ocp(power.ocp_ma())?;
+        self.write_register(Register::RegPaDac.addr(), dac)?;
+        self.write_register(Register::RegPaConfig.addr(), pa)?;
+        let ramp = self.read_register(Register::RegPaRamp.addr())? & !pa_ramp::PA_RAMP_MASK;
+        self.write_register(Register::RegPaRamp.addr(), ramp | power.ramp as u8)
+    }
+
+    /// Returns the output, power and PA ramp time the registers are set to.
+    pub fn tx_power(&mut self) -> Result<TxPower> {
+        Ok(TxPower::from_registers(
+            self.read_register(Register::RegPaConfig.addr())?,
+            self.read_register(Register::RegPaDac.addr())?,
+            self.read_register(Register::RegPaRamp.addr())?,
+        ))
+    }
+
+    /// Lets the AGC pick the LNA gain or fixes it. Default is `Agc`.
+    pub fn set_lna_gain(&mut self, gain: LnaGain) -> Result<()> {
+        if gain != LnaGain::Agc {
+            self.modify(|r: &mut Lna| r.gain = gain as u8)?;
+        }
+        self.modify(|r: &mut ModemConfig3| r.agc_auto = gain == LnaGain::Agc)
+    }
+
+    /// Returns `Agc` or the fixed LNA gain.
+    pub fn get_lna_gain(&mut self) -> Result<LnaGain> {
+        if self.read::<ModemConfig3>()?.agc_auto {
+            return Ok(LnaGain::Agc);
         }
+        let gain = self.read::<Lna>()?.gain;
+        LnaGain::FIXED
+            .get((gain as usize).wrapping_sub(1))
+            .copied()
+            .ok_or_else(|| anyhow!("Reserved LNA gain {}.", gain))
     }
 
+    /// Runs the LNA at 150% current on the high frequency port (bands above 525 MHz) for
+    /// better sensitivity. The low frequency port used at 433 MHz has no boost, its setting is
+    /// kept at the default current. Default is on.
+    pub fn set_lna_boost(&mut self, on: bool) -> Result<()> {
+        self.modify(|r: &mut Lna| {
+            r.boost_hf = if on { lna::LNA_BOOST_HF_ON } else { 0 };
+            r.boost_lf = 0;
+        })
+    }
 
-    /// Sets the radio to use an explicit header. Default state is `ON`.
-    fn set_explicit_header_mode(&mut self) -> Result<()> {
-        let reg_modem_config_1 = self.read_register(Register::RegModemConfig1.addr())?;
-        self.write_register(Register::RegModemConfig1.addr(), reg_modem_config_1 & 0xfe)?;
-        self.explicit_header = true;
-        Ok(())
+    /// Returns whether the high frequency LNA boost is on.
+    pub fn get_lna_boost(&mut self) -> Result<bool> {
+        Ok(self.read::<Lna>()?.boost_hf == lna::LNA_BOOST_HF_ON)
     }
 
-    /// Sets the radio to use an implicit header. Default state is `OFF`.
-    /// 
-    fn set_implicit_header_mode(&mut self) -> Result<()> {
-        let reg_modem_config_1 = self.read_register(Register::RegModemConfig1.addr())?;
-        println!("Implicit header: config: {:08b}", reg_modem_config_1);
-        self.write_register(Register::RegModemConfig1.addr(), reg_modem_config_1 & 0x01)?;
-        self.explicit_header = false;
+    /// Sets the header mode. In implicit mode the fixed length goes to `RegPayloadLength`,
+    /// which the receiver needs to know where a packet ends. Spreading factor 6 only works
+    /// with an implicit header. Default mode is `Explicit`.
+    pub fn set_header_mode(&mut self, mode: HeaderMode) -> Result<()> {
+        match mode {
+            HeaderMode::Explicit => {
+                if self.get_spreading_factor()? == 6 {
+                    return Err(anyhow!("Spreading factor 6 requires implicit header mode."));
+                }
+                self.modify(|r: &mut ModemConfig1| r.implicit_header = false)?;
+            }
+            HeaderMode::Implicit(0) => return Err(anyhow!("Implicit header mode needs a length.")),
+            HeaderMode::Implicit(length) => {
+                self.write_register(Register::RegPayloadLength.addr(), length)?;
+                self.modify(|r: &mut ModemConfig1| r.implicit_header = true)?;
+            }
+        }
+        self.header_mode = mode;
         Ok(())
     }
 
+    /// Returns the header mode.
+    pub fn get_header_mode(&self) -> HeaderMode {
+        self.header_mode
+    }
+
+    /// Writes the length of an outgoing packet. Implicit packets always have the fixed length.
+    fn write_payload_length(&mut self, length: usize) -> Result<()> {
+        match self.header_mode {
+            HeaderMode::Explicit => self.write_register(Register::RegPayloadLength.addr(), length as u8),
+            HeaderMode::Implicit(fixed) if fixed as usize == length => Ok(()),
+            HeaderMode::Implicit(fixed) => {
+                Err(anyhow!("Implicit header mode sends {} bytes, not {}.", fixed, length))
+            }
+        }
+    }
+
     /// Blocks the current thread, returning the size of a packet if one is received or an error is the
     /// task timed out. The timeout can be supplied with None to make it poll indefinitely or
     /// with `Some(timeout_in_mill_seconds)`
     /// 
     pub fn poll_irq(&mut self,timeout_ms: Option<i32>) -> Result<usize> {
-        self.set_mode(RadioMode::RxContinuous)?;
         match timeout_ms {
-            Some(value) => {
-                let mut count = 0;
-                let packet_ready = loop {
-                    let packet_ready = self.read_register(Register::RegIrqFlags.addr())?.get_bit(6);
-                    if count >= value || packet_ready {
-                        break packet_ready;
-                    }
-                    count += 1;
-                    spin_sleep::sleep(Duration::from_millis(1));
-                };
-                if packet_ready {
-                    self.clear_irq()?;
-                    Ok(self.read_register(Register::RegRxNbBytes.addr())? as usize)
-                } else {
-                    Err(anyhow!("poll failed"))
-                }
-            }
+            Some(value) => self.poll_packet(value)?.ok_or_else(|| anyhow!("poll failed")),
             None => {
-                while !self.read_register(Register::RegIrqFlags.addr())?.get_bit(6) {
+                self.set_mode(RadioMode::RxContinuous)?;
+                loop {
+                    self.irq_flags = self.read_register(Register::RegIrqFlags.addr())?;
+                    if self.irq_flags & IRQ::IrqRxDoneMask.addr() != 0 {
+                        break;
+                    }
                     spin_sleep::sleep(Duration::from_millis(100));
                 }
                 self.clear_irq()?;
@@ -238,45 +581,145 @@ impl LoRa {
             }
         }
     }
-    
+
+    /// Like `poll_irq` with a timeout, but returns `None` when it expires so callers can tell
+    /// a quiet channel from a failing SPI bus.
+    pub fn poll_packet(&mut self, timeout_ms: i32) -> Result<Option<usize>> {
+        self.set_mode(RadioMode::RxContinuous)?;
+        let mut count = 0;
+        loop {
+            self.irq_flags = self.read_register(Register::RegIrqFlags.addr())?;
+            if self.irq_flags & IRQ::IrqRxDoneMask.addr() != 0 {
+                break;
+            }
+            if count >= timeout_ms {
+                return Ok(None);
+            }
+            count += 1;
+            spin_sleep::sleep(Duration::from_millis(1));
+        }
+        self.clear_irq()?;
+        Ok(Some(self.read_register(Register::RegRxNbBytes.addr())? as usize))
+    }
+
+    /// Listens once in `RxSingle` mode, returning the size of a packet or `None` if no preamble
+    /// was found within `symbol_timeout` symbols (see `SYMBOL_TIMEOUT`). A packet whose
+    /// preamble starts in time is received to the end. The radio returns to standby by itself
+    /// either way, which makes this suited to short receive windows after a transmission.
+    /// Fails if the window outlasts the longest possible packet.
+    pub fn receive_single(&mut self, symbol_timeout: u16) -> Result<Option<usize>> {
+        if !SYMBOL_TIMEOUT.contains(&symbol_timeout) {
+            return Err(anyhow!("Symbol timeout {} is not between 4 and 1023.", symbol_timeout));
+        }
+        let sf = self.get_spreading_factor()?;
+        let bw = self.get_signal_bandwidth()?;
+        let preamble = ((self.read_register(Register::RegPreambleMsb.addr())? as u64) << 8)
+            | self.read_register(Register::RegPreambleLsb.addr())? as u64;
+        // the window, then a packet found at its very end, the modem adds 4.25 preamble symbols
+        let symbols = symbol_timeout as u64 + preamble + 5 + max_payload_symbols(sf, bw, self.get_coding_rate_4()?);
+        let deadline = Instant::now() + Duration::from_micros(symbols * symbol_us(sf, bw)) + RX_SINGLE_MARGIN;
+
+        self.set_mode(RadioMode::Stdby)?;
+        self.write_register(Register::RegSymbTimeoutLsb.addr(), symbol_timeout as u8)?;
+        self.modify(|r: &mut ModemConfig2| r.symb_timeout_msb = (symbol_timeout >> 8) as u8)?;
+        self.write_register(Register::RegFifoAddrPtr.addr(), 0)?;
+        self.write_register(Register::RegIrqFlags.addr(), 0xff)?;
+        self.set_mode(RadioMode::RxSingle)?;
+
+        let done = IRQ::IrqRxDoneMask.addr() | IRQ::IrqRxTimeoutMask.addr();
+        loop {
+            self.irq_flags = self.read_register(Register::RegIrqFlags.addr())?;
+            if self.irq_flags & done != 0 {
+                break;
+            }
+            if Instant::now() > deadline {
+                self.set_mode(RadioMode::Stdby)?;
+                return Err(anyhow!("RxSingle did not end within {} symbols.", symbols));
+            }
+            spin_sleep::sleep(Duration::from_millis(1));
+        }
+        self.enter(RadioMode::Stdby);
+        self.clear_irq()?;
+        if self.rx_timeout() {
+            return Ok(None);
+        }
+        Ok(Some(self.read_register(Register::RegRxNbBytes.addr())? as usize))
+    }
+
+    /// Returns true if the last packet returned by `poll_irq` failed its payload CRC check.
+    pub fn crc_error(&self) -> bool {
+        self.irq_flags & IRQ::IrqPayloadCrcErrorMask.addr() != 0
+    }
+
+    /// Returns true if the last `receive_single` ended without a packet.
+    pub fn rx_timeout(&self) -> bool {
+        self.irq_flags & IRQ::IrqRxTimeoutMask.addr() != 0
+    }
+
      /// Clears the radio's IRQ registers.
      pub fn clear_irq(&mut self) -> Result<()> {
         let irq_flags = self.read_register(Register::RegIrqFlags.addr())?;
         self.write_register(Register::RegIrqFlags.addr(), irq_flags)
     }
 
-    // /// Returns true if the radio is currently transmitting a packet.
+    /// Returns true if the radio is currently transmitting a packet.
     pub fn transmitting(&mut self) -> Result<bool> {
-        let op_mode = self.read_register(Register::RegOpMode.addr())?;
-        if (op_mode & RadioMode::Tx.addr()) == RadioMode::Tx.addr()
-            || (op_mode & RadioMode::FsTx.addr()) == RadioMode::FsTx.addr()
-        {
+        let mode = self.read_register(Register::RegOpMode.addr())? & op_mode::MODE_MASK;
+        if mode == RadioMode::Tx.addr() || mode == RadioMode::FsTx.addr() {
             Ok(true)
         } else {
-            if (self.read_register(Register::RegIrqFlags.addr())? & IRQ::IrqTxDoneMask.addr()) == 1
-            {
+            // the chip returns to standby by itself once the packet is sent
+            if matches!(self.mode, RadioMode::Tx | RadioMode::FsTx) {
+                self.enter(RadioMode::Stdby);
+            }
+            if self.read_register(Register::RegIrqFlags.addr())? & IRQ::IrqTxDoneMask.addr() != 0 {
                 self.write_register(Register::RegIrqFlags.addr(), IRQ::IrqTxDoneMask.addr())?;
             }
             Ok(false)
         }
     }
 
+    /// Runs a channel activity detection and returns true if a LoRa preamble was heard. The
+    /// radio is left in standby.
+    pub fn channel_activity(&mut self) -> Result<bool> {
+        self.set_mode(RadioMode::Stdby)?;
+        self.clear_irq()?;
+        self.set_mode(RadioMode::Cad)?;
+        let flags = loop {
+            let flags = self.read_register(Register::RegIrqFlags.addr())?;
+            if flags & IRQ::IrqCadDoneMask.addr() != 0 {
+                break flags;
+            }
+            spin_sleep::sleep(Duration::from_micros(100));
+        };
+        self.clear_irq()?;
+        self.set_mode(RadioMode::Stdby)?;
+        Ok(flags & IRQ::IrqCadDetectedMask.addr() != 0)
+    }
+
+    /// Returns true once the packet started by `transmit_payload` is sent, the chip is then
+    /// back in standby.
     pub fn tx_done(&mut self) -> Result<bool>{
-        let res = self.read_register(Register::RegIrqFlags.addr())?;
-        println!("{:08b}", res);
-        match (res & 0x8) >> 3 {
-            0 => Ok(false),
-            _ => Ok(true),
+        let flags = self.read_register(Register::RegIrqFlags.addr())?;
+        let done = flags & IRQ::IrqTxDoneMask.addr() != 0;
+        if done && matches!(self.mode, RadioMode::Tx) {
+            self.enter(RadioMode::Stdby);
         }
+        Ok(done)
     }
 
     // Sets the frequency of the radio. Values are in megahertz.
     /// I.E. 915 MHz must be used for North America. Check regulation for your area.
     pub fn set_frequency(&mut self, freq: i64) -> Result<()> {
+        self.set_frequency_hz(freq as u64 * 1_000_000)
+    }
+
+    /// Sets the frequency of the radio in hertz, for channels that are not whole megahertz
+    /// (e.g. `433_175_000`).
+    pub fn set_frequency_hz(&mut self, freq: u64) -> Result<()> {
         self.frequency = freq;
-        // calculate register values
-        let base = 1;
-        let frf = (freq * (base << 19)) / 32;
+        // calculate register values, Frf = freq * 2^19 / Fxosc
+        let frf = (freq << 19) / FXOSC;
         // write registers
         self.write_register(
             Register::RegFrfMsb.addr(),
@@ -286,84 +729,68 @@ impl LoRa {
         self.write_register(Register::RegFrfLsb.addr(), (frf & 0x0000_00FF) as u8)
     }
 
-    /// Sets the over current protection on the radio(mA).
-    pub fn set_ocp(&mut self, ma: u8) -> Result<()> {
-        let mut ocp_trim: u8 = 27;
+    /// Returns the frequency the radio was last tuned to, in hertz.
+    pub fn get_frequency(&self) -> u64 {
+        self.frequency
+    }
 
-        if ma <= 120 {
-            ocp_trim = (ma - 45) / 5;
-        } else if ma <= 240 {
-            ocp_trim = (ma + 30) / 10;
-        }
-        self.write_register(Register::RegOcp.addr(), 0x20 | (0x1F & ocp_trim))
+    /// Sets the over current protection on the radio(mA), rounded up to the next step the
+    /// chip has.
+    pub fn set_ocp(&mut self, ma: u8) -> Result<()> {
+        self.write_register(Register::RegOcp.addr(), ocp::OCP_ON | ocp_trim(ma))
     }
 
     /// Returns the signal bandwidth of the radio.
     pub fn get_signal_bandwidth(&mut self) -> Result<i64> {
-        let bw = self.read_register(Register::RegModemConfig1.addr())? >> 4;
-        let bw = match bw {
-            0 => 7_800,
-            1 => 10_400,
-            2 => 15_600,
-            3 => 20_800,
-            4 => 31_250,
-            5 => 41_700,
-            6 => 62_500,
-            7 => 125_000,
-            8 => 250_000,
-            9 => 500_000,
-            _ => -1,
-        };
-        Ok(bw)
+        Ok(self.read::<ModemConfig1>()?.bandwidth().hz())
     }
 
     /// Returns the spreading factor of the radio.
     pub fn get_spreading_factor(&mut self) -> Result<u8> {
-        Ok(self.read_register(Register::RegModemConfig2.addr())? >> 4)
+        Ok(self.read::<ModemConfig2>()?.spreading_factor)
     }
 
     fn set_ldo_flag(&mut self) -> Result<()> {
         let sw = self.get_signal_bandwidth()?;
         // Section 4.1.1.5
-        let symbol_duration = 1000 / (sw / ((1 as i64) << self.get_spreading_factor()?));
+        let symbol_duration = 1000 / (sw / (1_i64 << self.get_spreading_factor()?));
 
         // Section 4.1.1.6
         let ldo_on = symbol_duration > 16;
 
-        let mut config_3 = self.read_register(Register::RegModemConfig3.addr())?;
-        config_3.set_bit(3, ldo_on);
-        self.write_register(Register::RegModemConfig3.addr(), config_3)
+        self.modify(|r: &mut ModemConfig3| r.low_data_rate_optimize = ldo_on)
     }
 
-        /// Sets the spreading factor of the radio. Supported values are between 6 and 12.
-    /// If a spreading factor of 6 is set, implicit header mode must be used to transmit
-    /// and receive packets. Default value is `7`.
+    /// Sets the spreading factor of the radio. Supported values are between 6 and 12.
+    /// Spreading factor 6 switches an explicit header to implicit, keeping the length in
+    /// `RegPayloadLength`, so set the length with `set_header_mode` first. Default value is `7`.
     pub fn set_spreading_factor(
         &mut self,
         mut sf: u8,
     ) -> Result<()> {
-        if sf < 6 {
-            sf = 6;
-        } else if sf > 12 {
-            sf = 12;
-        }
+        sf = sf.clamp(6, 12);
 
         if sf == 6 {
-            self.write_register(Register::RegDetectionOptimize.addr(), 0xc5)?;
-            self.write_register(Register::RegDetectionThreshold.addr(), 0x0c)?;
+            if self.header_mode == HeaderMode::Explicit {
+                let length = self.read_register(Register::RegPayloadLength.addr())?;
+                self.set_header_mode(HeaderMode::Implicit(length))?;
+            }
+            self.write_detection_optimize(detection::OPTIMIZE_SF6)?;
+            self.write_register(Register::RegDetectionThreshold.addr(), detection::THRESHOLD_SF6)?;
         } else {
-            self.write_register(Register::RegDetectionOptimize.addr(), 0xc3)?;
-            self.write_register(Register::RegDetectionThreshold.addr(), 0x0a)?;
+            self.write_detection_optimize(detection::OPTIMIZE_SF7_TO_SF12)?;
+            self.write_register(Register::RegDetectionThreshold.addr(), detection::THRESHOLD_SF7_TO_SF12)?;
         }
-        let modem_config_2 = self.read_register(Register::RegModemConfig2.addr())?;
-        self.write_register(
-            Register::RegModemConfig2.addr(),
-            (modem_config_2 & 0x0f) | ((sf << 4) & 0xf0),
-        )?;
+        self.modify(|r: &mut ModemConfig2| r.spreading_factor = sf)?;
         self.set_ldo_flag()?;
         Ok(())
     }
 
+    /// Writes the detection optimisation, keeping the reserved upper bits.
+    fn write_detection_optimize(&mut self, value: u8) -> Result<()> {
+        self.modify(|r: &mut DetectionOptimize| r.optimize = value)
+    }
+
     /// Transmits up to 255 bytes of data. To avoid the use of an allocator, this takes a fixed 255 u8
     /// array and a payload size and returns the number of bytes sent if successful.
     pub fn transmit_payload_busy(&mut self,buffer: [u8; 255],payload_size: usize,) -> Result<usize> {
@@ -371,20 +798,16 @@ impl LoRa {
             Err(anyhow!("ALREADY TX"))
         } else {
             self.set_mode(RadioMode::Stdby)?;
-            // if self.explicit_header {
-            //     self.set_explicit_header_mode()?;
-            // } else {
-            //     self.set_implicit_header_mode()?;
-            // }
+            self.write_payload_length(payload_size)?;
 
-            self.write_register(Register::RegIrqFlags.addr(), 0)?;
+            // a TxDone left from the last packet would end this one in `tx_done`
+            self.write_register(Register::RegIrqFlags.addr(), IRQ::IrqTxDoneMask.addr())?;
             self.write_register(Register::RegFifoAddrPtr.addr(), 0)?;
-            self.write_register(Register::RegPayloadLength.addr(), 0)?;
             for byte in buffer.iter().take(payload_size) {
                 self.write_register(Register::RegFifo.addr(), *byte)?;
             }
-            self.write_register(Register::RegPayloadLength.addr(), payload_size as u8)?;
             self.set_mode(RadioMode::Tx)?;
+            self.capture_frame(Direction::Outbound, &buffer[..payload_size])?;
             while self.transmitting()? {}
             Ok(payload_size)
         }
@@ -402,22 +825,17 @@ impl LoRa {
 
     /// Enables are disables the radio's CRC check. Default value is `false`.
     pub fn set_crc(&mut self, value: bool) -> Result<()> {
-        let modem_config_2 = self.read_register(Register::RegModemConfig2.addr())?;
-        if value {
-            self.write_register(Register::RegModemConfig2.addr(), modem_config_2 | 0x04)
-        } else {
-            self.write_register(Register::RegModemConfig2.addr(), modem_config_2 & 0xfb)
-        }
+        self.modify(|r: &mut ModemConfig2| r.crc_on = value)
     }
 
     /// Inverts the radio's IQ signals. Default value is `false`.
     pub fn set_invert_iq(&mut self, value: bool) -> Result<()> {
         if value {
-            self.write_register(Register::RegInvertiq.addr(), 0x66)?;
-            self.write_register(Register::RegInvertiq2.addr(), 0x19)
+            self.write_register(Register::RegInvertiq.addr(), invert_iq::INVERTED)?;
+            self.write_register(Register::RegInvertiq2.addr(), invert_iq::INVERTED_2)
         } else {
-            self.write_register(Register::RegInvertiq.addr(), 0x27)?;
-            self.write_register(Register::RegInvertiq2.addr(), 0x1d)
+            self.write_register(Register::RegInvertiq.addr(), invert_iq::NORMAL)?;
+            self.write_register(Register::RegInvertiq2.addr(), invert_iq::NORMAL_2)
         }
     }
 
@@ -429,37 +847,21 @@ impl LoRa {
         &mut self,
         sbw: i64,
     ) -> Result<()> {
-        let bw: i64 = match sbw {
-            7_800 => 0,
-            10_400 => 1,
-            15_600 => 2,
-            20_800 => 3,
-            31_250 => 4,
-            41_700 => 5,
-            62_500 => 6,
-            125_000 => 7,
-            250_000 => 8,
-            _ => 9,
-        };
+        let bw = Bandwidth::from_hz(sbw).unwrap_or(Bandwidth::Bw500k);
 
-        if bw == 9 {
-            if self.frequency < 525 {
-                self.write_register(Register::RegHighBWOptimize1.addr(), 0x02)?;
-                self.write_register(Register::RegHighBWOptimize2.addr(), 0x7f)?;
+        if bw == Bandwidth::Bw500k {
+            self.write_register(Register::RegHighBWOptimize1.addr(), high_bw_optimize::OPTIMIZE_1_500K)?;
+            if self.frequency < 525_000_000 {
+                self.write_register(Register::RegHighBWOptimize2.addr(), high_bw_optimize::OPTIMIZE_2_500K_LF)?;
             } else {
-                self.write_register(Register::RegHighBWOptimize1.addr(), 0x02)?;
-                self.write_register(Register::RegHighBWOptimize2.addr(), 0x64)?;
+                self.write_register(Register::RegHighBWOptimize2.addr(), high_bw_optimize::OPTIMIZE_2_500K_HF)?;
             }
         } else {
-            self.write_register(Register::RegHighBWOptimize1.addr(), 0x03)?;
-            self.write_register(Register::RegHighBWOptimize2.addr(), 0x65)?;
+            self.write_register(Register::RegHighBWOptimize1.addr(), high_bw_optimize::OPTIMIZE_1_DEFAULT)?;
+            self.write_register(Register::RegHighBWOptimize2.addr(), high_bw_optimize::OPTIMIZE_2_DEFAULT)?;
         }
 
-        let modem_config_1 = self.read_register(Register::RegModemConfig1.addr())?;
-        self.write_register(
-            Register::RegModemConfig1.addr(),
-            (modem_config_1 & 0x0f) | ((bw << 4) as u8),
-        )?;
+        self.modify(|r: &mut ModemConfig1| r.set_bandwidth(bw))?;
         self.set_ldo_flag()?;
         Ok(())
     }
@@ -471,17 +873,14 @@ impl LoRa {
         &mut self,
         mut denominator: u8,
     ) -> Result<()> {
-        if denominator < 5 {
-            denominator = 5;
-        } else if denominator > 8 {
-            denominator = 8;
-        }
+        denominator = denominator.clamp(5, 8);
         let cr = denominator - 4;
-        let modem_config_1 = self.read_register(Register::RegModemConfig1.addr())?;
-        self.write_register(
-            Register::RegModemConfig1.addr(),
-            (modem_config_1 & 0xf1) | (cr << 1),
-        )
+        self.modify(|r: &mut ModemConfig1| r.coding_rate = cr)
+    }
+
+    /// Returns the denominator of the coding rate, i.e. `5` for `4/5`.
+    pub fn get_coding_rate_4(&mut self) -> Result<u8> {
+        Ok(self.read::<ModemConfig1>()?.coding_rate + 4)
     }
 
 
@@ -490,24 +889,16 @@ impl LoRa {
             Err(anyhow!("Transmitting"))
         } else {
             self.set_mode(RadioMode::Stdby)?;
-            // if self.explicit_header {
-            //     self.set_explicit_header_mode()?;
-            // } else {
-            //     self.set_implicit_header_mode()?;
-            // }
+            self.write_payload_length(payload.len().min(255))?;
 
-            self.write_register(Register::RegIrqFlags.addr(), 0)?;
+            // a TxDone left from the last packet would end this one in `tx_done`
+            self.write_register(Register::RegIrqFlags.addr(), IRQ::IrqTxDoneMask.addr())?;
             self.write_register(Register::RegFifoAddrPtr.addr(), 0)?;
-            self.write_register(Register::RegPayloadLength.addr(), 0)?;
             for &byte in payload.iter().take(255) {
                 self.write_register(Register::RegFifo.addr(), byte)?;
             }
-            self.write_register(
-                Register::RegPayloadLength.addr(),
-                payload.len().min(255) as u8,
-            )?;
             self.set_mode(RadioMode::Tx)?;
-            Ok(())
+            self.capture_frame(Direction::Outbound, &payload[..payload.len().min(255)])
         }
     }
 
@@ -521,7 +912,7 @@ impl LoRa {
     /// Returns the contents of the fifo as a fixed 255 u8 array. This should only be called if there is a
     /// new packet ready to be read.
     pub fn read_packet(&mut self) -> Result<[u8; 255]> {
-        let mut buffer = [0 as u8; 255];
+        let mut buffer = [0_u8; 255];
         self.clear_irq()?;
         let size = self.get_ready_packet_size()?;
         let fifo_addr = self.read_register(Register::RegFifoRxCurrentAddr.addr())?;
@@ -531,13 +922,108 @@ impl LoRa {
             buffer[i as usize] = byte;
         }
         self.write_register(Register::RegFifoAddrPtr.addr(), 0)?;
+        self.capture_frame(Direction::Inbound, &buffer[..size as usize])?;
         Ok(buffer)
     }
 
-    pub fn tx_bulk(&mut self, data: &[u8]) {
+    /// Writes every frame sent by `transmit_payload` or `transmit_payload_busy` and every frame
+    /// returned by `read_packet` to a pcapng capture. `None` stops capturing.
+    pub fn set_capture(&mut self, capture: Option<Capture>) {
+        self.capture = capture;
+    }
+
+    fn capture_frame(&mut self, direction: Direction, data: &[u8]) -> Result<()> {
+        if self.capture.is_none() {
+            return Ok(());
+        }
+        let modem_config_1: ModemConfig1 = self.read()?;
+        let modem_config_2: ModemConfig2 = self.read()?;
+        let (rssi, snr, crc) = match direction {
+            Direction::Inbound => {
+                // the CRC presence of a received frame comes from its header
+                let crc_on_payload = self.read_register(Register::RegHopChannel.addr())? & hop_channel::CRC_ON_PAYLOAD != 0;
+                let crc = if self.crc_error() {
+                    Crc::Bad
+                } else if crc_on_payload {
+                    Crc::Ok
+                } else {
+                    Crc::None
+                };
+                (Some(self.get_packet_rssi()?), Some(self.get_packet_snr()?), crc)
+            }
+            Direction::Outbound if modem_config_2.crc_on => (None, None, Crc::Ok),
+            Direction::Outbound => (None, None, Crc::None),
+        };
+        let info = LinkInfo {
+            direction,
+            frequency: self.frequency,
+            bandwidth: modem_config_1.bandwidth().hz(),
+            spreading_factor: modem_config_2.spreading_factor,
+            coding_rate: modem_config_1.coding_rate + 4,
+            sync_word: self.read_register(Register::RegSyncWord.addr())?,
+            invert_iq: self.read_register(Register::RegInvertiq.addr())? & invert_iq::INVERT_IQ_RX != 0,
+            implicit_header: modem_config_1.implicit_header,
+            rssi,
+            snr,
+            crc,
+        };
+        if let Some(capture) = &mut self.capture {
+            capture.write(&info, data)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the SNR of the last received packet in dB.
+    pub fn get_packet_snr(&mut self) -> Result<f32> {
+        Ok(self.read_register(Register::RegPktSnrValue.addr())? as i8 as f32 / 4.0)
+    }
+
+    /// Returns the RSSI of the last received packet in dBm. See section 5.5.5 of the datasheet,
+    /// packets received below the noise floor are corrected by their SNR.
+    pub fn get_packet_rssi(&mut self) -> Result<f32> {
+        let offset = self.rssi_offset();
+        let rssi = self.read_register(Register::RegPktRssiValue.addr())? as f32;
+        let snr = self.get_packet_snr()?;
+        if snr < 0.0 {
+            Ok(offset + rssi + snr)
+        } else {
+            Ok(offset + rssi * 16.0 / 15.0)
+        }
+    }
+
+    /// Returns the current RSSI in dBm, only meaningful in a receive mode.
+    pub fn get_rssi(&mut self) -> Result<f32> {
+        Ok(self.rssi_offset() + self.read_register(Register::RegRssiValue.addr())? as f32)
+    }
+
+    /// Returns the raw wideband RSSI reading (`RegRssiWideband`), which is uncalibrated.
+    pub fn get_rssi_wideband(&mut self) -> Result<u8> {
+        self.read_register(Register::RegRssiWideband.addr())
+    }
+
+    /// RSSI register offset of the low and high frequency ports (section 5.5.5).
+    fn rssi_offset(&self) -> f32 {
+        if self.frequency < 525_000_000 { -164.0 } else { -157.0 }
+    }
+
+    /// Returns the frequency error of the last received packet in hertz (section 4.1.5).
+    pub fn get_frequency_error(&mut self) -> Result<i64> {
+        let msb = self.read_register(Register::RegFreqErrorMsb.addr())? as i64 & 0x0f;
+        let mid = self.read_register(Register::RegFreqErrorMid.addr())? as i64;
+        let lsb = self.read_register(Register::RegFreqErrorLsb.addr())? as i64;
+        let mut error = (msb << 16) | (mid << 8) | lsb;
+        // 20 bit two's complement
+        if error & 0x8_0000 != 0 {
+            error -= 0x10_0000;
+        }
+        let bw = self.get_signal_bandwidth()?;
+        Ok(error * (1 << 24) * bw / (FXOSC as i64 * 500_000))
+    }
+
+    /// Sends `data` in packets of up to 255 bytes, blocking until each is on air.
+    pub fn tx_bulk(&mut self, data: &[u8]) -> Result<()> {
 
-        // interrupting on tx done.
-        self.write_register(Register::RegDioMapping1.addr(), 0x1).unwrap();
+        self.write_register(Register::RegDioMapping1.addr(), dio_mapping_1::DIO0_TX_DONE)?;
 
         for chunk in data.chunks(TX_CHUNK_SIZE){
             let mut buffer = [0u8; TX_CHUNK_SIZE]; // Initialize a buffer with zeros
@@ -548,12 +1034,96 @@ impl LoRa {
 
 
             if chunk_len < TX_CHUNK_SIZE {
-                self.transmit_payload_busy(buffer, chunk_len).unwrap();
+                self.transmit_payload_busy(buffer, chunk_len)?;
             } else {
-                self.transmit_payload_busy(buffer, 255).unwrap(); // Transmit the full buffer
+                self.transmit_payload_busy(buffer, 255)?; // Transmit the full buffer
             }
         }
+        Ok(())
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The limit `OcpTrim` sets, the inverse of `ocp_trim`.
+    fn ocp_limit(trim: u8) -> u16 {
+        match trim {
+            0..=15 => 45 + 5 * trim as u16,
+            16..=27 => 10 * trim as u16 - 30,
+            _ => 240,
+        }
+    }
+
+    #[test]
+    fn rfo_registers() {
+        for dbm in -3..=15 {
+            let power = TxPower::rfo(dbm);
+            let (pa, dac) = power.registers().unwrap();
+            assert_eq!(pa & pa_config::PA_SELECT, 0);
+            assert_eq!(dac, pa_dac::DEFAULT);
+            // Pout = 10.8 + 0.6 * MaxPower - (15 - OutputPower)
+            let max_power = ((pa & pa_config::MAX_POWER_MASK) >> pa_config::MAX_POWER_SHIFT) as f32;
+            let pout = 10.8 + 0.6 * max_power - (15.0 - (pa & pa_config::OUTPUT_POWER_MASK) as f32);
+            assert!((pout - dbm as f32).abs() < 1e-4, "{} dBm gives {}", dbm, pout);
+            assert_eq!(TxPower::from_registers(pa, dac, FskRampUpRamDown::_40us as u8), power);
+        }
+        for dbm in [-4, 16] {
+            assert!(TxPower::rfo(dbm).registers().is_err());
+        }
+    }
+
+    #[test]
+    fn pa_boost_registers() {
+        for dbm in 2..=20 {
+            let power = TxPower::pa_boost(dbm);
+            let (pa, dac) = power.registers().unwrap();
+            assert_eq!(pa & (pa_config::PA_SELECT | pa_config::MAX_POWER_MASK), 0xf0);
+            let boost = if dbm > 17 { pa_dac::BOOST_20DBM } else { pa_dac::DEFAULT };
+            assert_eq!(dac, boost, "{} dBm", dbm);
+            assert_eq!(TxPower::from_registers(pa, dac, FskRampUpRamDown::_40us as u8), power);
+        }
+        for dbm in [1, 21] {
+            assert!(TxPower::pa_boost(dbm).registers().is_err());
+        }
+        let ramp = TxPower { ramp: FskRampUpRamDown::_1ms, ..TxPower::pa_boost(14) };
+        let (pa, dac) = ramp.registers().unwrap();
+        assert_eq!(TxPower::from_registers(pa, dac, 0x10 | FskRampUpRamDown::_1ms as u8), ramp);
+    }
 
+    #[test]
+    fn ocp_follows_the_power() {
+        // RFO draws at most 33 mA, below the lowest limit
+        assert!((-3..=15).all(|dbm| TxPower::rfo(dbm).ocp_ma() == 45));
+        let pa_boost: Vec<u8> = (2..=20).map(|dbm| TxPower::pa_boost(dbm).ocp_ma()).collect();
+        assert_eq!(
+            pa_boost,
+            [100, 100, 100, 100, 100, 100, 100, 100, 100, 100, 100, 100, 100, 100, 100, 105, 114, 129, 144]
+        );
+        for dbm in 2..=20 {
+            let power = TxPower::pa_boost(dbm);
+            let current = power::tx_current_ma(true, dbm);
+            assert!(power.ocp_ma() as f64 >= current * 1.2);
+            // the register never limits below what was asked for
+            assert!(ocp_limit(ocp_trim(power.ocp_ma())) >= power.ocp_ma() as u16);
+        }
     }
 
+    #[test]
+    fn ocp_trim_rounds_up() {
+        assert_eq!(ocp_trim(0), 0);
+        assert_eq!(ocp_trim(45), 0);
+        assert_eq!(ocp_trim(100), 11);
+        assert_eq!(ocp_trim(101), 12);
+        assert_eq!(ocp_trim(120), 15);
+        assert_eq!(ocp_trim(121), 16);
+        assert_eq!(ocp_trim(240), 27);
+        assert_eq!(ocp_trim(255), ocp::OCP_TRIM_MASK);
+        for ma in 45..=240u8 {
+            let limit = ocp_limit(ocp_trim(ma));
+            assert!(limit >= ma as u16 && limit < ma as u16 + 10, "{} mA gives {}", ma, limit);
+        }
+    }
 }
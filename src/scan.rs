@@ -0,0 +1,172 @@
+use anyhow::{anyhow, Result};
+use std::time::Duration;
+
+use crate::rfm96w::LoRa;
+
+/// Symbols to listen for after activity was detected when no window is given, enough for the
+/// preamble, header and a short payload.
+const WINDOW_SYMBOLS: u64 = 100;
+
+/// How each combination is checked.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Method {
+    /// Channel activity detection, with an RX window only after a detection.
+    Cad,
+    /// An RX window on every combination, slower but independent of CAD sensitivity.
+    Rx,
+}
+
+pub struct ScanConfig {
+    /// Frequencies in hertz.
+    pub frequencies: Vec<u64>,
+    pub spreading_factors: Vec<u8>,
+    /// Bandwidths in hertz.
+    pub bandwidths: Vec<i64>,
+    pub method: Method,
+    /// RX window, computed from the symbol time when `None`.
+    pub window: Option<Duration>,
+    /// Passes over all combinations, `None` scans until killed.
+    pub rounds: Option<u32>,
+}
+
+/// Results for one frequency, spreading factor and bandwidth.
+#[derive(Default)]
+struct Combination {
+    frequency: u64,
+    spreading_factor: u8,
+    bandwidth: i64,
+    checks: u64,
+    detections: u64,
+    packets: u64,
+    crc_errors: u64,
+    best_rssi: Option<f32>,
+    last_snr: Option<f32>,
+}
+
+impl Combination {
+    fn label(&self) -> String {
+        format!(
+            "{:.3} MHz SF{} BW {}",
+            self.frequency as f64 / 1e6,
+            self.spreading_factor,
+            self.bandwidth
+        )
+    }
+
+    /// Listening time for one packet after a detection.
+    fn window(&self, config: &ScanConfig) -> Duration {
+        config.window.unwrap_or_else(|| {
+            let symbol_us = (1_000_000u64 << self.spreading_factor) / self.bandwidth as u64;
+            Duration::from_micros(symbol_us * WINDOW_SYMBOLS)
+        })
+    }
+}
+
+/// Cycles through every combination of frequency, spreading factor and bandwidth, checking
+/// each for activity and trying to receive a packet. Detections and packets are printed as
+/// they happen, a table of all combinations with activity after every round. The original
+/// settings are restored when the scan ends.
+pub fn run(radio: &mut LoRa, config: &ScanConfig) -> Result<()> {
+    if config.frequencies.is_empty() || config.spreading_factors.is_empty() || config.bandwidths.is_empty() {
+        return Err(anyhow!("Nothing to scan."));
+    }
+    let original = (
+        radio.get_frequency(),
+        radio.get_spreading_factor()?,
+        radio.get_signal_bandwidth()?,
+    );
+
+    let mut combinations = Vec::new();
+    for &frequency in &config.frequencies {
+        for &spreading_factor in &config.spreading_factors {
+            for &bandwidth in &config.bandwidths {
+                combinations.push(Combination { frequency, spreading_factor, bandwidth, ..Default::default() });
+            }
+        }
+    }
+    println!("Scanning {} combinations.", combinations.len());
+
+    let mut round = 0;
+    while config.rounds.is_none_or(|rounds| round < rounds) {
+        for combination in &mut combinations {
+            check(radio, combination, config)?;
+        }
+        round += 1;
+        report(round, &combinations);
+    }
+
+    radio.set_frequency_hz(original.0)?;
+    radio.set_spreading_factor(original.1)?;
+    radio.set_signal_bandwidth(original.2)
+}
+
+fn check(radio: &mut LoRa, combination: &mut Combination, config: &ScanConfig) -> Result<()> {
+    radio.set_frequency_hz(combination.frequency)?;
+    radio.set_spreading_factor(combination.spreading_factor)?;
+    radio.set_signal_bandwidth(combination.bandwidth)?;
+    combination.checks += 1;
+
+    if config.method == Method::Cad {
+        if !radio.channel_activity()? {
+            return Ok(());
+        }
+        combination.detections += 1;
+        println!("{}: activity", combination.label());
+    }
+
+    let window = combination.window(config).as_millis().max(1) as i32;
+    let Ok(size) = radio.poll_irq(Some(window)) else {
+        return Ok(());
+    };
+    let crc_ok = !radio.crc_error();
+    let buffer = radio.read_packet()?;
+    let rssi = radio.get_packet_rssi()?;
+    let snr = radio.get_packet_snr()?;
+    if config.method == Method::Rx {
+        combination.detections += 1;
+    }
+    combination.packets += 1;
+    combination.crc_errors += !crc_ok as u64;
+    combination.best_rssi = Some(combination.best_rssi.map_or(rssi, |best| best.max(rssi)));
+    combination.last_snr = Some(snr);
+
+    let preview: String = buffer[..size.min(16)].iter().map(|b| format!("{:02x}", b)).collect();
+    println!(
+        "{}: {} bytes, rssi {:.0} dBm, snr {:.2} dB, crc {}, {}{}",
+        combination.label(),
+        size,
+        rssi,
+        snr,
+        if crc_ok { "ok" } else { "error" },
+        preview,
+        if size > 16 { "..." } else { "" }
+    );
+    Ok(())
+}
+
+fn report(round: u32, combinations: &[Combination]) {
+    let active: Vec<&Combination> = combinations.iter().filter(|c| c.detections > 0).collect();
+    println!("\nRound {}: activity on {} of {} combinations.", round, active.len(), combinations.len());
+    if active.is_empty() {
+        return;
+    }
+    println!(
+        "{:<30} {:>7} {:>9} {:>8} {:>8} {:>9} {:>8}",
+        "combination", "checks", "activity", "packets", "crc err", "best rssi", "snr"
+    );
+    for c in active {
+        let rssi = c.best_rssi.map_or("-".to_string(), |r| format!("{:.0}", r));
+        let snr = c.last_snr.map_or("-".to_string(), |s| format!("{:.2}", s));
+        println!(
+            "{:<30} {:>7} {:>9} {:>8} {:>8} {:>9} {:>8}",
+            c.label(),
+            c.checks,
+            c.detections,
+            c.packets,
+            c.crc_errors,
+            rssi,
+            snr
+        );
+    }
+    println!();
+}
@@ -0,0 +1,460 @@
+use aes_gcm::aead::{AeadInPlace, KeyInit};
+use aes_gcm::{Aes128Gcm, Nonce};
+use anyhow::{anyhow, Result};
+use bit_field::BitField;
+use chacha20poly1305::ChaCha20Poly1305;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::datagram::{Header, BROADCAST, FLAG_SECURE, HEADER_LEN, MAX_PAYLOAD};
+use crate::rfm96w::LoRa;
+
+pub const COUNTER_LEN: usize = 4;
+pub const TAG_LEN: usize = 16;
+/// Bytes a secured datagram costs on top of its plaintext: the counter and the tag.
+pub const OVERHEAD: usize = COUNTER_LEN + TAG_LEN;
+/// Largest plaintext that still fits in a single 255 byte packet.
+pub const MAX_PLAINTEXT: usize = MAX_PAYLOAD - OVERHEAD;
+
+// Number of counters behind the highest seen that are still accepted once.
+const REPLAY_WINDOW: u32 = 64;
+// Counters are reserved on disk in blocks so a reboot never reuses a nonce.
+const COUNTER_BLOCK: u32 = 1024;
+
+/// A pre-shared key and the AEAD it is used with.
+#[derive(Clone)]
+pub enum Key {
+    Aes128Gcm([u8; 16]),
+    ChaCha20Poly1305([u8; 32]),
+}
+
+impl Key {
+    /// Parses a key from a cipher name (`aes128` or `chacha20`) and the key in hex.
+    pub fn parse(cipher: &str, hex: &str) -> Result<Self> {
+        let bytes = (0..hex.len())
+            .step_by(2)
+            .map(|i| {
+                hex.get(i..i + 2)
+                    .and_then(|b| u8::from_str_radix(b, 16).ok())
+                    .ok_or_else(|| anyhow!("Invalid hex in key."))
+            })
+            .collect::<Result<Vec<u8>>>()?;
+        match cipher {
+            "aes128" => Ok(Key::Aes128Gcm(
+                bytes.try_into().map_err(|_| anyhow!("AES-128 keys are 16 bytes."))?,
+            )),
+            "chacha20" => Ok(Key::ChaCha20Poly1305(
+                bytes.try_into().map_err(|_| anyhow!("ChaCha20 keys are 32 bytes."))?,
+            )),
+            _ => Err(anyhow!("Unknown cipher: {}", cipher)),
+        }
+    }
+
+    fn seal(&self, nonce: &[u8; 12], aad: &[u8], buffer: &mut [u8]) -> Result<[u8; TAG_LEN]> {
+        let nonce = Nonce::from_slice(nonce);
+        let tag = match self {
+            Key::Aes128Gcm(key) => Aes128Gcm::new(&(*key).into())
+                .encrypt_in_place_detached(nonce, aad, buffer),
+            Key::ChaCha20Poly1305(key) => ChaCha20Poly1305::new(&(*key).into())
+                .encrypt_in_place_detached(nonce, aad, buffer),
+        }
+        .map_err(|_| anyhow!("Encryption failed."))?;
+        Ok(tag.into())
+    }
+
+    fn open(&self, nonce: &[u8; 12], aad: &[u8], buffer: &mut [u8], tag: &[u8]) -> Result<()> {
+        let nonce = Nonce::from_slice(nonce);
+        match self {
+            Key::Aes128Gcm(key) => Aes128Gcm::new(&(*key).into())
+                .decrypt_in_place_detached(nonce, aad, buffer, tag.into()),
+            Key::ChaCha20Poly1305(key) => ChaCha20Poly1305::new(&(*key).into())
+                .decrypt_in_place_detached(nonce, aad, buffer, tag.into()),
+        }
+        .map_err(|_| anyhow!("Authentication failed."))
+    }
+}
+
+/// Replaces the counter file atomically: the value is written to a temporary file, synced and
+/// renamed over `path`, then the directory is synced so the rename survives a power loss.
+fn write_counter(path: &Path, value: u32) -> Result<()> {
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    let mut file = File::create(&temporary)?;
+    file.write_all(value.to_string().as_bytes())?;
+    file.sync_all()?;
+    std::fs::rename(&temporary, path)?;
+    let directory = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    File::open(directory)?.sync_all()?;
+    Ok(())
+}
+
+/// Sliding window over the counters received from one sender (RFC 4303, 3.4.3).
+#[derive(Default)]
+struct ReplayWindow {
+    highest: Option<u32>,
+    /// Bit `n` is set if `highest - n` has been received.
+    seen: u64,
+}
+
+impl ReplayWindow {
+    fn is_fresh(&self, counter: u32) -> bool {
+        match self.highest {
+            None => true,
+            Some(highest) if counter > highest => true,
+            Some(highest) => {
+                let age = highest - counter;
+                age < REPLAY_WINDOW && !self.seen.get_bit(age as usize)
+            }
+        }
+    }
+
+    /// Marks `counter` as received, it must have passed `is_fresh`.
+    fn accept(&mut self, counter: u32) {
+        match self.highest {
+            Some(highest) if counter <= highest => self.seen |= 1 << (highest - counter),
+            Some(highest) => {
+                let shift = counter - highest;
+                self.seen = if shift < REPLAY_WINDOW { (self.seen << shift) | 1 } else { 1 };
+                self.highest = Some(counter);
+            }
+            None => {
+                self.seen = 1;
+                self.highest = Some(counter);
+            }
+        }
+    }
+}
+
+/// Encrypts and authenticates datagrams with keys shared in advance with each peer.
+///
+/// A secured packet is the header with `FLAG_SECURE` set, the sender's 32 bit counter, the
+/// ciphertext and a 16 byte tag. The header and counter are authenticated but sent in the clear.
+/// The nonce is the sender address followed by the counter, so a key may be shared by both ends
+/// of a link, or by a whole group for broadcasts (registered under address `255`).
+pub struct SecureChannel {
+    address: u8,
+    counter: u32,
+    id: u8,
+    keys: HashMap<u8, Key>,
+    windows: HashMap<u8, ReplayWindow>,
+    counter_file: Option<(PathBuf, u32)>,
+}
+
+impl SecureChannel {
+    pub fn new(address: u8) -> Self {
+        SecureChannel {
+            address,
+            counter: 0,
+            id: 0,
+            keys: HashMap::new(),
+            windows: HashMap::new(),
+            counter_file: None,
+        }
+    }
+
+    pub fn add_peer(&mut self, address: u8, key: Key) {
+        self.keys.insert(address, key);
+    }
+
+    /// Loads keys from a file with one `address cipher hex-key` line per peer. Lines starting
+    /// with `#` are ignored.
+    pub fn load_keys<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        for line in std::fs::read_to_string(path)?.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields[..] {
+                [address, cipher, key] => self.add_peer(address.parse()?, Key::parse(cipher, key)?),
+                _ => return Err(anyhow!("Bad key line: {}", line)),
+            }
+        }
+        Ok(())
+    }
+
+    /// Keeps the transmit counter in `path` so it survives restarts. Counters are reserved in
+    /// blocks, a crash skips the rest of the block instead of reusing it. `seal` refuses to work
+    /// until this has been called.
+    pub fn persist_counter<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        let path = path.as_ref().to_path_buf();
+        self.counter = match std::fs::read_to_string(&path) {
+            Ok(value) => value
+                .trim()
+                .parse()
+                .map_err(|_| anyhow!("Corrupt counter file {}.", path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e.into()),
+        };
+        let reserved = self.counter.saturating_add(COUNTER_BLOCK);
+        write_counter(&path, reserved)?;
+        self.counter_file = Some((path, reserved));
+        Ok(())
+    }
+
+    /// Returns the counter the next datagram will be sent with.
+    pub fn counter(&self) -> u32 {
+        self.counter
+    }
+
+    fn next_counter(&mut self) -> Result<u32> {
+        let Some((path, reserved)) = &mut self.counter_file else {
+            return Err(anyhow!("No counter file, call persist_counter before sealing."));
+        };
+        if self.counter == u32::MAX {
+            return Err(anyhow!("Counter exhausted, keys must be replaced."));
+        }
+        if self.counter >= *reserved {
+            let next = reserved.saturating_add(COUNTER_BLOCK);
+            write_counter(path, next)?;
+            *reserved = next;
+        }
+        let counter = self.counter;
+        self.counter += 1;
+        Ok(counter)
+    }
+
+    fn nonce(from: u8, counter: u32) -> [u8; 12] {
+        let mut nonce = [0u8; 12];
+        nonce[0] = from;
+        nonce[8..].copy_from_slice(&counter.to_be_bytes());
+        nonce
+    }
+
+    /// Builds a secured packet for `to`, header included.
+    pub fn seal(&mut self, to: u8, flags: u8, payload: &[u8]) -> Result<Vec<u8>> {
+        if payload.len() > MAX_PLAINTEXT {
+            return Err(anyhow!("Payload exceeds {} bytes.", MAX_PLAINTEXT));
+        }
+        let key = self.keys.get(&to).ok_or_else(|| anyhow!("No key for peer {}.", to))?.clone();
+        let counter = self.next_counter()?;
+        let header = Header {
+            to,
+            from: self.address,
+            id: self.id,
+            flags: flags | FLAG_SECURE,
+        };
+        self.id = self.id.wrapping_add(1);
+
+        let mut packet = header.to_bytes().to_vec();
+        packet.extend_from_slice(&counter.to_be_bytes());
+        let aad_len = packet.len();
+        packet.extend_from_slice(payload);
+        let (aad, buffer) = packet.split_at_mut(aad_len);
+        let tag = key.seal(&Self::nonce(self.address, counter), aad, buffer)?;
+        packet.extend_from_slice(&tag);
+        Ok(packet)
+    }
+
+    /// Authenticates and decrypts a received packet, rejecting replays. Returns the header and
+    /// the plaintext.
+    pub fn open(&mut self, packet: &[u8]) -> Result<(Header, Vec<u8>)> {
+        let header = Header::from_bytes(packet)?;
+        if header.flags & FLAG_SECURE == 0 {
+            return Err(anyhow!("Packet is not secured."));
+        }
+        if packet.len() < HEADER_LEN + OVERHEAD {
+            return Err(anyhow!("Packet too short."));
+        }
+        let peer = if header.to == BROADCAST { BROADCAST } else { header.from };
+        let key = self.keys.get(&peer).ok_or_else(|| anyhow!("No key for peer {}.", peer))?;
+
+        let (aad, rest) = packet.split_at(HEADER_LEN + COUNTER_LEN);
+        let counter = u32::from_be_bytes(aad[HEADER_LEN..].try_into()?);
+        let window = self.windows.entry(header.from).or_default();
+        if !window.is_fresh(counter) {
+            return Err(anyhow!("Replayed counter {} from {}.", counter, header.from));
+        }
+
+        let (ciphertext, tag) = rest.split_at(rest.len() - TAG_LEN);
+        let mut plaintext = ciphertext.to_vec();
+        key.open(&Self::nonce(header.from, counter), aad, &mut plaintext, tag)?;
+        // only authentic packets may move the window
+        window.accept(counter);
+        Ok((header, plaintext))
+    }
+
+    /// Seals `payload` and transmits it, blocking until it is on air.
+    pub fn send(&mut self, radio: &mut LoRa, to: u8, payload: &[u8]) -> Result<()> {
+        let packet = self.seal(to, 0, payload)?;
+        radio.tx_bulk(&packet)
+    }
+
+    /// Opens a packet returned by `read_packet`, `size` being the value from `poll_irq`. Packets
+    /// addressed to other nodes are rejected.
+    pub fn receive(&mut self, buffer: &[u8], size: usize) -> Result<(Header, Vec<u8>)> {
+        let header = Header::from_bytes(&buffer[..size])?;
+        if !header.is_for(self.address) {
+            return Err(anyhow!("Packet for {}, not us.", header.to));
+        }
+        self.open(&buffer[..size])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn counter_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("rora-counter-{}-{}", std::process::id(), name))
+    }
+
+    /// A channel with a fresh counter file, removed again once it has been reserved.
+    fn channel(address: u8) -> SecureChannel {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let path = counter_path(&NEXT.fetch_add(1, Ordering::Relaxed).to_string());
+        let _ = std::fs::remove_file(&path);
+        let mut channel = SecureChannel::new(address);
+        channel.persist_counter(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        channel
+    }
+
+    fn pair(key: Key) -> (SecureChannel, SecureChannel) {
+        let mut a = channel(1);
+        let mut b = channel(2);
+        a.add_peer(2, key.clone());
+        b.add_peer(1, key);
+        (a, b)
+    }
+
+    fn keys() -> [Key; 2] {
+        [
+            Key::parse("aes128", "000102030405060708090a0b0c0d0e0f").unwrap(),
+            Key::parse("chacha20", &"42".repeat(32)).unwrap(),
+        ]
+    }
+
+    #[test]
+    fn seal_open() {
+        for key in keys() {
+            let (mut a, mut b) = pair(key);
+            let packet = a.seal(2, 0, b"hello").unwrap();
+            assert_eq!(packet.len(), HEADER_LEN + OVERHEAD + 5);
+            let (header, plaintext) = b.open(&packet).unwrap();
+            assert_eq!(header, Header { to: 2, from: 1, id: 0, flags: FLAG_SECURE });
+            assert_eq!(plaintext, b"hello");
+            // the reply uses the same key with the other address in the nonce
+            let reply = b.seal(1, 0, b"hi").unwrap();
+            assert_eq!(a.open(&reply).unwrap().1, b"hi");
+        }
+    }
+
+    #[test]
+    fn max_plaintext_fits_one_packet() {
+        let (mut a, _) = pair(keys()[0].clone());
+        assert_eq!(a.seal(2, 0, &[0; MAX_PLAINTEXT]).unwrap().len(), 255);
+        assert!(a.seal(2, 0, &[0; MAX_PLAINTEXT + 1]).is_err());
+    }
+
+    #[test]
+    fn tampering_is_rejected() {
+        for key in keys() {
+            let (mut a, mut b) = pair(key);
+            let packet = a.seal(2, 0, b"hello").unwrap();
+            // header, counter, ciphertext and tag are all authenticated
+            for i in [2, HEADER_LEN + 1, HEADER_LEN + COUNTER_LEN, packet.len() - 1] {
+                let mut tampered = packet.clone();
+                tampered[i] ^= 0x01;
+                assert!(b.open(&tampered).is_err(), "byte {} flipped", i);
+            }
+            // a rejected packet must not move the replay window
+            assert_eq!(b.open(&packet).unwrap().1, b"hello");
+        }
+    }
+
+    #[test]
+    fn wrong_key_is_rejected() {
+        let [aes, chacha] = keys();
+        let mut a = channel(1);
+        let mut b = channel(2);
+        a.add_peer(2, aes);
+        b.add_peer(1, chacha);
+        assert!(b.open(&a.seal(2, 0, b"hello").unwrap()).is_err());
+        assert!(SecureChannel::new(3).open(&a.seal(2, 0, b"hello").unwrap()).is_err());
+    }
+
+    #[test]
+    fn replays_are_rejected() {
+        let (mut a, mut b) = pair(keys()[1].clone());
+        let packets: Vec<Vec<u8>> = (0..REPLAY_WINDOW + 2).map(|_| a.seal(2, 0, b"x").unwrap()).collect();
+        b.open(&packets[1]).unwrap();
+        assert!(b.open(&packets[1]).is_err());
+        // late but inside the window, accepted once
+        b.open(&packets[0]).unwrap();
+        assert!(b.open(&packets[0]).is_err());
+
+        let last = packets.len() - 1;
+        b.open(&packets[last]).unwrap();
+        // the oldest counter still inside the window, then one just outside it
+        assert!(b.open(&packets[last - REPLAY_WINDOW as usize + 1]).is_ok());
+        let (mut c, mut d) = pair(keys()[1].clone());
+        let old = c.seal(2, 0, b"x").unwrap();
+        for _ in 0..REPLAY_WINDOW {
+            c.seal(2, 0, b"x").unwrap();
+        }
+        d.open(&c.seal(2, 0, b"x").unwrap()).unwrap();
+        assert!(d.open(&old).is_err());
+        assert!(b.open(&packets[last]).is_err());
+    }
+
+    #[test]
+    fn receive_checks_address() {
+        let (mut a, mut b) = pair(keys()[0].clone());
+        a.add_peer(3, keys()[0].clone());
+        let mut buffer = [0u8; 255];
+        let packet = a.seal(3, 0, b"hello").unwrap();
+        buffer[..packet.len()].copy_from_slice(&packet);
+        assert!(b.receive(&buffer, packet.len()).is_err());
+        let packet = a.seal(2, 0, b"hello").unwrap();
+        buffer[..packet.len()].copy_from_slice(&packet);
+        assert_eq!(b.receive(&buffer, packet.len()).unwrap().1, b"hello");
+    }
+
+    #[test]
+    fn counter_persists_across_restarts() {
+        let path = counter_path("restart");
+        let _ = std::fs::remove_file(&path);
+
+        let mut a = SecureChannel::new(1);
+        a.add_peer(2, keys()[0].clone());
+        a.persist_counter(&path).unwrap();
+        assert_eq!(a.counter(), 0);
+        for _ in 0..COUNTER_BLOCK + 1 {
+            a.seal(2, 0, b"x").unwrap();
+        }
+        let used = a.counter();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), (2 * COUNTER_BLOCK).to_string());
+
+        // a restart without a clean shutdown skips to the end of the reserved block
+        let mut restarted = SecureChannel::new(1);
+        restarted.persist_counter(&path).unwrap();
+        assert!(restarted.counter() >= used);
+        assert_eq!(restarted.counter(), 2 * COUNTER_BLOCK);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn sealing_requires_a_counter_file() {
+        let mut a = SecureChannel::new(1);
+        a.add_peer(2, keys()[0].clone());
+        assert!(a.seal(2, 0, b"hello").is_err());
+        assert_eq!(a.counter(), 0);
+    }
+
+    #[test]
+    fn corrupt_counter_file_is_rejected() {
+        let path = counter_path("corrupt");
+        std::fs::write(&path, "12x").unwrap();
+        assert!(SecureChannel::new(1).persist_counter(&path).is_err());
+        // the file is left for the operator to inspect instead of being reset
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "12x");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,254 @@
+use anyhow::{anyhow, Result};
+use serde_json::{json, Map, Value};
+
+use crate::register::{
+    dio_mapping_2, hop_channel, invert_iq, modem_stat, ocp, op_mode, pa_config, pa_dac, pa_ramp, tcxo, Lna,
+    ModemConfig1, ModemConfig2, ModemConfig3, Register, RegisterFields, IRQ,
+};
+use crate::rfm96w::FXOSC;
+
+/// The values of registers 0x01 to 0x7f at one moment, see `LoRa::dump_registers`. The FIFO at
+/// 0x00 is left out, reading it would move the FIFO pointer.
+#[derive(Clone)]
+pub struct Snapshot {
+    pub values: [Option<u8>; 0x80],
+}
+
+/// A register that differs between two snapshots.
+pub struct Difference {
+    pub address: u8,
+    pub before: Option<u8>,
+    pub after: Option<u8>,
+}
+
+fn irq_fields(value: u8) -> Value {
+    json!({
+        "rx_timeout": value & IRQ::IrqRxTimeoutMask.addr() != 0,
+        "rx_done": value & IRQ::IrqRxDoneMask.addr() != 0,
+        "payload_crc_error": value & IRQ::IrqPayloadCrcErrorMask.addr() != 0,
+        "valid_header": value & IRQ::IrqValidHeaderMask.addr() != 0,
+        "tx_done": value & IRQ::IrqTxDoneMask.addr() != 0,
+        "cad_done": value & IRQ::IrqCadDoneMask.addr() != 0,
+        "fhss_change_channel": value & IRQ::IrqFhssChangeChannelMask.addr() != 0,
+        "cad_detected": value & IRQ::IrqCadDetectedMask.addr() != 0,
+    })
+}
+
+/// Decodes the fields of a register, `null` for registers that are a plain value.
+pub fn fields(address: u8, value: u8) -> Value {
+    let Some(register) = Register::from_addr(address) else {
+        return Value::Null;
+    };
+    match register {
+        Register::RegOpMode => json!({
+            "long_range_mode": value & op_mode::LONG_RANGE_MODE != 0,
+            "access_shared_reg": value & op_mode::ACCESS_SHARED_REG != 0,
+            "low_frequency_mode": value & op_mode::LOW_FREQUENCY_MODE_ON != 0,
+            "mode": value & op_mode::MODE_MASK,
+        }),
+        Register::RegPaConfig => json!({
+            "pa_boost": value & pa_config::PA_SELECT != 0,
+            "max_power": (value & pa_config::MAX_POWER_MASK) >> pa_config::MAX_POWER_SHIFT,
+            "output_power": value & pa_config::OUTPUT_POWER_MASK,
+        }),
+        Register::RegPaRamp => json!({ "pa_ramp": value & pa_ramp::PA_RAMP_MASK }),
+        Register::RegOcp => json!({
+            "ocp_on": value & ocp::OCP_ON != 0,
+            "ocp_trim": value & ocp::OCP_TRIM_MASK,
+        }),
+        Register::RegLna => {
+            let lna = Lna::from_bits(value);
+            json!({ "gain": lna.gain, "boost_lf": lna.boost_lf, "boost_hf": lna.boost_hf })
+        }
+        Register::RegIrqFlags | Register::RegIrqFlagsMask => irq_fields(value),
+        Register::RegModemStat => json!({
+            "rx_coding_rate": (value & modem_stat::RX_CODING_RATE_MASK) >> modem_stat::RX_CODING_RATE_SHIFT,
+            "modem_clear": value & modem_stat::MODEM_CLEAR != 0,
+            "header_info_valid": value & modem_stat::HEADER_INFO_VALID != 0,
+            "rx_ongoing": value & modem_stat::RX_ONGOING != 0,
+            "signal_synchronized": value & modem_stat::SIGNAL_SYNCHRONIZED != 0,
+            "signal_detected": value & modem_stat::SIGNAL_DETECTED != 0,
+        }),
+        Register::RegPktSnrValue => json!({ "snr_db": value as i8 as f32 / 4.0 }),
+        Register::RegHopChannel => json!({
+            "pll_timeout": value & hop_channel::PLL_TIMEOUT != 0,
+            "crc_on_payload": value & hop_channel::CRC_ON_PAYLOAD != 0,
+            "fhss_present_channel": value & hop_channel::FHSS_PRESENT_CHANNEL_MASK,
+        }),
+        Register::RegModemConfig1 => {
+            let r = ModemConfig1::from_bits(value);
+            json!({
+                "bandwidth": r.bandwidth().hz(),
+                "coding_rate": format!("4/{}", r.coding_rate + 4),
+                "implicit_header": r.implicit_header,
+            })
+        }
+        Register::RegModemConfig2 => {
+            let r = ModemConfig2::from_bits(value);
+            json!({
+                "spreading_factor": r.spreading_factor,
+                "tx_continuous": r.tx_continuous,
+                "crc_on": r.crc_on,
+                "symb_timeout_msb": r.symb_timeout_msb,
+            })
+        }
+        Register::RegModemConfig3 => {
+            let r = ModemConfig3::from_bits(value);
+            json!({ "low_data_rate_optimize": r.low_data_rate_optimize, "agc_auto": r.agc_auto })
+        }
+        Register::RegInvertiq => json!({
+            "invert_iq_rx": value & invert_iq::INVERT_IQ_RX != 0,
+            "invert_iq_tx": value & invert_iq::INVERT_IQ_TX != 0,
+        }),
+        Register::RegDioMapping1 => json!({
+            "dio0": value >> 6,
+            "dio1": (value >> 4) & 0x03,
+            "dio2": (value >> 2) & 0x03,
+            "dio3": value & 0x03,
+        }),
+        Register::RegDioMapping2 => json!({
+            "dio4": value >> 6,
+            "dio5": (value >> 4) & 0x03,
+            "map_preamble_detect": value & dio_mapping_2::MAP_PREAMBLE_DETECT != 0,
+        }),
+        Register::RegTcxo => json!({ "tcxo_input_on": value & tcxo::TCXO_INPUT_ON != 0 }),
+        Register::RegPaDac => json!({ "pa_dac": value & pa_dac::PA_DAC_MASK }),
+        _ => Value::Null,
+    }
+}
+
+pub fn name(address: u8) -> Option<String> {
+    Register::from_addr(address).map(|r| format!("{:?}", r))
+}
+
+impl Snapshot {
+    /// Carrier frequency in hertz from the three `RegFrf` registers.
+    pub fn frequency(&self) -> Option<u64> {
+        let byte = |r: Register| self.values[r.addr() as usize].map(u64::from);
+        let frf = (byte(Register::RegFrfMsb)? << 16) | (byte(Register::RegFrfMid)? << 8) | byte(Register::RegFrfLsb)?;
+        Some((frf * FXOSC) >> 19)
+    }
+
+    /// `{"frequency": hz, "registers": {"0x01": {"name": ..., "value": "0x81", "fields": {...}}}}`,
+    /// registers without a name are included with a `null` name.
+    pub fn to_json(&self) -> Value {
+        let mut registers = Map::new();
+        for (address, value) in self.values.iter().enumerate() {
+            let Some(value) = *value else { continue };
+            registers.insert(
+                format!("0x{:02x}", address),
+                json!({
+                    "name": name(address as u8),
+                    "value": format!("0x{:02x}", value),
+                    "fields": fields(address as u8, value),
+                }),
+            );
+        }
+        json!({ "frequency": self.frequency(), "registers": registers })
+    }
+
+    /// Reads a snapshot written by `to_json`. Only the addresses and values are used.
+    pub fn from_json(json: &Value) -> Result<Self> {
+        let registers = json["registers"].as_object().ok_or_else(|| anyhow!("No registers in snapshot."))?;
+        let mut values = [None; 0x80];
+        for (address, register) in registers {
+            let parse = |hex: &str| u8::from_str_radix(hex.trim_start_matches("0x"), 16);
+            let address = parse(address).map_err(|e| anyhow!("Bad address {}: {}", address, e))?;
+            let value = register["value"].as_str().ok_or_else(|| anyhow!("No value for 0x{:02x}.", address))?;
+            let value = parse(value).map_err(|e| anyhow!("Bad value for 0x{:02x}: {}", address, e))?;
+            *values
+                .get_mut(address as usize)
+                .ok_or_else(|| anyhow!("Address 0x{:02x} out of range.", address))? = Some(value);
+        }
+        Ok(Snapshot { values })
+    }
+
+    /// Registers whose values differ. Unless `all` is set only configuration registers are
+    /// compared, status registers such as `RegIrqFlags` or `RegRssiValue` always differ.
+    pub fn diff(&self, other: &Snapshot, all: bool) -> Vec<Difference> {
+        (1..0x80u8)
+            .filter(|&address| all || crate::register::is_configuration(address))
+            .filter_map(|address| {
+                let before = self.values[address as usize];
+                let after = other.values[address as usize];
+                (before != after).then_some(Difference { address, before, after })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// LoRa mode in standby at 434 MHz, SF9, 125 kHz, with some IRQ flags set.
+    fn snapshot() -> Snapshot {
+        let mut values = [None; 0x80];
+        for (register, value) in [
+            (Register::RegOpMode, 0x81),
+            (Register::RegFrfMsb, 0x6c),
+            (Register::RegFrfMid, 0x80),
+            (Register::RegFrfLsb, 0x00),
+            (Register::RegIrqFlags, 0x48),
+            (Register::RegModemConfig1, 0x72),
+            (Register::RegModemConfig2, 0x94),
+        ] {
+            values[register.addr() as usize] = Some(value);
+        }
+        // an address without a name
+        values[0x7f] = Some(0x00);
+        Snapshot { values }
+    }
+
+    #[test]
+    fn json_round_trip() {
+        let json = snapshot().to_json();
+        assert_eq!(json["frequency"], 434_000_000);
+        let config2 = &json["registers"]["0x1e"];
+        assert_eq!(config2["name"], "RegModemConfig2");
+        assert_eq!(config2["value"], "0x94");
+        assert_eq!(config2["fields"]["spreading_factor"], 9);
+        assert_eq!(json["registers"]["0x1d"]["fields"]["bandwidth"], 125_000);
+        assert_eq!(json["registers"]["0x12"]["fields"]["tx_done"], true);
+        assert!(json["registers"]["0x7f"]["name"].is_null());
+        assert!(json["registers"].get("0x02").is_none());
+
+        // through text, as `regs dump` writes and `regs load` reads it
+        let text = serde_json::to_string_pretty(&json).unwrap();
+        let loaded = Snapshot::from_json(&serde_json::from_str(&text).unwrap()).unwrap();
+        assert_eq!(loaded.values, snapshot().values);
+        assert_eq!(loaded.frequency(), Some(434_000_000));
+    }
+
+    #[test]
+    fn bad_json_is_rejected() {
+        assert!(Snapshot::from_json(&json!({})).is_err());
+        assert!(Snapshot::from_json(&json!({"registers": {"0x80": {"value": "0x00"}}})).is_err());
+        assert!(Snapshot::from_json(&json!({"registers": {"0x01": {"value": "0x100"}}})).is_err());
+        assert!(Snapshot::from_json(&json!({"registers": {"0x01": {"value": 129}}})).is_err());
+        assert!(Snapshot::from_json(&json!({"registers": {"zz": {"value": "0x00"}}})).is_err());
+        // an incomplete frequency is left out
+        let partial = Snapshot::from_json(&json!({"registers": {"0x06": {"value": "0x6c"}}})).unwrap();
+        assert_eq!(partial.frequency(), None);
+    }
+
+    #[test]
+    fn diff_skips_status_registers() {
+        let before = snapshot();
+        let mut after = snapshot();
+        after.values[Register::RegModemConfig2.addr() as usize] = Some(0xc4);
+        after.values[Register::RegIrqFlags.addr() as usize] = Some(0x00);
+        after.values[Register::RegRssiValue.addr() as usize] = Some(0x2a);
+        after.values[Register::RegPaConfig.addr() as usize] = Some(0x8f);
+
+        let changed = |all: bool| -> Vec<(u8, Option<u8>, Option<u8>)> {
+            before.diff(&after, all).iter().map(|d| (d.address, d.before, d.after)).collect()
+        };
+        assert_eq!(changed(false), [(0x09, None, Some(0x8f)), (0x1e, Some(0x94), Some(0xc4))]);
+        assert_eq!(
+            changed(true),
+            [(0x09, None, Some(0x8f)), (0x12, Some(0x48), Some(0x00)), (0x1b, None, Some(0x2a)), (0x1e, Some(0x94), Some(0xc4))]
+        );
+        assert!(before.diff(&before, true).is_empty());
+    }
+}
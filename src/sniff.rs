@@ -0,0 +1,431 @@
+use anyhow::Result;
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt::Write as _;
+use std::io::{self, Read, Write};
+use std::time::{Duration, Instant};
+
+use crate::datagram::{Header, HEADER_LEN};
+use crate::register::Register;
+use crate::rfm96w::LoRa;
+
+const POLL_MS: i32 = 10;
+const REDRAW: Duration = Duration::from_millis(250);
+/// Samples kept per source for the sparklines.
+const HISTORY: usize = 24;
+const SPARK: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+const BANDWIDTHS: [i64; 10] = [7_800, 10_400, 15_600, 20_800, 31_250, 41_700, 62_500, 125_000, 250_000, 500_000];
+/// Sync word of public LoRaWAN networks.
+const LORAWAN_SYNC_WORD: u8 = 0x34;
+
+pub struct SniffConfig {
+    /// Frequency change per `f`/`F` keypress in hertz.
+    pub step: u64,
+}
+
+/// A header recognised in a received frame.
+enum Decoded {
+    RadioHead(Header),
+    LoRaWan { mtype: &'static str, dev_addr: u32, fcnt: u16, fport: Option<u8> },
+}
+
+impl Decoded {
+    fn source(&self) -> String {
+        match self {
+            Decoded::RadioHead(h) => format!("RH {}", h.from),
+            Decoded::LoRaWan { dev_addr, .. } => format!("LoRaWAN {:08x}", dev_addr),
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            Decoded::RadioHead(h) => {
+                format!("RadioHead to {} from {} id {} flags {:#04x}", h.to, h.from, h.id, h.flags)
+            }
+            Decoded::LoRaWan { mtype, dev_addr, fcnt, fport } => {
+                let port = fport.map_or("-".to_string(), |p| p.to_string());
+                format!("LoRaWAN {} DevAddr {:08x} FCnt {} FPort {}", mtype, dev_addr, fcnt, port)
+            }
+        }
+    }
+}
+
+/// Decodes LoRaWAN data frames (MHDR, FHDR, optional FPort, 4 byte MIC).
+fn decode_lorawan(data: &[u8]) -> Option<Decoded> {
+    let mhdr = *data.first()?;
+    // major version 0 (LoRaWAN R1) only
+    if mhdr & 0x03 != 0 || data.len() < 12 {
+        return None;
+    }
+    let mtype = match mhdr >> 5 {
+        2 => "UnconfirmedUp",
+        3 => "UnconfirmedDown",
+        4 => "ConfirmedUp",
+        5 => "ConfirmedDown",
+        _ => return None,
+    };
+    let dev_addr = u32::from_le_bytes([data[1], data[2], data[3], data[4]]);
+    let fopts_len = (data[5] & 0x0f) as usize;
+    let fcnt = u16::from_le_bytes([data[6], data[7]]);
+    let fhdr_end = 8 + fopts_len;
+    if data.len() < fhdr_end + 4 {
+        return None;
+    }
+    let fport = (data.len() > fhdr_end + 4).then(|| data[fhdr_end]);
+    Some(Decoded::LoRaWan { mtype, dev_addr, fcnt, fport })
+}
+
+fn decode(data: &[u8], sync_word: u8) -> Option<Decoded> {
+    if sync_word == LORAWAN_SYNC_WORD {
+        decode_lorawan(data)
+    } else if data.len() >= HEADER_LEN {
+        Header::from_bytes(data).ok().map(Decoded::RadioHead)
+    } else {
+        None
+    }
+}
+
+#[derive(Default)]
+struct Source {
+    packets: u64,
+    crc_errors: u64,
+    rssi: VecDeque<f32>,
+    snr: VecDeque<f32>,
+}
+
+impl Source {
+    fn record(&mut self, rssi: f32, snr: f32, crc_ok: bool) {
+        self.packets += 1;
+        self.crc_errors += !crc_ok as u64;
+        for (history, value) in [(&mut self.rssi, rssi), (&mut self.snr, snr)] {
+            if history.len() == HISTORY {
+                history.pop_front();
+            }
+            history.push_back(value);
+        }
+    }
+}
+
+/// Maps samples between `min` and `max` to block characters.
+fn sparkline(samples: &VecDeque<f32>, min: f32, max: f32) -> String {
+    samples
+        .iter()
+        .map(|&v| {
+            let level = ((v - min) / (max - min) * SPARK.len() as f32) as isize;
+            SPARK[level.clamp(0, SPARK.len() as isize - 1) as usize]
+        })
+        .collect()
+}
+
+fn hex_dump(data: &[u8], out: &mut String) {
+    for (row, chunk) in data.chunks(16).enumerate() {
+        let hex: Vec<String> = chunk.iter().map(|b| format!("{:02x}", b)).collect();
+        let ascii: String = chunk
+            .iter()
+            .map(|&b| if b.is_ascii_graphic() || b == b' ' { b as char } else { '.' })
+            .collect();
+        let _ = writeln!(out, "{:04x}  {:<47}  |{}|", row * 16, hex.join(" "), ascii);
+    }
+}
+
+struct LastPacket {
+    received: Instant,
+    data: Vec<u8>,
+    rssi: f32,
+    snr: f32,
+    crc_ok: bool,
+    decoded: Option<String>,
+}
+
+/// Puts the terminal into non-canonical mode without echo for single key presses, and restores
+/// it when dropped. Signals are disabled too, so Ctrl-C arrives as a key and the screen is
+/// restored on the way out.
+struct RawTerminal {
+    original: libc::termios,
+}
+
+impl RawTerminal {
+    fn enter() -> Result<Self> {
+        // SAFETY: termios is plain data filled in by tcgetattr before use.
+        unsafe {
+            let mut original = std::mem::zeroed();
+            if libc::tcgetattr(libc::STDIN_FILENO, &mut original) != 0 {
+                return Err(io::Error::last_os_error().into());
+            }
+            let mut raw = original;
+            raw.c_lflag &= !(libc::ICANON | libc::ECHO | libc::ISIG);
+            // reads return at once, with or without a key
+            raw.c_cc[libc::VMIN] = 0;
+            raw.c_cc[libc::VTIME] = 0;
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw);
+            // alternate screen, hidden cursor
+            print!("\x1b[?1049h\x1b[?25l");
+            Ok(RawTerminal { original })
+        }
+    }
+}
+
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
+        print!("\x1b[?25h\x1b[?1049l");
+        let _ = io::stdout().flush();
+        // SAFETY: restores the settings read in `enter`.
+        unsafe {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.original);
+        }
+    }
+}
+
+struct Sniffer {
+    started: Instant,
+    sources: BTreeMap<String, Source>,
+    packets: u64,
+    crc_errors: u64,
+    last: Option<LastPacket>,
+    status: String,
+}
+
+/// Receives everything on the configured channel and shows a live dashboard. Keys: `s`/`S`
+/// lower or raise the spreading factor, `b`/`B` the bandwidth, `f`/`F` the frequency by
+/// `config.step`, `c` clears the statistics and `q` quits.
+pub fn run(radio: &mut LoRa, config: &SniffConfig) -> Result<()> {
+    let _terminal = RawTerminal::enter()?;
+    let mut sniffer = Sniffer {
+        started: Instant::now(),
+        sources: BTreeMap::new(),
+        packets: 0,
+        crc_errors: 0,
+        last: None,
+        status: String::new(),
+    };
+    let mut stdin = io::stdin();
+    let mut drawn = Instant::now() - REDRAW;
+
+    loop {
+        let mut keys = [0u8; 16];
+        let len = stdin.read(&mut keys).unwrap_or(0);
+        for &key in &keys[..len] {
+            match key {
+                b'q' | 0x03 => return Ok(()),
+                b'c' => {
+                    sniffer.sources.clear();
+                    sniffer.packets = 0;
+                    sniffer.crc_errors = 0;
+                    sniffer.last = None;
+                    sniffer.started = Instant::now();
+                }
+                _ => sniffer.status = change_setting(radio, key, config.step)?,
+            }
+            drawn = Instant::now() - REDRAW;
+        }
+
+        if let Ok(size) = radio.poll_irq(Some(POLL_MS)) {
+            let crc_ok = !radio.crc_error();
+            let buffer = radio.read_packet()?;
+            let rssi = radio.get_packet_rssi()?;
+            let snr = radio.get_packet_snr()?;
+            let sync_word = radio.read_register(Register::RegSyncWord.addr())?;
+            let data = buffer[..size].to_vec();
+            // a frame with a bad CRC would only produce bogus sources
+            let decoded = if crc_ok { decode(&data, sync_word) } else { None };
+            let source = decoded.as_ref().map_or("unknown".to_string(), Decoded::source);
+            sniffer.sources.entry(source).or_default().record(rssi, snr, crc_ok);
+            sniffer.packets += 1;
+            sniffer.crc_errors += !crc_ok as u64;
+            sniffer.last = Some(LastPacket {
+                received: Instant::now(),
+                data,
+                rssi,
+                snr,
+                crc_ok,
+                decoded: decoded.as_ref().map(Decoded::describe),
+            });
+            drawn = Instant::now() - REDRAW;
+        }
+
+        if drawn.elapsed() >= REDRAW {
+            print!("{}", sniffer.draw(radio)?);
+            io::stdout().flush()?;
+            drawn = Instant::now();
+        }
+    }
+}
+
+/// Applies a settings hotkey and returns a status line describing the result.
+fn change_setting(radio: &mut LoRa, key: u8, step: u64) -> Result<String> {
+    match key {
+        b's' | b'S' => {
+            // SF6 needs implicit header mode, which would hide every other frame
+            let sf = radio.get_spreading_factor()?;
+            let sf = if key == b's' { sf.saturating_sub(1).max(7) } else { (sf + 1).min(12) };
+            radio.set_spreading_factor(sf)?;
+            Ok(format!("Spreading factor set to {}.", sf))
+        }
+        b'b' | b'B' => {
+            let bw = radio.get_signal_bandwidth()?;
+            let index = BANDWIDTHS.iter().position(|&b| b == bw).unwrap_or(7);
+            let index = if key == b'b' { index.saturating_sub(1) } else { (index + 1).min(BANDWIDTHS.len() - 1) };
+            radio.set_signal_bandwidth(BANDWIDTHS[index])?;
+            Ok(format!("Bandwidth set to {} Hz.", BANDWIDTHS[index]))
+        }
+        b'f' | b'F' => {
+            let frequency = radio.get_frequency();
+            let frequency = if key == b'f' { frequency.saturating_sub(step) } else { frequency + step };
+            radio.set_frequency_hz(frequency)?;
+            Ok(format!("Frequency set to {:.3} MHz.", frequency as f64 / 1e6))
+        }
+        _ => Ok(String::new()),
+    }
+}
+
+impl Sniffer {
+    fn draw(&self, radio: &mut LoRa) -> Result<String> {
+        let mut out = String::from("\x1b[H\x1b[2J");
+        let _ = writeln!(
+            out,
+            "rora sniff  {:.3} MHz  SF{}  BW {} Hz  CR 4/{}  sync {:#04x}",
+            radio.get_frequency() as f64 / 1e6,
+            radio.get_spreading_factor()?,
+            radio.get_signal_bandwidth()?,
+            radio.get_coding_rate_4()?,
+            radio.read_register(Register::RegSyncWord.addr())?,
+        );
+        let _ = writeln!(out, "[s/S] SF  [b/B] bandwidth  [f/F] frequency  [c] clear  [q] quit");
+        let error_rate = if self.packets > 0 { self.crc_errors as f64 * 100.0 / self.packets as f64 } else { 0.0 };
+        let _ = writeln!(
+            out,
+            "packets {}  crc errors {} ({:.1}%)  running {}s",
+            self.packets,
+            self.crc_errors,
+            error_rate,
+            self.started.elapsed().as_secs()
+        );
+        let _ = writeln!(out, "{}\n", self.status);
+
+        let _ = writeln!(out, "{:<18} {:>7} {:>7}  {:<34}  snr", "source", "packets", "crc err", "rssi");
+        for (name, source) in &self.sources {
+            let _ = writeln!(
+                out,
+                "{:<18} {:>7} {:>7}  {:<width$} {:>5.0} dBm  {:<width$} {:>4.1} dB",
+                name,
+                source.packets,
+                source.crc_errors,
+                sparkline(&source.rssi, -130.0, -30.0),
+                source.rssi.back().copied().unwrap_or_default(),
+                sparkline(&source.snr, -20.0, 15.0),
+                source.snr.back().copied().unwrap_or_default(),
+                width = HISTORY
+            );
+        }
+
+        if let Some(last) = &self.last {
+            let _ = writeln!(
+                out,
+                "\nlast packet {}s ago  {} bytes  rssi {:.0} dBm  snr {:.2} dB  crc {}",
+                last.received.elapsed().as_secs(),
+                last.data.len(),
+                last.rssi,
+                last.snr,
+                if last.crc_ok { "ok" } else { "error" }
+            );
+            if let Some(decoded) = &last.decoded {
+                let _ = writeln!(out, "{}", decoded);
+            }
+            hex_dump(&last.data, &mut out);
+        }
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A LoRaWAN data frame for DevAddr 0x26011234 with FCnt 0x0102, `rest` being the FOpts,
+    /// FPort and payload. The MIC is not checked.
+    fn frame(mhdr: u8, fopts_len: u8, rest: &[u8]) -> Vec<u8> {
+        let mut data = vec![mhdr, 0x34, 0x12, 0x01, 0x26, 0x80 | fopts_len, 0x02, 0x01];
+        data.extend_from_slice(rest);
+        data.extend_from_slice(&[0xde, 0xad, 0xbe, 0xef]);
+        data
+    }
+
+    fn describe(data: &[u8]) -> Option<String> {
+        decode(data, LORAWAN_SYNC_WORD).map(|d| d.describe())
+    }
+
+    #[test]
+    fn lorawan_fport_follows_the_fopts() {
+        assert_eq!(
+            describe(&frame(0x40, 0, &[10, 0xaa])).unwrap(),
+            "LoRaWAN UnconfirmedUp DevAddr 26011234 FCnt 258 FPort 10"
+        );
+        // three bytes of MAC commands in FOpts, the FPort comes after them
+        assert_eq!(
+            describe(&frame(0x80, 3, &[0x02, 0x03, 0x04, 7, 0xaa])).unwrap(),
+            "LoRaWAN ConfirmedUp DevAddr 26011234 FCnt 258 FPort 7"
+        );
+        assert_eq!(
+            describe(&frame(0xa0, 0, &[0])).unwrap(),
+            "LoRaWAN ConfirmedDown DevAddr 26011234 FCnt 258 FPort 0"
+        );
+    }
+
+    #[test]
+    fn lorawan_without_fport() {
+        assert_eq!(
+            describe(&frame(0x60, 0, &[])).unwrap(),
+            "LoRaWAN UnconfirmedDown DevAddr 26011234 FCnt 258 FPort -"
+        );
+        assert_eq!(
+            describe(&frame(0x40, 2, &[0x02, 0x03])).unwrap(),
+            "LoRaWAN UnconfirmedUp DevAddr 26011234 FCnt 258 FPort -"
+        );
+    }
+
+    #[test]
+    fn short_and_foreign_lorawan_frames_are_rejected() {
+        // FOpts longer than the frame
+        assert!(describe(&frame(0x40, 4, &[0x02, 0x03])).is_none());
+        // FHDR and MIC need 12 bytes
+        assert!(describe(&frame(0x40, 0, &[])[..11]).is_none());
+        assert!(describe(&[]).is_none());
+        // join request, proprietary and an unknown major version
+        for mhdr in [0x00, 0xe0, 0x41] {
+            assert!(describe(&frame(mhdr, 0, &[1, 2])).is_none(), "{:#04x}", mhdr);
+        }
+    }
+
+    #[test]
+    fn radiohead_headers_on_other_sync_words() {
+        let decoded = decode(&[2, 1, 9, 0x08, b'x'], 0x12).unwrap();
+        assert_eq!(decoded.describe(), "RadioHead to 2 from 1 id 9 flags 0x08");
+        assert_eq!(decoded.source(), "RH 1");
+        assert!(decode(&[2, 1, 9], 0x12).is_none());
+    }
+
+    #[test]
+    fn sparkline_levels() {
+        let samples: VecDeque<f32> = [-120.0, -100.0, -90.0, -80.0, -61.0, -60.0, -20.0].into();
+        // values outside the range are clamped to the lowest and highest block
+        assert_eq!(sparkline(&samples, -100.0, -60.0), "▁▁▃▅███");
+        assert_eq!(sparkline(&VecDeque::new(), -100.0, -60.0), "");
+    }
+
+    #[test]
+    fn hex_dump_rows() {
+        let mut out = String::new();
+        let data: Vec<u8> = (0x3c..0x4d).collect();
+        hex_dump(&data, &mut out);
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                "0000  3c 3d 3e 3f 40 41 42 43 44 45 46 47 48 49 4a 4b  |<=>?@ABCDEFGHIJK|",
+                "0010  4c                                               |L|",
+            ]
+        );
+        out.clear();
+        hex_dump(&[0x00, b' ', 0x7f, 0xff], &mut out);
+        assert_eq!(out, format!("0000  00 20 7f ff{}  |. ..|\n", " ".repeat(36)));
+    }
+}
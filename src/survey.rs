@@ -0,0 +1,140 @@
+use anyhow::{anyhow, Result};
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::rfm96w::{LoRa, RadioMode};
+
+/// Waterfall shades from quiet to loud.
+const SHADES: &[u8] = b" .:-=+*#%@";
+/// Channels listed as quietest at the end of a survey.
+const QUIETEST: usize = 5;
+
+pub struct SurveyConfig {
+    /// First and last frequency in hertz.
+    pub start: u64,
+    pub stop: u64,
+    pub step: u64,
+    /// RSSI readings per frequency.
+    pub samples: u32,
+    /// Wait after retuning before the first reading, the RSSI needs a few symbols to settle.
+    pub settle: Duration,
+    /// Passes over the range, `None` sweeps until killed.
+    pub sweeps: Option<u32>,
+    /// Writes one CSV line per frequency and sweep, `-` for stdout instead of the waterfall.
+    pub csv: Option<PathBuf>,
+    /// RSSI mapped to the quietest and loudest shade of the waterfall, in dBm.
+    pub floor: f32,
+    pub ceiling: f32,
+}
+
+/// Readings at one frequency during one sweep.
+struct Reading {
+    mean: f32,
+    max: f32,
+    wideband: u8,
+}
+
+fn sample(radio: &mut LoRa, frequency: u64, config: &SurveyConfig) -> Result<Reading> {
+    // retune in standby, the synthesizer restarts when RX is entered again
+    radio.set_mode(RadioMode::Stdby)?;
+    radio.set_frequency_hz(frequency)?;
+    radio.set_mode(RadioMode::RxContinuous)?;
+    spin_sleep::sleep(config.settle);
+
+    let mut sum = 0.0;
+    let mut max = f32::MIN;
+    for _ in 0..config.samples {
+        let rssi = radio.get_rssi()?;
+        sum += rssi;
+        max = max.max(rssi);
+        spin_sleep::sleep(Duration::from_micros(500));
+    }
+    Ok(Reading {
+        mean: sum / config.samples as f32,
+        max,
+        wideband: radio.get_rssi_wideband()?,
+    })
+}
+
+fn shade(rssi: f32, config: &SurveyConfig) -> char {
+    let level = ((rssi - config.floor) / (config.ceiling - config.floor) * SHADES.len() as f32) as isize;
+    SHADES[level.clamp(0, SHADES.len() as isize - 1) as usize] as char
+}
+
+/// Steps the synthesizer across the range in RX continuous mode and records the instantaneous
+/// and wideband RSSI at every step. Each sweep is drawn as one waterfall row of mean RSSI, or
+/// written as CSV. Finished surveys end with the quietest channels by average RSSI.
+pub fn run(radio: &mut LoRa, config: &SurveyConfig) -> Result<()> {
+    if config.step == 0 || config.stop < config.start || config.samples == 0 {
+        return Err(anyhow!("Empty survey range."));
+    }
+    let original = radio.get_frequency();
+    let frequencies: Vec<u64> = (config.start..=config.stop).step_by(config.step as usize).collect();
+
+    let mut csv: Option<Box<dyn Write>> = match &config.csv {
+        Some(path) if path.as_os_str() == "-" => Some(Box::new(io::stdout())),
+        Some(path) => Some(Box::new(File::create(path)?)),
+        None => None,
+    };
+    let waterfall = config.csv.as_ref().is_none_or(|path| path.as_os_str() != "-");
+    if let Some(csv) = &mut csv {
+        writeln!(csv, "timestamp,sweep,frequency,rssi_mean,rssi_max,wideband")?;
+    }
+    if waterfall {
+        println!(
+            "{} steps from {:.3} to {:.3} MHz, '{}' is {} dBm or less, '{}' is {} dBm or more.",
+            frequencies.len(),
+            config.start as f64 / 1e6,
+            frequencies[frequencies.len() - 1] as f64 / 1e6,
+            SHADES[0] as char,
+            config.floor,
+            SHADES[SHADES.len() - 1] as char,
+            config.ceiling
+        );
+    }
+
+    let mut totals = vec![0.0; frequencies.len()];
+    let mut sweep = 0;
+    while config.sweeps.is_none_or(|sweeps| sweep < sweeps) {
+        let mut row = String::with_capacity(frequencies.len());
+        for (i, &frequency) in frequencies.iter().enumerate() {
+            let reading = sample(radio, frequency, config)?;
+            totals[i] += reading.mean;
+            row.push(shade(reading.mean, config));
+            if let Some(csv) = &mut csv {
+                let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis();
+                writeln!(
+                    csv,
+                    "{},{},{},{:.1},{:.1},{}",
+                    timestamp, sweep, frequency, reading.mean, reading.max, reading.wideband
+                )?;
+            }
+        }
+        if let Some(csv) = &mut csv {
+            csv.flush()?;
+        }
+        if waterfall {
+            println!("|{}|", row);
+        }
+        sweep += 1;
+    }
+
+    radio.set_mode(RadioMode::Stdby)?;
+    radio.set_frequency_hz(original)?;
+
+    if waterfall {
+        let mut channels: Vec<(u64, f32)> = frequencies
+            .iter()
+            .zip(&totals)
+            .map(|(&frequency, &total)| (frequency, total / sweep as f32))
+            .collect();
+        channels.sort_by(|a, b| a.1.total_cmp(&b.1));
+        println!("Quietest channels:");
+        for (frequency, rssi) in channels.iter().take(QUIETEST) {
+            println!("  {:.3} MHz  {:.1} dBm", *frequency as f64 / 1e6, rssi);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,532 @@
+use anyhow::{anyhow, Result};
+use sha2::{Digest, Sha256};
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::datagram::{Header, FLAG_TRANSFER, HEADER_LEN, MAX_PAYLOAD};
+use crate::netif::Link;
+
+// Message types, the first byte after the datagram header.
+const MANIFEST: u8 = 0x01;
+const NACK: u8 = 0x02;
+const DATA: u8 = 0x03;
+const POLL: u8 = 0x04;
+const DONE: u8 = 0x05;
+
+const DATA_HEADER_LEN: usize = 9;
+/// Largest block that fits in a `DATA` packet.
+pub const MAX_BLOCK_SIZE: usize = MAX_PAYLOAD - DATA_HEADER_LEN;
+/// Blocks covered by one NACK bitmap, and so sent per window.
+pub const WINDOW: u32 = 64;
+const MAX_NAME: usize = MAX_PAYLOAD - 42;
+
+/// Settings shared by both ends of a transfer.
+pub struct TransferConfig {
+    pub address: u8,
+    pub peer: u8,
+    pub block_size: usize,
+    /// How long to wait for a reply before asking again.
+    pub timeout: Duration,
+    /// Unanswered requests before the sender gives up.
+    pub retries: u32,
+}
+
+/// Name, size and hash of the file being transferred. The first four bytes of the hash
+/// identify the transfer, so a restarted sender resumes where the receiver left off.
+#[derive(Clone)]
+struct Manifest {
+    name: String,
+    size: u32,
+    block_size: usize,
+    sha256: [u8; 32],
+}
+
+impl Manifest {
+    fn id(&self) -> [u8; 4] {
+        [self.sha256[0], self.sha256[1], self.sha256[2], self.sha256[3]]
+    }
+
+    fn blocks(&self) -> u32 {
+        (self.size as usize).div_ceil(self.block_size) as u32
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![MANIFEST];
+        bytes.extend_from_slice(&self.id());
+        bytes.extend_from_slice(&self.size.to_be_bytes());
+        bytes.push(self.block_size as u8);
+        bytes.extend_from_slice(&self.sha256);
+        bytes.extend_from_slice(self.name.as_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < 42 {
+            return Err(anyhow!("Manifest too short."));
+        }
+        let name = String::from_utf8(bytes[42..].to_vec())?;
+        // never let a peer write outside the target directory
+        if name.is_empty() || name.contains('/') || name == "." || name == ".." {
+            return Err(anyhow!("Bad file name in manifest."));
+        }
+        Ok(Manifest {
+            name,
+            size: u32::from_be_bytes(bytes[5..9].try_into()?),
+            block_size: (bytes[9] as usize).max(1),
+            sha256: bytes[10..42].try_into()?,
+        })
+    }
+}
+
+fn hash_file(path: &Path) -> Result<[u8; 32]> {
+    let mut hasher = Sha256::new();
+    let mut file = File::open(path)?;
+    let mut buffer = [0u8; 4096];
+    loop {
+        let len = file.read(&mut buffer)?;
+        if len == 0 {
+            break;
+        }
+        hasher.update(&buffer[..len]);
+    }
+    Ok(hasher.finalize().into())
+}
+
+fn send<L: Link>(link: &mut L, config: &TransferConfig, message: &[u8]) -> Result<()> {
+    let header = Header {
+        to: config.peer,
+        from: config.address,
+        id: message[0],
+        flags: FLAG_TRANSFER,
+    };
+    let mut packet = header.to_bytes().to_vec();
+    packet.extend_from_slice(message);
+    link.send_packet(&packet)
+}
+
+/// Waits up to `timeout` for a transfer message from `from` (any sender if `None`), returning
+/// the sender and the message.
+fn receive<L: Link>(link: &mut L, address: u8, from: Option<u8>, timeout: Duration) -> Result<Option<(u8, Vec<u8>)>> {
+    let start = Instant::now();
+    while start.elapsed() < timeout {
+        let remaining = (timeout - start.elapsed()).as_millis().max(1) as i32;
+        let Some(packet) = link.receive_packet(remaining)? else {
+            continue;
+        };
+        let header = match Header::from_bytes(&packet) {
+            Ok(header) => header,
+            Err(_) => continue,
+        };
+        if header.to == address
+            && header.flags & FLAG_TRANSFER != 0
+            && from.is_none_or(|f| f == header.from)
+            && packet.len() > HEADER_LEN
+        {
+            return Ok(Some((header.from, packet[HEADER_LEN..].to_vec())));
+        }
+    }
+    Ok(None)
+}
+
+/// Sends `request` until a reply for transfer `id` arrives, giving up after `retries`.
+fn request<L: Link>(link: &mut L, config: &TransferConfig, request: &[u8], id: [u8; 4]) -> Result<Vec<u8>> {
+    for _ in 0..config.retries {
+        send(link, config, request)?;
+        let start = Instant::now();
+        while let Some((_, reply)) = receive(link, config.address, Some(config.peer), config.timeout.saturating_sub(start.elapsed()))? {
+            if reply.len() >= 5 && reply[1..5] == id && (reply[0] == NACK || reply[0] == DONE) {
+                return Ok(reply);
+            }
+        }
+    }
+    Err(anyhow!("No reply from {} after {} tries.", config.peer, config.retries))
+}
+
+/// Sends a file to `config.peer`. Blocks are sent a window at a time; the receiver answers
+/// each window with a NACK of the blocks it is still missing, which are sent again. The
+/// transfer ends when the receiver reports the SHA-256 of the reassembled file matched.
+pub fn send_file<L: Link>(link: &mut L, config: &TransferConfig, path: &Path) -> Result<()> {
+    let name = path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .ok_or_else(|| anyhow!("Bad file name."))?
+        .to_string();
+    if name.len() > MAX_NAME {
+        return Err(anyhow!("File name longer than {} bytes.", MAX_NAME));
+    }
+    let size = fs::metadata(path)?.len();
+    let manifest = Manifest {
+        name,
+        size: u32::try_from(size).map_err(|_| anyhow!("File too large."))?,
+        block_size: config.block_size.clamp(1, MAX_BLOCK_SIZE),
+        sha256: hash_file(path)?,
+    };
+    let id = manifest.id();
+    let mut file = File::open(path)?;
+    println!("Sending {} ({} bytes, {} blocks).", manifest.name, size, manifest.blocks());
+
+    let mut reply = request(link, config, &manifest.to_bytes(), id)?;
+    loop {
+        match reply[0] {
+            DONE if reply.get(5) == Some(&0) => {
+                println!("Transfer complete, hash verified.");
+                return Ok(());
+            }
+            DONE => return Err(anyhow!("Receiver reports a hash mismatch.")),
+            _ => {}
+        }
+        if reply.len() < 9 {
+            return Err(anyhow!("Short NACK."));
+        }
+        let base = u32::from_be_bytes(reply[5..9].try_into()?);
+        let bitmap = &reply[9..];
+        let missing = (0..WINDOW)
+            .filter(|&i| bitmap.get(i as usize / 8).is_some_and(|b| b & (1 << (i % 8)) != 0))
+            .map(|i| base + i)
+            .filter(|&block| block < manifest.blocks());
+        let mut sent = 0;
+        for block in missing {
+            let offset = block as u64 * manifest.block_size as u64;
+            let mut data = vec![0u8; manifest.block_size.min((size - offset) as usize)];
+            file.seek(SeekFrom::Start(offset))?;
+            file.read_exact(&mut data)?;
+            let mut message = vec![DATA];
+            message.extend_from_slice(&id);
+            message.extend_from_slice(&block.to_be_bytes());
+            message.extend_from_slice(&data);
+            send(link, config, &message)?;
+            sent += 1;
+        }
+        println!("Block {}: sent {} blocks.", base, sent);
+
+        let mut poll = vec![POLL];
+        poll.extend_from_slice(&id);
+        reply = request(link, config, &poll, id)?;
+    }
+}
+
+/// A file being received, with the blocks received so far. The partial file and its block
+/// map are kept on disk next to the target so an interrupted transfer can resume.
+struct Incoming {
+    manifest: Manifest,
+    peer: u8,
+    file: File,
+    received: Vec<bool>,
+    part_path: PathBuf,
+    map_path: PathBuf,
+}
+
+impl Incoming {
+    fn open(dir: &Path, manifest: Manifest, peer: u8) -> Result<Self> {
+        let part_path = dir.join(format!("{}.part", manifest.name));
+        let map_path = dir.join(format!("{}.part.map", manifest.name));
+        let blocks = manifest.blocks() as usize;
+        // the map starts with the transfer id, a different file with the same name starts over
+        let received = match fs::read(&map_path) {
+            Ok(map) if map.len() == 4 + blocks.div_ceil(8) && map[..4] == manifest.id() => (0..blocks)
+                .map(|i| map[4 + i / 8] & (1 << (i % 8)) != 0)
+                .collect(),
+            _ => vec![false; blocks],
+        };
+        let file = OpenOptions::new().create(true).truncate(false).read(true).write(true).open(&part_path)?;
+        file.set_len(manifest.size as u64)?;
+        let resumed = received.iter().filter(|&&r| r).count();
+        if resumed > 0 {
+            println!("Resuming {}, {} of {} blocks on disk.", manifest.name, resumed, blocks);
+        }
+        Ok(Incoming { manifest, peer, file, received, part_path, map_path })
+    }
+
+    fn save_map(&self) -> Result<()> {
+        let mut map = self.manifest.id().to_vec();
+        map.resize(4 + self.received.len().div_ceil(8), 0);
+        for (i, _) in self.received.iter().enumerate().filter(|(_, &r)| r) {
+            map[4 + i / 8] |= 1 << (i % 8);
+        }
+        fs::write(&self.map_path, map)?;
+        Ok(())
+    }
+
+    fn write_block(&mut self, block: u32, data: &[u8]) -> Result<()> {
+        let Some(received) = self.received.get_mut(block as usize) else {
+            return Err(anyhow!("Block {} out of range.", block));
+        };
+        // only the last block may be short, anything else is left missing to be NACKed
+        let offset = block as usize * self.manifest.block_size;
+        let expected = self.manifest.block_size.min(self.manifest.size as usize - offset);
+        if data.len() != expected {
+            return Err(anyhow!("Block {} is {} bytes, expected {}.", block, data.len(), expected));
+        }
+        self.file.seek(SeekFrom::Start(offset as u64))?;
+        self.file.write_all(data)?;
+        *received = true;
+        Ok(())
+    }
+
+    /// Builds a NACK for the first window that still has missing blocks.
+    fn nack(&self) -> Vec<u8> {
+        let base = self.received.iter().position(|&r| !r).unwrap_or(0) as u32;
+        let mut message = vec![NACK];
+        message.extend_from_slice(&self.manifest.id());
+        message.extend_from_slice(&base.to_be_bytes());
+        let mut bitmap = [0u8; WINDOW as usize / 8];
+        for i in 0..WINDOW {
+            if self.received.get((base + i) as usize) == Some(&false) {
+                bitmap[i as usize / 8] |= 1 << (i % 8);
+            }
+        }
+        message.extend_from_slice(&bitmap);
+        message
+    }
+
+    fn is_complete(&self) -> bool {
+        self.received.iter().all(|&r| r)
+    }
+
+    /// Checks the hash and moves the file into place. On a mismatch everything is discarded.
+    fn finish(self, dir: &Path) -> Result<bool> {
+        drop(self.file);
+        let ok = hash_file(&self.part_path)? == self.manifest.sha256;
+        if ok {
+            fs::rename(&self.part_path, dir.join(&self.manifest.name))?;
+        } else {
+            fs::remove_file(&self.part_path)?;
+        }
+        let _ = fs::remove_file(&self.map_path);
+        Ok(ok)
+    }
+}
+
+fn done(id: [u8; 4], ok: bool) -> Vec<u8> {
+    let mut message = vec![DONE];
+    message.extend_from_slice(&id);
+    message.push(!ok as u8);
+    message
+}
+
+/// Receives files into `dir` until `count` files have been verified, or forever if `None`.
+pub fn receive_files<L: Link>(link: &mut L, config: &TransferConfig, dir: &Path, count: Option<usize>) -> Result<()> {
+    let mut incoming: Option<Incoming> = None;
+    // id of the last completed transfer, its DONE is repeated if the sender missed it
+    let mut completed: Option<([u8; 4], bool, u8)> = None;
+    let mut finished = 0;
+    while count.is_none_or(|c| finished < c) {
+        let Some((from, message)) = receive(link, config.address, None, config.timeout)? else {
+            continue;
+        };
+        if message.len() < 5 {
+            continue;
+        }
+        let id: [u8; 4] = message[1..5].try_into()?;
+        let reply_config = TransferConfig { peer: from, ..*config };
+
+        if let Some((done_id, ok, peer)) = completed {
+            if done_id == id && peer == from && message[0] != DATA {
+                send(link, &reply_config, &done(id, ok))?;
+                continue;
+            }
+        }
+
+        match message[0] {
+            MANIFEST => {
+                let manifest = match Manifest::from_bytes(&message) {
+                    Ok(manifest) => manifest,
+                    Err(e) => {
+                        println!("Rejected manifest: {}", e);
+                        continue;
+                    }
+                };
+                if incoming.as_ref().is_none_or(|i| i.manifest.id() != id || i.peer != from) {
+                    if let Some(previous) = &incoming {
+                        previous.save_map()?;
+                    }
+                    println!("Receiving {} ({} bytes) from {}.", manifest.name, manifest.size, from);
+                    incoming = Some(Incoming::open(dir, manifest, from)?);
+                }
+                let current = incoming.as_ref().unwrap();
+                send(link, &reply_config, &current.nack())?;
+            }
+            DATA if message.len() > DATA_HEADER_LEN => {
+                if let Some(current) = incoming.as_mut().filter(|i| i.manifest.id() == id && i.peer == from) {
+                    let block = u32::from_be_bytes(message[5..9].try_into()?);
+                    if let Err(e) = current.write_block(block, &message[DATA_HEADER_LEN..]) {
+                        println!("{}", e);
+                    }
+                }
+            }
+            POLL => {
+                let Some(current) = incoming.take_if(|i| i.manifest.id() == id && i.peer == from) else {
+                    continue;
+                };
+                if current.is_complete() {
+                    let name = current.manifest.name.clone();
+                    let ok = current.finish(dir)?;
+                    println!("{} {}.", name, if ok { "received, hash verified" } else { "failed the hash check" });
+                    send(link, &reply_config, &done(id, ok))?;
+                    completed = Some((id, ok, from));
+                    finished += ok as usize;
+                } else {
+                    current.save_map()?;
+                    send(link, &reply_config, &current.nack())?;
+                    incoming = Some(current);
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::netif::SimRadio;
+    use std::collections::HashSet;
+    use std::thread;
+
+    /// The sending end, with `fault` dropping (returning false) or mangling what it sends.
+    struct Faulty<F> {
+        radio: SimRadio,
+        fault: F,
+        /// Every DATA block handed to the link, in order.
+        blocks: Vec<u32>,
+    }
+
+    impl<F: FnMut(&mut Vec<u8>) -> bool> Link for Faulty<F> {
+        fn send_packet(&mut self, packet: &[u8]) -> Result<()> {
+            let mut packet = packet.to_vec();
+            if let Some(block) = data_block(&packet) {
+                self.blocks.push(block);
+            }
+            if (self.fault)(&mut packet) {
+                self.radio.send_packet(&packet)?;
+            }
+            Ok(())
+        }
+
+        fn receive_packet(&mut self, timeout_ms: i32) -> Result<Option<Vec<u8>>> {
+            self.radio.receive_packet(timeout_ms)
+        }
+    }
+
+    fn data_block(packet: &[u8]) -> Option<u32> {
+        (packet[HEADER_LEN] == DATA).then(|| u32::from_be_bytes(packet[HEADER_LEN + 5..HEADER_LEN + 9].try_into().unwrap()))
+    }
+
+    fn config(address: u8, peer: u8) -> TransferConfig {
+        TransferConfig { address, peer, block_size: 16, timeout: Duration::from_millis(100), retries: 3 }
+    }
+
+    /// An empty directory for one test.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rora-transfer-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// 100 blocks of 16 bytes and a short last one, so the transfer takes two windows.
+    fn source_file(dir: &Path) -> (PathBuf, Vec<u8>) {
+        let contents: Vec<u8> = (0..1610u32).map(|i| (i * 7 % 251) as u8).collect();
+        let path = dir.join("data.bin");
+        fs::write(&path, &contents).unwrap();
+        (path, contents)
+    }
+
+    /// Sends `path` from node 1 to a receiver on node 2 writing into `dir`. Returns the
+    /// result of both ends and the blocks that were sent.
+    fn transfer<F>(path: &Path, dir: &Path, fault: F) -> (Result<()>, Result<()>, Vec<u32>)
+    where
+        F: FnMut(&mut Vec<u8>) -> bool,
+    {
+        let (a, mut b) = SimRadio::pair();
+        let dir = dir.to_path_buf();
+        let receiver = thread::spawn(move || receive_files(&mut b, &config(2, 1), &dir, Some(1)));
+        let mut sender = Faulty { radio: a, fault, blocks: Vec::new() };
+        let sent = send_file(&mut sender, &config(1, 2), path);
+        // dropping the sender's radio ends a receiver that is still waiting
+        drop(sender.radio);
+        (sent, receiver.join().unwrap(), sender.blocks)
+    }
+
+    #[test]
+    fn lost_and_short_blocks_are_sent_again() {
+        let dir = temp_dir("lost");
+        let (path, contents) = source_file(&dir);
+        let received = dir.join("received");
+        fs::create_dir(&received).unwrap();
+
+        // the first copy of blocks 3 and 70 is lost, one in each window, and block 5 is short
+        let mut faults = HashSet::new();
+        let (sent, receiver, blocks) = transfer(&path, &received, |packet| match data_block(packet) {
+            Some(block @ (3 | 70)) => !faults.insert(block),
+            Some(5) if faults.insert(5) => {
+                packet.pop();
+                true
+            }
+            _ => true,
+        });
+        sent.unwrap();
+        receiver.unwrap();
+        assert_eq!(fs::read(received.join("data.bin")).unwrap(), contents);
+        for block in [3, 5, 70] {
+            assert_eq!(blocks.iter().filter(|&&b| b == block).count(), 2, "block {}", block);
+        }
+        assert_eq!(blocks.len(), 101 + 3);
+        assert!(!received.join("data.bin.part").exists());
+        assert!(!received.join("data.bin.part.map").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn interrupted_transfer_resumes_from_the_map() {
+        let dir = temp_dir("resume");
+        let (path, contents) = source_file(&dir);
+        let received = dir.join("received");
+        fs::create_dir(&received).unwrap();
+
+        // the link dies after the first window has been polled for
+        let mut alive = true;
+        let (sent, receiver, blocks) = transfer(&path, &received, |packet| {
+            let poll = packet[HEADER_LEN] == POLL;
+            let pass = alive;
+            alive &= !poll;
+            pass
+        });
+        assert!(sent.is_err());
+        assert!(receiver.is_err());
+        // the second window went into the dead link
+        assert_eq!(blocks.len(), 101);
+        assert!(received.join("data.bin.part.map").exists());
+
+        // only the blocks missing from the map are sent the second time
+        let (sent, receiver, blocks) = transfer(&path, &received, |_| true);
+        sent.unwrap();
+        receiver.unwrap();
+        assert_eq!(blocks, (WINDOW..101).collect::<Vec<u32>>());
+        assert_eq!(fs::read(received.join("data.bin")).unwrap(), contents);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn hash_mismatch_is_reported_and_discarded() {
+        let dir = temp_dir("mismatch");
+        let (path, _) = source_file(&dir);
+        let received = dir.join("received");
+        fs::create_dir(&received).unwrap();
+
+        let (sent, receiver, _) = transfer(&path, &received, |packet| {
+            if data_block(packet) == Some(42) {
+                *packet.last_mut().unwrap() ^= 0xff;
+            }
+            true
+        });
+        assert_eq!(sent.unwrap_err().to_string(), "Receiver reports a hash mismatch.");
+        // the receiver only counts verified files, so it is still waiting when the link goes
+        assert!(receiver.is_err());
+        assert_eq!(fs::read_dir(&received).unwrap().count(), 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}